@@ -17,7 +17,7 @@ class ImportPlugin extends EditorImportPlugin:
 
 	enum Presets {
 		DONT_IMPORT,
-		# IMPORT_AS_SINGLE_TEXTURE,
+		IMPORT_AS_SINGLE_TEXTURE,
 		IMPORT_AS_SEPERATE_TEXTURES,
 		# IMPORT_ALL_AS_SINGLE_TEXTURE,
 		# IMPORT_ALL_AS_SEPERATE_TEXTURES
@@ -57,9 +57,9 @@ class ImportPlugin extends EditorImportPlugin:
 			Presets.DONT_IMPORT:
 				return 'Dont import'
 
-			# Presets.IMPORT_AS_SINGLE_TEXTURE:
-			# 	return 'Import as single texture'
-            #
+			Presets.IMPORT_AS_SINGLE_TEXTURE:
+				return 'Import as single texture'
+
 			Presets.IMPORT_AS_SEPERATE_TEXTURES:
 				return 'Import as seperate textures'
             #
@@ -79,20 +79,14 @@ class ImportPlugin extends EditorImportPlugin:
 				name = 'dont_import',
 				default_value = preset == Presets.DONT_IMPORT
 			},
-			# {
-			# 	name = 'import_only_visible',
-			# 	default_value = [
-			# 		Presets.IMPORT_ALL_AS_SINGLE_TEXTURE,
-			# 		Presets.IMPORT_ALL_AS_SEPERATE_TEXTURES
-			# 	].has(preset)
-			# },
-			# {
-			# 	name = 'single_import',
-			# 	default_value = [
-			# 		Presets.IMPORT_AS_SINGLE_TEXTURE,
-			# 		Presets.IMPORT_ALL_AS_SINGLE_TEXTURE
-			# 	].has(preset)
-			# },
+			{
+				name = 'import_only_visible',
+				default_value = preset == Presets.IMPORT_AS_SEPERATE_TEXTURES
+			},
+			{
+				name = 'single_import',
+				default_value = preset == Presets.IMPORT_AS_SINGLE_TEXTURE
+			},
 			{
 				name = 'custom_import_script',
 				default_value = '',
@@ -134,6 +128,7 @@ class ImportPlugin extends EditorImportPlugin:
 
 		else:
 			script = DefaultPsdImportScript.new()
+			script.file_name = source_file.get_file().get_basename()
 
 		if not script:
 			return FAILED
@@ -152,19 +147,40 @@ class PsdImportScript:
 
 
 class DefaultPsdImportScript extends PsdImportScript:
+	var file_name = 'psd'
+
 	func import(plugin, importer, options, base_directory):
-		import_children(plugin, importer.get_children(), base_directory)
+		if options.get('single_import'):
+			return import_single(importer, base_directory)
+
+		import_children(plugin, importer.get_children(), options, base_directory)
 
 		return OK
 
 
-	func import_children(plugin, children, base_directory):
+	func import_single(importer, base_directory):
+		var image_path = '%s%s.png' % [base_directory, file_name]
+
+		var result = importer.render_image(false)
+		if result and result.image:
+			result.image.save_png(image_path)
+			print('Imported "%s" to "%s"' % [file_name, image_path])
+		else:
+			printerr('Tried saving image to "%s" but something went wrong' % image_path)
+
+		return OK
+
+
+	func import_children(plugin, children, options, base_directory):
 		var directory = Directory.new()
 
 		for child in children:
+			if options.get('import_only_visible') and not child.effective_visible:
+				continue
+
 			match child.node_type:
 				'Group':
-					import_children(plugin, child.get_children(), base_directory)
+					import_children(plugin, child.get_children(), options, base_directory)
 
 				'Layer':
 					var directory_path = child.path.trim_suffix(child.path.get_file())
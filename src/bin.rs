@@ -1,47 +1,119 @@
-use godot_psd::psd_lib::{
-    psd::{ColorMode, Psd},
-    PsdTree,
-};
-use std::path::PathBuf;
+use godot_psd::psd_lib::{PsdTree, RenderOptions};
+
+use std::path::{Path, PathBuf};
 
 fn main() {
-    let psd = Psd::from_bytes(include_bytes!("../test_import/test.psd")).unwrap();
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
 
-    assert_eq!(psd.color_mode(), ColorMode::Rgb);
+    match args.first().map(String::as_str) {
+        Some("translations") => translations(&args[1..]),
+        Some("export") => export(&args[1..]),
+        _ => export_test_layer(),
+    }
+}
 
-    let tree = PsdTree::new(psd);
+fn export_test_layer() {
+    let bytes = include_bytes!("../test_import/test.psd");
+    let tree = PsdTree::from_bytes(bytes).unwrap();
 
     // println!("{}", tree.list().join("\n"));
 
     // let layer = tree.get_path("/Face Shadows/Masculine")
 
-    let path = PathBuf::from("/Face Shadows/Masculine");
-    let mut pieces = path.iter().map(|e| e.to_str().unwrap());
+    let node = tree.get_node("/Face Shadows/Masculine").unwrap();
+
+    node.export_to_file();
+
+    // tree.export_all_to_file();
+}
+
+/// `translations <file.psd> [output.csv] [--locale <locale>]`, writing next to the PSD by default
+fn translations(args: &[String]) {
+    let mut locale = "en".to_string();
+    let mut paths = vec![];
 
-    // Skip the first root
-    if path.is_absolute() {
-        pieces.next();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--locale" => match args.next() {
+                Some(value) => locale = value.clone(),
+                None => {
+                    eprintln!("--locale needs a value, like `--locale en`");
+                    std::process::exit(1);
+                }
+            },
+            path => paths.push(PathBuf::from(path)),
+        }
     }
-    // TODO: Remove relative path
-
-    let piece = pieces.next().unwrap();
-
-    let mut node = tree
-        .get_children()
-        .into_iter()
-        .find(|child| child.element.name() == piece)
-        .unwrap();
-
-    'pieces: for piece in pieces {
-        for child in node.get_children().unwrap().into_iter() {
-            if child.element.name() == piece {
-                node = child;
-                continue 'pieces;
-            }
+
+    let input = match paths.first() {
+        Some(input) => input.clone(),
+        None => {
+            eprintln!(
+                "Usage: godot_psd_cli translations <file.psd> [output.csv] [--locale <locale>]"
+            );
+            std::process::exit(1);
+        }
+    };
+    let output = paths
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| input.with_extension("csv"));
+
+    let tree = read_tree(&input);
+
+    match tree.export_translation_csv(&output, &locale) {
+        Ok(count) => println!("Exported {count} strings to {}", output.to_str().unwrap()),
+        Err(error) => {
+            eprintln!("Couldn't write {}: {error}", output.to_str().unwrap());
+            std::process::exit(1);
         }
     }
+}
 
-    node.export_to_file();
+/// Reads the PSD at `input`, exiting when it can't be read
+fn read_tree(input: &Path) -> PsdTree {
+    let bytes = match std::fs::read(input) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("Couldn't read {}: {error}", input.to_str().unwrap());
+            std::process::exit(1);
+        }
+    };
 
-    // tree.export_all_to_file();
+    match PsdTree::from_bytes(&bytes) {
+        Ok(tree) => tree,
+        Err(error) => {
+            eprintln!("Couldn't parse {}: {error:?}", input.to_str().unwrap());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `export <file.psd> [--sixteen-bit] [--linear]`, writing a PNG of every layer to `./psd-output`
+fn export(args: &[String]) {
+    let sixteen_bit = args.iter().any(|arg| arg == "--sixteen-bit");
+    let linear = args.iter().any(|arg| arg == "--linear");
+    let input = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(input) => PathBuf::from(input),
+        None => {
+            eprintln!("Usage: godot_psd_cli export <file.psd> [--sixteen-bit] [--linear]");
+            std::process::exit(1);
+        }
+    };
+
+    let tree = read_tree(&input);
+
+    if sixteen_bit && tree.file.depth == 8 {
+        println!(
+            "{} has 8 bits per channel, exporting 8-bit PNGs",
+            input.to_str().unwrap()
+        );
+    }
+
+    tree.export_all_to_file_with(&RenderOptions {
+        sixteen_bit,
+        linear,
+        ..RenderOptions::default()
+    });
 }
@@ -1,15 +1,15 @@
 pub mod psd;
 
-use std::path::PathBuf;
-use std::sync::Arc;
-
 use auto_image_cropper::imagecrop::ImageCrop;
+use gdnative::api::File;
 use gdnative::prelude::*;
 
 pub use crate::psd as psd_lib;
 use crate::psd::{
-    psd::{ColorMode, Psd, PsdError},
-    PsdElement, PsdNode as InternalPsdNode, PsdTree,
+    psd::PsdError, Adjustment, Bounds, Canvas, FloatCanvas, Glow, LayerComp, LayerEffects,
+    LayerLocks, LoadError, NodeId, NodeQuery, NodeType, Overrides, PsdElement,
+    PsdNode as InternalPsdNode, PsdTree, RenderOptions, Rgb, Shadow, StrokePosition,
+    TextData as InternalTextData, TextStyle, WalkOrder,
 };
 
 #[derive(NativeClass)]
@@ -25,9 +25,12 @@ impl PsdImporter {
     #[method]
     fn load(&mut self, bytes: PoolArray<u8>) {
         let bytes = bytes.to_vec();
-        let psd = match Psd::from_bytes(&bytes) {
-            Ok(psd) => psd,
-            Err(error) => match error {
+        let tree = match PsdTree::from_bytes(&bytes) {
+            Ok(tree) => tree,
+            Err(LoadError::Records(error)) => {
+                panic!("Failed to parse PSD layer records: {error}")
+            }
+            Err(LoadError::Psd(error)) => match error {
                 PsdError::HeaderError(error) => panic!("Failed to parse PSD header: {error:#?}"),
                 PsdError::LayerError(error) => panic!("Failed to parse PSD layer: {error:#?}"),
                 PsdError::ImageError(error) => {
@@ -40,13 +43,13 @@ impl PsdImporter {
             },
         };
 
-        match psd.color_mode() {
-            ColorMode::Rgb => {
-                let tree = PsdTree::new(psd);
+        if !tree.records_match() {
+            godot_warn!("[PSD] You loaded a PSD whose layer records don't match up with its layers, so masks, effects, text and other extra properties are left out.");
+        }
 
-                self.0 = Some(tree);
-            },
-            other_format => godot_error!("[PSD] You tried loading in format {other_format:#?}, but we only support ColorMode::RGB for now :/"),
+        match tree.file.supports_color_mode() {
+            true => self.0 = Some(tree),
+            false => godot_error!("[PSD] You tried loading in format {}, but we can only convert Bitmap, Grayscale, Indexed, RGB, CMYK, Duotone and Lab documents :/", tree.file.color_mode_name()),
         }
     }
 
@@ -67,35 +70,186 @@ impl PsdImporter {
                 godot_error!("[PSD] You tried getting a node (layer or group), but you didn't load a PSD file (succesfully) yet.");
                 None
             }
-            Some(tree) => {
-                let path = PathBuf::from(path);
-                let mut pieces = path.iter().map(|e| e.to_str().unwrap());
+            Some(tree) => tree
+                .get_node(&path)
+                .map(|internal_node| PsdNode::from(internal_node).emplace().into_shared()),
+        }
+    }
 
-                // Skip the first root
-                if path.is_absolute() {
-                    pieces.next();
-                }
-                // TODO: Remove relative path
+    #[method]
+    fn get_node_by_id(&self, id: u32) -> Option<Instance<PsdNode>> {
+        match &self.0 {
+            None => {
+                godot_error!("[PSD] You tried getting a node (layer or group) by id, but you didn't load a PSD file (succesfully) yet.");
+                None
+            }
+            Some(tree) => tree
+                .get_node_by_id(NodeId(id as usize))
+                .map(|internal_node| PsdNode::from(internal_node).emplace().into_shared()),
+        }
+    }
 
-                let piece = pieces.next()?;
+    #[method]
+    fn find_nodes(
+        &self,
+        pattern: String,
+        #[opt] node_type: Option<PsdType>,
+        #[opt] visible: Option<bool>,
+    ) -> Vec<Instance<PsdNode>> {
+        match &self.0 {
+            None => {
+                godot_error!("[PSD] You tried finding nodes (layers or groups), but you didn't load a PSD file (succesfully) yet.");
+                vec![]
+            }
+            Some(tree) => tree
+                .find_nodes(&node_query(pattern, node_type, visible))
+                .into_iter()
+                .map(|internal_node| PsdNode::from(internal_node).emplace().into_shared())
+                .collect::<Vec<Instance<PsdNode>>>(),
+        }
+    }
 
-                let mut node = tree
+    #[method]
+    fn get_tree_dictionary(&self) -> Option<TreeDictionary> {
+        match &self.0 {
+            None => {
+                godot_error!("[PSD] You tried getting the tree as a dictionary, but you didn't load a PSD file (succesfully) yet.");
+                None
+            }
+            Some(tree) => Some(TreeDictionary {
+                width: tree.psd.width(),
+                height: tree.psd.height(),
+                color_mode: tree.file.color_mode_name().to_string(),
+                depth: tree.file.depth,
+                color_profile: tree.file.color_profile().map(|profile| profile.name),
+                children: tree
                     .get_children()
-                    .into_iter()
-                    .find(|child| child.element.name() == piece)?;
-
-                'pieces: for piece in pieces {
-                    for child in node.get_children()?.into_iter() {
-                        if child.element.name() == piece {
-                            node = child;
-                            continue 'pieces;
-                        }
+                    .iter()
+                    .map(TreeEntry::from)
+                    .collect::<Vec<TreeEntry>>(),
+            }),
+        }
+    }
+
+    #[method]
+    fn render_image(
+        &self,
+        cropped: bool,
+        #[opt] options: Option<Dictionary>,
+    ) -> Option<RenderedImage> {
+        match &self.0 {
+            None => {
+                godot_error!("[PSD] You tried rendering the document, but you didn't load a PSD file (succesfully) yet.");
+                None
+            }
+            Some(tree) => {
+                let options = options.unwrap_or_else(|| Dictionary::new().into_shared());
+                let render = RenderOptions {
+                    overrides: overrides(tree, &options),
+                    ..RenderOptions::default()
+                };
+
+                let canvas = match options.get("path") {
+                    None => tree.render_with(&render),
+                    Some(path) => {
+                        let path = String::from_variant(&path).unwrap_or_default();
+                        let node = match tree.get_node(&path) {
+                            Some(node) => node,
+                            None => {
+                                godot_error!("[PSD] You tried rendering \"{path}\", but there's no node (layer or group) at that path.");
+                                return None;
+                            }
+                        };
+
+                        let opacity = render.overrides.opacity_of(&node);
+
+                        let mut canvas = node.render_with(&render);
+                        canvas.multiply_alpha(opacity);
+
+                        canvas
                     }
+                };
 
-                    return None;
-                }
+                let (rect, image) = create_image(canvas, cropped);
 
-                Some(PsdNode::from(node).emplace().into_shared())
+                Some(RenderedImage {
+                    image: image.into_shared(),
+                    rect,
+                })
+            }
+        }
+    }
+
+    #[method]
+    fn get_layer_comps(&self) -> Vec<LayerCompEntry> {
+        match &self.0 {
+            None => {
+                godot_error!("[PSD] You tried getting the layer comps, but you didn't load a PSD file (succesfully) yet.");
+                vec![]
+            }
+            Some(tree) => tree
+                .layer_comps()
+                .iter()
+                .map(|comp| LayerCompEntry::new(tree, comp))
+                .collect::<Vec<LayerCompEntry>>(),
+        }
+    }
+
+    #[method]
+    fn render_layer_comp(&self, name: String, cropped: bool) -> Option<RenderedImage> {
+        match &self.0 {
+            None => {
+                godot_error!("[PSD] You tried rendering a layer comp, but you didn't load a PSD file (succesfully) yet.");
+                None
+            }
+            Some(tree) => {
+                let comp = match tree.get_layer_comp(&name) {
+                    Some(comp) => comp,
+                    None => {
+                        godot_error!("[PSD] You tried rendering layer comp \"{name}\", but there's no layer comp with that name.");
+                        return None;
+                    }
+                };
+
+                let (rect, image) = create_image(tree.with_comp(&comp).render(), cropped);
+
+                Some(RenderedImage {
+                    image: image.into_shared(),
+                    rect,
+                })
+            }
+        }
+    }
+
+    #[method]
+    fn get_translation_csv(&self, #[opt] locale: Option<String>) -> Option<String> {
+        match &self.0 {
+            None => {
+                godot_error!("[PSD] You tried getting a translation CSV, but you didn't load a PSD file (succesfully) yet.");
+                None
+            }
+            Some(tree) => Some(tree.translation_csv(locale.as_deref().unwrap_or("en"))),
+        }
+    }
+
+    #[method]
+    fn export_translation_csv(&self, path: String, #[opt] locale: Option<String>) -> bool {
+        let csv = match self.get_translation_csv(locale) {
+            Some(csv) => csv,
+            None => return false,
+        };
+
+        // Goes through Godot so `res://` and `user://` paths work
+        let file = File::new();
+        match file.open(path.clone(), File::WRITE) {
+            Ok(_) => {
+                file.store_string(csv);
+                file.close();
+                true
+            }
+            Err(error) => {
+                godot_error!("[PSD] You tried exporting a translation CSV to \"{path}\", but the file couldn't be opened: {error:?}");
+                false
             }
         }
     }
@@ -125,8 +279,8 @@ impl PsdImporter {
             Some(tree) => tree
                 .get_children()
                 .into_iter()
-                .filter_map(|internal_node| match internal_node.element {
-                    PsdElement::Layer(_) => {
+                .filter_map(|internal_node| match internal_node.element() {
+                    PsdElement::Layer(..) => {
                         Some(PsdNode::from(internal_node).emplace().into_shared())
                     }
                     _ => None,
@@ -145,8 +299,8 @@ impl PsdImporter {
             Some(tree) => tree
                 .get_children()
                 .into_iter()
-                .filter_map(|internal_node| match internal_node.element {
-                    PsdElement::Group(_) => {
+                .filter_map(|internal_node| match internal_node.element() {
+                    PsdElement::Group(..) => {
                         Some(PsdNode::from(internal_node).emplace().into_shared())
                     }
                     _ => None,
@@ -160,17 +314,25 @@ impl PsdImporter {
 #[no_constructor]
 #[register_with(Self::register_signals)]
 pub struct PsdNode {
-    internal_node: Arc<InternalPsdNode>,
+    internal_node: InternalPsdNode,
     thread: Option<std::thread::JoinHandle<(Rect2, Ref<Image, Unique>)>>,
 
+    #[property]
+    id: u32,
     #[property]
     name: String,
     #[property]
     path: String,
     #[property]
-    properties: Option<LayerProperties>,
+    properties: NodeProperties,
     #[property]
     node_type: PsdType,
+    #[property]
+    stacking_index: u32,
+    #[property]
+    effective_visible: bool,
+    #[property]
+    effective_opacity: u8,
 }
 
 #[methods]
@@ -192,35 +354,38 @@ impl PsdNode {
 
     #[method]
     fn get_node(&self, path: String) -> Option<Instance<PsdNode>> {
-        let path = PathBuf::from(path);
-        let mut pieces = path.iter().map(|e| e.to_str().unwrap());
+        self.internal_node
+            .get_node(&path)
+            .map(|internal_node| PsdNode::from(internal_node).emplace().into_shared())
+    }
 
-        // Skip the first root
-        if path.is_absolute() {
-            pieces.next();
-        }
-        // TODO: Remove relative path
+    #[method]
+    fn get_parent(&self) -> Option<Instance<PsdNode>> {
+        self.internal_node
+            .get_parent()
+            .map(|internal_node| PsdNode::from(internal_node).emplace().into_shared())
+    }
 
-        let piece = pieces.next()?;
+    #[method]
+    fn get_node_by_id(&self, id: u32) -> Option<Instance<PsdNode>> {
+        self.internal_node
+            .tree
+            .get_node_by_id(NodeId(id as usize))
+            .map(|internal_node| PsdNode::from(internal_node).emplace().into_shared())
+    }
 
-        let mut node = self
-            .internal_node
-            .get_children()?
+    #[method]
+    fn find_nodes(
+        &self,
+        pattern: String,
+        #[opt] node_type: Option<PsdType>,
+        #[opt] visible: Option<bool>,
+    ) -> Vec<Instance<PsdNode>> {
+        self.internal_node
+            .find_nodes(&node_query(pattern, node_type, visible))
             .into_iter()
-            .find(|child| child.element.name() == piece)?;
-
-        'pieces: for piece in pieces {
-            for child in node.get_children()?.into_iter() {
-                if child.element.name() == piece {
-                    node = child;
-                    continue 'pieces;
-                }
-            }
-
-            return None;
-        }
-
-        Some(PsdNode::from(node).emplace().into_shared())
+            .map(|internal_node| PsdNode::from(internal_node).emplace().into_shared())
+            .collect::<Vec<Instance<PsdNode>>>()
     }
 
     #[method]
@@ -240,8 +405,8 @@ impl PsdNode {
             None => vec![],
             Some(children) => children
                 .into_iter()
-                .filter_map(|internal_node| match internal_node.element {
-                    PsdElement::Layer(_) => {
+                .filter_map(|internal_node| match internal_node.element() {
+                    PsdElement::Layer(..) => {
                         Some(PsdNode::from(internal_node).emplace().into_shared())
                     }
                     _ => None,
@@ -256,8 +421,8 @@ impl PsdNode {
             None => vec![],
             Some(children) => children
                 .into_iter()
-                .filter_map(|internal_node| match internal_node.element {
-                    PsdElement::Group(_) => {
+                .filter_map(|internal_node| match internal_node.element() {
+                    PsdElement::Group(..) => {
                         Some(PsdNode::from(internal_node).emplace().into_shared())
                     }
                     _ => None,
@@ -268,102 +433,128 @@ impl PsdNode {
 
     #[method]
     fn get_rect2(&self) -> Rect2 {
-        match self.internal_node.element.clone() {
-            PsdElement::Layer(layer) => Rect2::new(
-                Vector2::new(layer.layer_left() as f32, layer.layer_top() as f32),
-                Vector2::new(layer.width().into(), layer.height().into()),
-            ),
-            PsdElement::Group(group) => Rect2::new(
-                Vector2::new(group.layer_left() as f32, group.layer_top() as f32),
-                Vector2::new(group.width().into(), group.height().into()),
-            ),
-        }
+        rect2(self.internal_node.bounds())
     }
 
     #[method]
-    fn get_image(&mut self, #[base] owner: TRef<Reference>, cropped: bool) {
-        match &self.internal_node.element {
-            PsdElement::Layer(_) => {
-                let internal_node = self.internal_node.clone();
-                let width = self.internal_node.tree.psd.width();
-                let height = self.internal_node.tree.psd.height();
-
-                // Cleanup thread after execution of this method
-                unsafe {
-                    owner.call_deferred("cleanup_thread", &[]);
-                }
+    fn get_image(
+        &mut self,
+        #[base] owner: TRef<Reference>,
+        cropped: bool,
+        #[opt] options: Option<Dictionary>,
+    ) {
+        let options = ImageOptions::from(options);
+        let internal_node = self.internal_node.clone();
 
-                let thread = std::thread::spawn(move || {
-                    let image = Image::new();
-
-                    let rect = if let PsdElement::Layer(layer) = &internal_node.element {
-                        match std::panic::catch_unwind(|| {
-                            let mut rect = Rect2::new(Vector2::ZERO, Vector2::ZERO);
-
-                            if cropped {
-                                let (top_left, _, width, height, bytes) =
-                                    ImageCrop::from_buffer(width, height, layer.rgba())
-                                        .unwrap()
-                                        .auto_crop();
-
-                                rect.size = Vector2::new(width as f32, height as f32);
-                                rect.position = Vector2::new(top_left.x as f32, top_left.y as f32);
-
-                                // Pre-multiply layer alpha
-                                let opacity = layer.opacity();
-                                let mut bytes = bytes.into_rgba8().into_raw();
-                                let mut index = 0;
-                                for byte in bytes.iter_mut() {
-                                    if index % 4 == 3 {
-                                        if byte > &mut 0u8 {
-                                            *byte = (*byte as f32 * opacity as f32 / 255.0).round()
-                                                as u8
-                                        }
-                                    }
-                                    index += 1;
-                                }
-
-                                let data = PoolArray::from_vec(bytes);
-                                image.create_from_data(
-                                    width.into(),
-                                    height.into(),
-                                    false,
-                                    Image::FORMAT_RGBA8,
-                                    data,
-                                );
-
-                                // image.premultiply_alpha();
-
-                                rect
-                            } else {
-                                let data = PoolArray::from_vec(layer.rgba());
-                                rect.size = Vector2::new(width as f32, height as f32);
-
-                                image.create_from_data(
-                                    width.into(),
-                                    height.into(),
-                                    false,
-                                    Image::FORMAT_RGBA8,
-                                    data,
-                                );
-
-                                rect
-                            }
-                        }) {
-                            Ok(result) => result,
-                            _ => Rect2::new(Vector2::ZERO, Vector2::ZERO),
-                        }
-                    } else {
-                        Rect2::new(Vector2::ZERO, Vector2::ZERO)
-                    };
+        // Cleanup thread after execution of this method
+        unsafe {
+            owner.call_deferred("cleanup_thread", &[]);
+        }
 
-                    (rect, image)
-                });
+        let thread = std::thread::spawn(move || {
+            let (mut canvas, (left, top)) = match options.render.effects {
+                true => internal_node.render_effects(&options.render),
+                false => (internal_node.render_with(&options.render), (0, 0)),
+            };
 
-                self.thread = Some(thread);
+            // Only cropped images grow past the document to fit the effects
+            if !cropped && (left, top) != (0, 0) {
+                let document = &internal_node.tree.psd;
+                canvas = canvas.region(-left, -top, document.width(), document.height());
+            }
+
+            // Bake in the opacity of the node itself, which uncropped layers have always left out
+            let opacity = match internal_node.element() {
+                _ if options.effective_opacity => Some(internal_node.effective_opacity()),
+                PsdElement::Layer(layer, _) if cropped => Some(layer.opacity()),
+                PsdElement::Layer(..) => None,
+                PsdElement::Group(group, _) => Some(group.opacity()),
+            };
+
+            // Documents with 16 or 32 bits per channel keep their precision where they can
+            let mut precise = match options.format != ImageFormat::Rgba8
+                || options.dither
+                || options.render.linear
+            {
+                true => Some(
+                    internal_node
+                        .render_float(&options.render)
+                        .unwrap_or_else(|| FloatCanvas::from(&canvas)),
+                ),
+                false => None,
+            };
+
+            if let Some(opacity) = opacity {
+                canvas.multiply_alpha(opacity);
+                if let Some(precise) = precise.as_mut() {
+                    precise.multiply_alpha(opacity);
+                }
             }
-            _ => {}
+
+            let (mut rect, image) = match precise {
+                Some(precise) => create_float_image(precise, cropped, &options),
+                None => create_image(canvas, cropped),
+            };
+            if cropped {
+                rect.position += Vector2::new(left as f32, top as f32);
+            }
+
+            (rect, image)
+        });
+
+        self.thread = Some(thread);
+    }
+
+    #[method]
+    fn get_mask_image(&self) -> Option<RenderedImage> {
+        let (bounds, pixels) = self.internal_node.render_mask()?;
+
+        // Godot can't create an image without pixels
+        if bounds.width == 0 || bounds.height == 0 {
+            return None;
         }
+
+        let image = Image::new();
+        image.create_from_data(
+            bounds.width.into(),
+            bounds.height.into(),
+            false,
+            Image::FORMAT_L8,
+            PoolArray::from_vec(pixels),
+        );
+
+        Some(RenderedImage {
+            image: image.into_shared(),
+            rect: rect2(bounds),
+        })
+    }
+
+    #[method]
+    fn get_vector_paths(&self) -> Vec<PoolArray<Vector2>> {
+        let width = self.internal_node.tree.psd.width();
+        let height = self.internal_node.tree.psd.height();
+
+        match self.internal_node.element().vector_mask() {
+            None => vec![],
+            Some(mask) => mask
+                .subpaths
+                .iter()
+                .map(|subpath| {
+                    PoolArray::from_vec(
+                        subpath
+                            .points(width, height)
+                            .into_iter()
+                            .map(|[x, y]| Vector2::new(x, y))
+                            .collect::<Vec<Vector2>>(),
+                    )
+                })
+                .collect::<Vec<PoolArray<Vector2>>>(),
+        }
+    }
+
+    #[method]
+    fn get_text_data(&self) -> Option<TextData> {
+        self.internal_node.element().text_data().map(TextData::from)
     }
 
     #[method]
@@ -378,53 +569,344 @@ impl PsdNode {
 impl From<InternalPsdNode> for PsdNode {
     fn from(internal_node: InternalPsdNode) -> PsdNode {
         PsdNode {
-            name: internal_node.element.name(),
+            id: internal_node.id.0 as u32,
+            name: internal_node.element().name(),
             path: internal_node.get_path().to_str().unwrap().to_string(),
-            node_type: match internal_node.element {
-                PsdElement::Group(_) => PsdType::Group,
-                PsdElement::Layer(_) => PsdType::Layer,
+            node_type: match internal_node.element() {
+                PsdElement::Group(..) => PsdType::Group,
+                PsdElement::Layer(..) => PsdType::Layer,
             },
-            properties: match &internal_node.element {
-                PsdElement::Layer(layer) => Some(LayerProperties {
+            properties: match internal_node.element() {
+                element @ PsdElement::Layer(layer, _) => NodeProperties::Layer(LayerProperties {
                     visible: layer.visible(),
                     opacity: layer.opacity(),
                     width: u32::try_from(layer.width()).unwrap(),
                     height: u32::try_from(layer.height()).unwrap(),
                     group_id: layer.parent_id(),
+                    blend_mode: element.blend_mode().name().to_string(),
+                    clipping: element.is_clipped(),
+                    fill_opacity: element.fill_opacity(),
+                    locks: Locks::from(element.locks()),
+                    color_label: element.color_label().name().to_string(),
+                    adjustment: internal_node
+                        .adjustment()
+                        .as_ref()
+                        .map(adjustment_dictionary),
+                    effects: Effects::new(
+                        &internal_node.effects(),
+                        internal_node.tree.file.global_angle(),
+                    ),
+                }),
+                element @ PsdElement::Group(group, _) => NodeProperties::Group(GroupProperties {
+                    visible: group.visible(),
+                    opacity: group.opacity(),
+                    bounds: rect2(internal_node.bounds()),
+                    group_id: group.parent_id(),
+                    blend_mode: element.blend_mode().name().to_string(),
+                    clipping: element.is_clipped(),
+                    fill_opacity: element.fill_opacity(),
+                    locks: Locks::from(element.locks()),
+                    color_label: element.color_label().name().to_string(),
                 }),
-                _ => None,
             },
+            stacking_index: internal_node.stacking_index() as u32,
+            effective_visible: internal_node.effective_visible(),
+            effective_opacity: internal_node.effective_opacity(),
 
-            internal_node: Arc::from(internal_node),
+            internal_node,
             thread: None,
         }
     }
 }
 
-impl From<Arc<InternalPsdNode>> for PsdNode {
-    fn from(internal_node: Arc<InternalPsdNode>) -> PsdNode {
-        PsdNode {
-            name: internal_node.element.name(),
-            path: internal_node.get_path().to_str().unwrap().to_string(),
-            node_type: match internal_node.element {
-                PsdElement::Group(_) => PsdType::Group,
-                PsdElement::Layer(_) => PsdType::Layer,
+/// Turns a rendered canvas into an `Image`, together with the rect it covers in the document
+fn create_image(canvas: Canvas, cropped: bool) -> (Rect2, Ref<Image, Unique>) {
+    let image = Image::new();
+    let (width, height) = (canvas.width, canvas.height);
+
+    let rect = match std::panic::catch_unwind(|| {
+        if cropped {
+            let (top_left, _, width, height, bytes) =
+                ImageCrop::from_buffer(width, height, canvas.into_rgba())
+                    .unwrap()
+                    .auto_crop();
+
+            image.create_from_data(
+                width.into(),
+                height.into(),
+                false,
+                Image::FORMAT_RGBA8,
+                PoolArray::from_vec(bytes.into_rgba8().into_raw()),
+            );
+
+            Rect2::new(
+                Vector2::new(top_left.x as f32, top_left.y as f32),
+                Vector2::new(width as f32, height as f32),
+            )
+        } else {
+            image.create_from_data(
+                width.into(),
+                height.into(),
+                false,
+                Image::FORMAT_RGBA8,
+                PoolArray::from_vec(canvas.into_rgba()),
+            );
+
+            Rect2::new(Vector2::ZERO, Vector2::new(width as f32, height as f32))
+        }
+    }) {
+        Ok(rect) => rect,
+        _ => Rect2::new(Vector2::ZERO, Vector2::ZERO),
+    };
+
+    (rect, image)
+}
+
+/// Like `create_image` for a canvas at the precision of the document, in the format asked for
+fn create_float_image(
+    canvas: FloatCanvas,
+    cropped: bool,
+    options: &ImageOptions,
+) -> (Rect2, Ref<Image, Unique>) {
+    let image = Image::new();
+    let (x, y, width, height) = match cropped {
+        true => canvas.opaque_bounds().unwrap_or_default(),
+        false => (0, 0, canvas.width, canvas.height),
+    };
+
+    if width == 0 || height == 0 {
+        return (Rect2::new(Vector2::ZERO, Vector2::ZERO), image);
+    }
+
+    let canvas = canvas.region(x as i32, y as i32, width, height);
+    // Every format is sRGB unless asked for linear colors, whatever the depth of the document
+    let canvas = match options.render.linear {
+        true => canvas.to_linear(),
+        false => canvas.to_srgb(),
+    };
+
+    let (format, bytes) = match options.format {
+        ImageFormat::Rgba8 => (
+            Image::FORMAT_RGBA8,
+            canvas.to_canvas(options.dither).into_rgba(),
+        ),
+        ImageFormat::Rgbah => (
+            Image::FORMAT_RGBAH,
+            canvas
+                .to_rgba_half()
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect::<Vec<u8>>(),
+        ),
+        ImageFormat::Rgbaf => (
+            Image::FORMAT_RGBAF,
+            canvas
+                .pixels
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect::<Vec<u8>>(),
+        ),
+    };
+
+    image.create_from_data(
+        width.into(),
+        height.into(),
+        false,
+        format,
+        PoolArray::from_vec(bytes),
+    );
+
+    (
+        Rect2::new(
+            Vector2::new(x as f32, y as f32),
+            Vector2::new(width as f32, height as f32),
+        ),
+        image,
+    )
+}
+
+/// Optional flags for `get_image`, passed as a Dictionary so new ones don't break existing calls
+#[derive(Default)]
+struct ImageOptions {
+    effective_opacity: bool,
+    format: ImageFormat,
+    dither: bool,
+    render: RenderOptions,
+}
+
+/// The "format" of `get_image`, with the float formats keeping the precision of 16 and 32-bit
+/// documents
+#[derive(Default, PartialEq, Eq)]
+enum ImageFormat {
+    #[default]
+    Rgba8,
+    Rgbah,
+    Rgbaf,
+}
+
+impl From<Option<Dictionary>> for ImageOptions {
+    fn from(options: Option<Dictionary>) -> ImageOptions {
+        let flag = |key: &str| {
+            options
+                .as_ref()
+                .and_then(|options| options.get(key))
+                .and_then(|value| bool::from_variant(&value).ok())
+                .unwrap_or_default()
+        };
+
+        let format = options
+            .as_ref()
+            .and_then(|options| options.get("format"))
+            .and_then(|value| String::from_variant(&value).ok());
+
+        ImageOptions {
+            effective_opacity: flag("effective_opacity"),
+            format: match format.as_deref() {
+                None | Some("rgba8") => ImageFormat::Rgba8,
+                Some("rgbah") => ImageFormat::Rgbah,
+                Some("rgbaf") => ImageFormat::Rgbaf,
+                Some(format) => {
+                    godot_warn!("[PSD] You tried to get an image in the \"{format}\" format, which isn't one of \"rgba8\", \"rgbah\" or \"rgbaf\". Falling back to \"rgba8\".");
+                    ImageFormat::Rgba8
+                }
             },
-            properties: match &internal_node.element {
-                PsdElement::Layer(layer) => Some(LayerProperties {
-                    visible: layer.visible(),
-                    opacity: layer.opacity(),
-                    width: u32::try_from(layer.width()).unwrap(),
-                    height: u32::try_from(layer.height()).unwrap(),
-                    group_id: layer.parent_id(),
-                }),
-                _ => None,
+            dither: flag("dither"),
+            render: RenderOptions {
+                clipped: flag("clipped"),
+                merge_clipped: flag("merge_clipped"),
+                effects: flag("effects"),
+                linear: flag("linear"),
+                ..RenderOptions::default()
             },
+        }
+    }
+}
 
-            internal_node,
-            thread: None,
+/// Reads the "visible" and "opacity" Dictionaries of `render_image`, which map node paths to the
+/// visibility and opacity (0-255) to render those nodes with
+fn overrides(tree: &PsdTree, options: &Dictionary) -> Overrides {
+    let mut overrides = Overrides::default();
+
+    let entries = |key: &str| {
+        options
+            .get(key)
+            .and_then(|value| Dictionary::from_variant(&value).ok())
+            .map(|dictionary| {
+                dictionary
+                    .iter()
+                    .filter_map(|(path, value)| {
+                        let path = String::from_variant(&path).unwrap_or_default();
+                        match tree.get_node(&path) {
+                            Some(node) => Some((node.id, value)),
+                            None => {
+                                godot_warn!("[PSD] Ignoring the {key} override for \"{path}\", as there's no node (layer or group) at that path.");
+                                None
+                            }
+                        }
+                    })
+                    .collect::<Vec<(NodeId, Variant)>>()
+            })
+            .unwrap_or_default()
+    };
+
+    for (id, value) in entries("visible") {
+        if let Ok(visible) = bool::from_variant(&value) {
+            overrides.visible.insert(id, visible);
         }
     }
+
+    for (id, value) in entries("opacity") {
+        if let Ok(opacity) = i64::from_variant(&value) {
+            overrides.opacity.insert(id, opacity.clamp(0, 255) as u8);
+        }
+    }
+
+    overrides
+}
+
+/// The parameters of an adjustment layer, with a `type` telling which kind of adjustment it is
+fn adjustment_dictionary(adjustment: &Adjustment) -> Dictionary {
+    let dictionary = Dictionary::new();
+    let channels = ["composite", "red", "green", "blue"];
+
+    match adjustment {
+        Adjustment::Levels(levels) => {
+            dictionary.insert("type", "levels");
+
+            for (channel, level) in channels.iter().zip(levels.iter()) {
+                let entry = Dictionary::new();
+                entry.insert("input_floor", level.input_floor);
+                entry.insert("input_ceiling", level.input_ceiling);
+                entry.insert("output_floor", level.output_floor);
+                entry.insert("output_ceiling", level.output_ceiling);
+                entry.insert("gamma", level.gamma);
+
+                dictionary.insert(*channel, entry.into_shared());
+            }
+        }
+        Adjustment::Curves(curves) => {
+            dictionary.insert("type", "curves");
+
+            for (channel, curve) in channels.iter().zip(curves.iter()) {
+                let points = curve
+                    .iter()
+                    .map(|[input, output]| Vector2::new(*input as f32, *output as f32))
+                    .collect::<Vec<Vector2>>();
+
+                dictionary.insert(*channel, PoolArray::from_vec(points));
+            }
+        }
+        Adjustment::HueSaturation(settings) => {
+            dictionary.insert("type", "hue_saturation");
+            dictionary.insert("hue", settings.hue);
+            dictionary.insert("saturation", settings.saturation);
+            dictionary.insert("lightness", settings.lightness);
+            dictionary.insert("colorize", settings.colorize);
+            dictionary.insert("colorize_hue", settings.colorize_hue);
+            dictionary.insert("colorize_saturation", settings.colorize_saturation);
+            dictionary.insert("colorize_lightness", settings.colorize_lightness);
+        }
+        Adjustment::BrightnessContrast {
+            brightness,
+            contrast,
+            legacy,
+        } => {
+            dictionary.insert("type", "brightness_contrast");
+            dictionary.insert("brightness", *brightness);
+            dictionary.insert("contrast", *contrast);
+            dictionary.insert("legacy", *legacy);
+        }
+        Adjustment::Unsupported(key) => {
+            dictionary.insert("type", "unsupported");
+            dictionary.insert("key", key.as_str());
+        }
+    }
+
+    dictionary.into_shared()
+}
+
+fn rect2(bounds: Bounds) -> Rect2 {
+    Rect2::new(
+        Vector2::new(bounds.left as f32, bounds.top as f32),
+        Vector2::new(bounds.width as f32, bounds.height as f32),
+    )
+}
+
+fn node_query(pattern: String, node_type: Option<PsdType>, visible: Option<bool>) -> NodeQuery {
+    NodeQuery {
+        pattern,
+        node_type: node_type.map(|node_type| match node_type {
+            PsdType::Group => NodeType::Group,
+            PsdType::Layer => NodeType::Layer,
+        }),
+        visible,
+    }
+}
+
+#[derive(ToVariant)]
+struct RenderedImage {
+    image: Ref<Image, Shared>,
+    rect: Rect2,
 }
 
 #[derive(FromVariant, ToVariant)]
@@ -442,6 +924,38 @@ impl gdnative::export::Export for PsdType {
     }
 }
 
+/// Either [`LayerProperties`] or [`GroupProperties`], exposed to Godot as a plain dictionary
+#[derive(Clone)]
+pub enum NodeProperties {
+    Layer(LayerProperties),
+    Group(GroupProperties),
+}
+
+impl ToVariant for NodeProperties {
+    fn to_variant(&self) -> Variant {
+        match self {
+            NodeProperties::Layer(properties) => properties.to_variant(),
+            NodeProperties::Group(properties) => properties.to_variant(),
+        }
+    }
+}
+
+impl FromVariant for NodeProperties {
+    fn from_variant(variant: &Variant) -> Result<Self, gdnative::core_types::FromVariantError> {
+        LayerProperties::from_variant(variant)
+            .map(NodeProperties::Layer)
+            .or_else(|_| GroupProperties::from_variant(variant).map(NodeProperties::Group))
+    }
+}
+
+impl gdnative::export::Export for NodeProperties {
+    type Hint = ();
+
+    fn export_info(_hint: Option<Self::Hint>) -> ExportInfo {
+        ExportInfo::new(VariantType::Dictionary)
+    }
+}
+
 #[derive(FromVariant, ToVariant, Clone)]
 pub struct LayerProperties {
     pub visible: bool,
@@ -449,13 +963,319 @@ pub struct LayerProperties {
     pub width: u32,
     pub height: u32,
     pub group_id: Option<u32>,
+    pub blend_mode: String,
+    pub clipping: bool,
+    pub fill_opacity: u8,
+    pub locks: Locks,
+    pub color_label: String,
+    pub adjustment: Option<Dictionary>,
+    pub effects: Effects,
 }
 
-impl gdnative::export::Export for LayerProperties {
-    type Hint = ();
+#[derive(ToVariant)]
+pub struct TreeDictionary {
+    pub width: u32,
+    pub height: u32,
+    pub color_mode: String,
+    pub depth: u16,
+    pub color_profile: Option<String>,
+    pub children: Vec<TreeEntry>,
+}
 
-    fn export_info(_hint: Option<Self::Hint>) -> ExportInfo {
-        ExportInfo::new(VariantType::Dictionary)
+#[derive(ToVariant)]
+pub struct TreeEntry {
+    pub id: u32,
+    pub name: String,
+    pub path: String,
+    pub node_type: PsdType,
+    pub bounds: Rect2,
+    pub visible: bool,
+    pub opacity: u8,
+    pub children: Vec<TreeEntry>,
+}
+
+impl From<&InternalPsdNode> for TreeEntry {
+    fn from(internal_node: &InternalPsdNode) -> TreeEntry {
+        let element = internal_node.element();
+
+        TreeEntry {
+            id: internal_node.id.0 as u32,
+            name: element.name(),
+            path: internal_node.get_path().to_str().unwrap().to_string(),
+            node_type: match element {
+                PsdElement::Group(..) => PsdType::Group,
+                PsdElement::Layer(..) => PsdType::Layer,
+            },
+            bounds: rect2(internal_node.bounds()),
+            visible: element.visible(),
+            opacity: element.opacity(),
+            children: internal_node
+                .get_children()
+                .unwrap_or_default()
+                .iter()
+                .map(TreeEntry::from)
+                .collect::<Vec<TreeEntry>>(),
+        }
+    }
+}
+
+#[derive(ToVariant)]
+pub struct LayerCompEntry {
+    pub id: u32,
+    pub name: String,
+    pub comment: String,
+    pub captures_visibility: bool,
+    pub captures_position: bool,
+    pub captures_appearance: bool,
+    pub layers: Vec<LayerCompState>,
+}
+
+/// How a single layer or group looks in a layer comp
+#[derive(ToVariant)]
+pub struct LayerCompState {
+    pub path: String,
+    pub visible: bool,
+    pub position: Vector2,
+    pub opacity: u8,
+    pub blend_mode: String,
+}
+
+impl LayerCompEntry {
+    fn new(tree: &PsdTree, comp: &LayerComp) -> LayerCompEntry {
+        let layers = tree
+            .walk(WalkOrder::DepthFirst)
+            .map(|entry| {
+                let node = entry.node;
+                let element = node.element();
+                let settings = node.comp_settings(comp);
+                let bounds = node.bounds();
+                let (x, y) = settings.offset.unwrap_or_default();
+
+                LayerCompState {
+                    path: entry.path.to_str().unwrap().to_string(),
+                    visible: settings.visible.unwrap_or_else(|| element.visible()),
+                    position: Vector2::new((bounds.left + x) as f32, (bounds.top + y) as f32),
+                    opacity: settings.opacity.unwrap_or_else(|| element.opacity()),
+                    blend_mode: settings
+                        .blend_mode
+                        .unwrap_or_else(|| element.blend_mode())
+                        .name()
+                        .to_string(),
+                }
+            })
+            .collect::<Vec<LayerCompState>>();
+
+        LayerCompEntry {
+            id: comp.id,
+            name: comp.name.clone(),
+            comment: comp.comment.clone(),
+            captures_visibility: comp.visibility,
+            captures_position: comp.position,
+            captures_appearance: comp.appearance,
+            layers,
+        }
+    }
+}
+
+#[derive(FromVariant, ToVariant, Clone)]
+pub struct GroupProperties {
+    pub visible: bool,
+    pub opacity: u8,
+    pub bounds: Rect2,
+    pub group_id: Option<u32>,
+    pub blend_mode: String,
+    pub clipping: bool,
+    pub fill_opacity: u8,
+    pub locks: Locks,
+    pub color_label: String,
+}
+
+#[derive(FromVariant, ToVariant, Clone)]
+pub struct Locks {
+    pub transparency: bool,
+    pub pixels: bool,
+    pub position: bool,
+    pub all: bool,
+}
+
+/// The layer effects of a layer, to recreate them with shaders instead of baking them
+#[derive(FromVariant, ToVariant, Clone)]
+pub struct Effects {
+    pub drop_shadows: Vec<ShadowEffect>,
+    pub inner_shadows: Vec<ShadowEffect>,
+    pub outer_glows: Vec<GlowEffect>,
+    pub inner_glows: Vec<GlowEffect>,
+    pub color_overlays: Vec<ColorOverlayEffect>,
+    pub strokes: Vec<StrokeEffect>,
+}
+
+#[derive(FromVariant, ToVariant, Clone)]
+pub struct ShadowEffect {
+    pub color: Color,
+    pub opacity: u8,
+    pub blend_mode: String,
+    pub angle: f64,
+    pub distance: f64,
+    pub offset: Vector2,
+    pub spread: f64,
+    pub size: f64,
+}
+
+#[derive(FromVariant, ToVariant, Clone)]
+pub struct GlowEffect {
+    pub color: Color,
+    pub opacity: u8,
+    pub blend_mode: String,
+    pub spread: f64,
+    pub size: f64,
+}
+
+#[derive(FromVariant, ToVariant, Clone)]
+pub struct ColorOverlayEffect {
+    pub color: Color,
+    pub opacity: u8,
+    pub blend_mode: String,
+}
+
+#[derive(FromVariant, ToVariant, Clone)]
+pub struct StrokeEffect {
+    pub color: Color,
+    pub opacity: u8,
+    pub blend_mode: String,
+    pub size: f64,
+    pub position: String,
+}
+
+impl Effects {
+    fn new(effects: &LayerEffects, global_angle: f64) -> Effects {
+        let color = |color: Rgb| Color::from_rgb(color[0], color[1], color[2]);
+
+        let shadow = |shadow: &Shadow| {
+            let [x, y] = shadow.offset(global_angle);
+
+            ShadowEffect {
+                color: color(shadow.color),
+                opacity: shadow.opacity,
+                blend_mode: shadow.blend_mode.name().to_string(),
+                angle: shadow.angle.unwrap_or(global_angle),
+                distance: shadow.distance,
+                offset: Vector2::new(x as f32, y as f32),
+                spread: shadow.spread,
+                size: shadow.size,
+            }
+        };
+
+        let glow = |glow: &Glow| GlowEffect {
+            color: color(glow.color),
+            opacity: glow.opacity,
+            blend_mode: glow.blend_mode.name().to_string(),
+            spread: glow.spread,
+            size: glow.size,
+        };
+
+        Effects {
+            drop_shadows: effects.drop_shadows.iter().map(shadow).collect(),
+            inner_shadows: effects.inner_shadows.iter().map(shadow).collect(),
+            outer_glows: effects.outer_glows.iter().map(glow).collect(),
+            inner_glows: effects.inner_glows.iter().map(glow).collect(),
+            color_overlays: effects
+                .color_overlays
+                .iter()
+                .map(|overlay| ColorOverlayEffect {
+                    color: color(overlay.color),
+                    opacity: overlay.opacity,
+                    blend_mode: overlay.blend_mode.name().to_string(),
+                })
+                .collect(),
+            strokes: effects
+                .strokes
+                .iter()
+                .map(|stroke| StrokeEffect {
+                    color: color(stroke.color),
+                    opacity: stroke.opacity,
+                    blend_mode: stroke.blend_mode.name().to_string(),
+                    size: stroke.size,
+                    position: match stroke.position {
+                        StrokePosition::Outside => "outside",
+                        StrokePosition::Inside => "inside",
+                        StrokePosition::Center => "center",
+                    }
+                    .to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(ToVariant)]
+pub struct TextData {
+    pub text: String,
+    pub font: String,
+    pub size: f64,
+    pub color: Color,
+    pub alignment: String,
+    pub leading: f64,
+    pub tracking: f64,
+    pub bounds: Rect2,
+    pub box_bounds: Option<Rect2>,
+    pub runs: Vec<TextRun>,
+}
+
+#[derive(ToVariant)]
+pub struct TextRun {
+    pub start: u32,
+    pub length: u32,
+    pub font: String,
+    pub size: f64,
+    pub color: Color,
+    pub leading: f64,
+    pub tracking: f64,
+}
+
+impl From<InternalTextData> for TextData {
+    fn from(data: InternalTextData) -> TextData {
+        let color =
+            |style: &TextStyle| Color::from_rgb(style.color[0], style.color[1], style.color[2]);
+        let rect = |[left, top, right, bottom]: [f64; 4]| Rect2 {
+            position: Vector2::new(left as f32, top as f32),
+            size: Vector2::new((right - left) as f32, (bottom - top) as f32),
+        };
+
+        TextData {
+            font: data.style.font.clone(),
+            size: data.style.size,
+            color: color(&data.style),
+            alignment: data.alignment.name().to_string(),
+            leading: data.style.leading,
+            tracking: data.style.tracking,
+            bounds: rect(data.bounds),
+            box_bounds: data.box_bounds.map(rect),
+            runs: data
+                .runs
+                .iter()
+                .map(|run| TextRun {
+                    start: run.start as u32,
+                    length: run.length as u32,
+                    font: run.style.font.clone(),
+                    size: run.style.size,
+                    color: color(&run.style),
+                    leading: run.style.leading,
+                    tracking: run.style.tracking,
+                })
+                .collect(),
+            text: data.text,
+        }
+    }
+}
+
+impl From<LayerLocks> for Locks {
+    fn from(locks: LayerLocks) -> Locks {
+        Locks {
+            transparency: locks.transparency,
+            pixels: locks.pixels,
+            position: locks.position,
+            all: locks.all,
+        }
     }
 }
 
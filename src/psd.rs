@@ -1,203 +1,847 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 use auto_image_cropper::imagecrop::ImageCrop;
-use psd::{Psd, PsdGroup, PsdLayer};
+use psd::{Psd, PsdError, PsdGroup, PsdLayer};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 
+pub use adjustment::{Adjustment, HueSaturation, Levels};
+pub use blend::Rgb;
+pub use comps::{CompSettings, LayerComp};
+pub use depth::FloatCanvas;
+pub use descriptor::{Descriptor, Value};
+pub use effects::{ColorOverlay, Glow, LayerEffects, Shadow, Stroke, StrokePosition};
+pub use icc::IccProfile;
 pub use psd;
+pub use records::{
+    BlendMode, ColorLabel, Knot, LayerLocks, LayerMask, LayerRecord, PathOperation, PsdFile,
+    RecordError, Subpath, VectorMask,
+};
+pub use render::{Canvas, Overrides, RenderOptions};
+pub use text::{TextAlignment, TextData, TextRun, TextStyle};
+pub use translation::TranslationEntry;
+pub use walk::{Walk, WalkEntry, WalkOrder};
+
+mod adjustment;
+mod blend;
+mod color;
+mod comps;
+mod depth;
+mod descriptor;
+mod effects;
+mod icc;
+mod records;
+mod render;
+mod text;
+mod translation;
+mod vector;
+mod walk;
+
+#[cfg(test)]
+mod fixture;
+
+use records::EMPTY_RECORD;
+
+/// Why [`PsdTree::from_bytes`] couldn't read a document
+#[derive(Debug)]
+pub enum LoadError {
+    /// The `psd` crate couldn't read it
+    Psd(PsdError),
+    /// The header, color mode or layer records couldn't be read
+    Records(RecordError),
+}
+
+impl From<PsdError> for LoadError {
+    fn from(error: PsdError) -> LoadError {
+        LoadError::Psd(error)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PsdTree {
     pub psd: Arc<Psd>,
+    pub file: Arc<PsdFile>,
+    index: Arc<TreeIndex>,
+    /// Applied to everything rendered from this tree, like the layer comp from [`PsdTree::with_comp`]
+    overrides: Arc<Overrides>,
 }
 
 impl PsdTree {
-    pub fn new(psd: Psd) -> Self {
+    /// Builds the tree out of a parsed `psd` and the `bytes` it was parsed from
+    pub fn new(psd: Psd, bytes: &[u8]) -> Result<Self, RecordError> {
+        Ok(Self::from_parts(psd, PsdFile::from_bytes(bytes)?))
+    }
+
+    /// Parses `bytes` into a tree. Documents the `psd` crate can't read the pixels of, like CMYK
+    /// or Lab ones, are converted to 8-bit RGB for it first.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        // Without the records there's no telling which color mode the pixels are in
+        let file = PsdFile::from_bytes(bytes).map_err(LoadError::Records)?;
+
+        // Colors of documents with a profile other than sRGB are converted to sRGB as well
+        let psd = match file.is_rgb_8_bit() && file.color_conversion().is_none() {
+            true => Psd::from_bytes(bytes)?,
+            false => match file.to_rgb_psd() {
+                Some(converted) => Psd::from_bytes(&converted)?,
+                None => Psd::from_bytes(bytes)?,
+            },
+        };
+
+        Ok(Self::from_parts(psd, file))
+    }
+
+    fn from_parts(psd: Psd, file: PsdFile) -> Self {
+        let index = TreeIndex::new(&psd, &file);
+
         PsdTree {
             psd: Arc::from(psd),
+            file: Arc::from(file),
+            index: Arc::from(index),
+            overrides: Arc::default(),
         }
     }
 
+    /// Whether the layer records line up with the layers and groups. When they don't, everything
+    /// read from the records (like masks, effects and text) is left out.
+    pub fn records_match(&self) -> bool {
+        self.index.matched
+    }
+
     pub fn get_children(&self) -> Vec<PsdNode> {
-        let tree = Arc::from(self.clone());
-        let groups = tree
-            .psd
-            .group_ids_in_order()
+        self.index
+            .roots
+            .iter()
+            .map(|id| self.node(*id))
+            .collect::<Vec<PsdNode>>()
+    }
+
+    pub fn get_node(&self, path: &str) -> Option<PsdNode> {
+        self.resolve(None, path)
+    }
+
+    pub fn get_node_by_id(&self, id: NodeId) -> Option<PsdNode> {
+        match id.0 < self.index.entries.len() {
+            true => Some(self.node(id)),
+            false => None,
+        }
+    }
+
+    /// Resolves `path` like a Godot `NodePath`: absolute paths start at the root, relative ones
+    /// at `from` (or the root when `None`). `.` stays put and `..` moves up to the parent group.
+    /// Other segments have to be escaped the same way [`PsdNode::segment`] is.
+    fn resolve(&self, from: Option<NodeId>, path: &str) -> Option<PsdNode> {
+        let mut cursor = match path.starts_with('/') {
+            true => None,
+            false => from,
+        };
+
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            cursor = match segment {
+                "." => cursor,
+                ".." => self.index.entry(cursor?).parent,
+                segment => {
+                    let children = match cursor {
+                        Some(id) => &self.index.entry(id).children,
+                        None => &self.index.roots,
+                    };
+
+                    let child = children
+                        .iter()
+                        .find(|child| self.index.entry(**child).segment == segment)?;
+
+                    Some(*child)
+                }
+            };
+        }
+
+        cursor.map(|id| self.node(id))
+    }
+
+    pub fn find_nodes(&self, query: &NodeQuery) -> Vec<PsdNode> {
+        self.query(None, query)
+    }
+
+    /// Finds every node matching `query`, starting at `from` (or the root when `None`) for
+    /// relative patterns. Results are in tree order, with siblings from bottom to top.
+    fn query(&self, from: Option<NodeId>, query: &NodeQuery) -> Vec<PsdNode> {
+        let from = match query.pattern.starts_with('/') {
+            true => None,
+            false => from,
+        };
+
+        let mut segments = query
+            .pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<&str>>();
+        // Any number of `**` in a row match the same as a single one
+        segments.dedup_by(|segment, previous| *segment == "**" && *previous == "**");
+
+        let mut matches = HashSet::new();
+        self.match_segments(from, &segments, 0, &mut HashSet::new(), &mut matches);
+
+        let mut results = vec![];
+        let mut stack = self
+            .index
+            .roots
             .iter()
-            .filter_map(|id| {
-                let group = tree.psd.groups().get(id).unwrap();
+            .rev()
+            .copied()
+            .collect::<Vec<NodeId>>();
+        while let Some(id) = stack.pop() {
+            let entry = self.index.entry(id);
+            stack.extend(entry.children.iter().rev());
+
+            if !matches.contains(&id) {
+                continue;
+            }
 
-                match group.parent_id() {
-                    Some(_) => None,
-                    None => {
-                        let element = PsdElement::Group(group.to_owned());
+            let node = self.node(id);
+            let element = node.element();
+
+            if (query.node_type.is_none() || query.node_type == Some(element.node_type()))
+                && (query.visible.is_none() || query.visible == Some(element.visible()))
+            {
+                results.push(node);
+            }
+        }
 
-                        Some(PsdNode::new(element, tree.clone(), 0))
+        results
+    }
+
+    /// Adds the nodes below `from` that match `segments` from `index` on to `matches`. Every node
+    /// is only tried once per segment, as patterns with several `**` can reach it in many ways.
+    fn match_segments(
+        &self,
+        from: Option<NodeId>,
+        segments: &[&str],
+        index: usize,
+        visited: &mut HashSet<(Option<NodeId>, usize)>,
+        matches: &mut HashSet<NodeId>,
+    ) {
+        if !visited.insert((from, index)) {
+            return;
+        }
+
+        let children = match from {
+            Some(id) => &self.index.entry(id).children,
+            None => &self.index.roots,
+        };
+
+        match segments.get(index) {
+            None => {
+                if let Some(id) = from {
+                    matches.insert(id);
+                }
+            }
+            Some(&"**") => {
+                self.match_segments(from, segments, index + 1, visited, matches);
+
+                for child in children {
+                    self.match_segments(Some(*child), segments, index, visited, matches);
+                }
+            }
+            Some(segment) => {
+                for child in children {
+                    if glob_match(segment, &self.index.entry(*child).segment) {
+                        self.match_segments(Some(*child), segments, index + 1, visited, matches);
                     }
                 }
+            }
+        }
+    }
+
+    fn node(&self, id: NodeId) -> PsdNode {
+        PsdNode {
+            tree: self.clone(),
+            id,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn walk<'a>(&self, order: WalkOrder) -> Walk<'a> {
+        Walk::new(self.clone(), order, None)
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.walk(WalkOrder::DepthFirst)
+            .map(|entry| entry.node.list_line())
+            .collect::<Vec<String>>()
+    }
+
+    pub fn export_all_to_file(self) {
+        self.export_all_to_file_with(&RenderOptions::default())
+    }
+
+    pub fn export_all_to_file_with(&self, options: &RenderOptions) {
+        for entry in self.walk(WalkOrder::DepthFirst) {
+            if let PsdElement::Layer(..) = entry.node.element() {
+                entry.node.export_to_file_with(options);
+            }
+        }
+    }
+}
+
+impl Serialize for PsdTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tree = serializer.serialize_struct("PsdTree", 6)?;
+        tree.serialize_field("width", &self.psd.width())?;
+        tree.serialize_field("height", &self.psd.height())?;
+        tree.serialize_field("color_mode", self.file.color_mode_name())?;
+        tree.serialize_field("depth", &self.file.depth)?;
+        tree.serialize_field(
+            "color_profile",
+            &self.file.color_profile().map(|profile| profile.name),
+        )?;
+        tree.serialize_field("children", &self.get_children())?;
+        tree.end()
+    }
+}
+
+/// Index of a node in its tree, which stays the same between loads of the same document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(pub usize);
+
+#[derive(Debug, Clone, Copy)]
+enum NodeSource {
+    Group(u32),
+    Layer(usize),
+}
+
+impl NodeSource {
+    fn name(&self, psd: &Psd) -> String {
+        match self {
+            NodeSource::Group(group_id) => psd.groups().get(group_id).unwrap().name(),
+            NodeSource::Layer(index) => psd.layers()[*index].name(),
+        }
+        .trim_matches(char::from(0))
+        .to_string()
+    }
+}
+
+#[derive(Debug)]
+struct NodeEntry {
+    source: NodeSource,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    depth: usize,
+    stacking_index: usize,
+    segment: String,
+    record: Option<usize>,
+    /// Worked out the first time they're asked for, as node handles are made all the time
+    bounds: OnceLock<Bounds>,
+    adjustment: OnceLock<Option<Adjustment>>,
+    effects: OnceLock<LayerEffects>,
+}
+
+/// Parent to children index of all groups and layers, built once when the tree gets created.
+#[derive(Debug)]
+struct TreeIndex {
+    entries: Vec<NodeEntry>,
+    roots: Vec<NodeId>,
+    /// Whether the layer records lined up with the nodes of the `psd` crate
+    matched: bool,
+}
+
+impl TreeIndex {
+    /// Layers are stored bottom to top in the PSD, so their index is their stacking order. A
+    /// group always spans a contiguous range of layers, which means the highest layer index among
+    /// its descendants tells us where the group sits between its siblings. Empty groups don't
+    /// have such an index and end up on top of their siblings.
+    fn new(psd: &Psd, file: &PsdFile) -> TreeIndex {
+        let sources = psd
+            .group_ids_in_order()
+            .iter()
+            .map(|id| NodeSource::Group(*id))
+            .chain((0..psd.layers().len()).map(NodeSource::Layer))
+            .collect::<Vec<NodeSource>>();
+
+        let group_ids = sources
+            .iter()
+            .enumerate()
+            .filter_map(|(index, source)| match source {
+                NodeSource::Group(group_id) => Some((*group_id, NodeId(index))),
+                NodeSource::Layer(_) => None,
             })
-            .collect::<Vec<PsdNode>>();
+            .collect::<HashMap<u32, NodeId>>();
 
-        let layers = tree
-            .psd
-            .layers()
+        let parents = sources
             .iter()
-            .filter_map(|layer| match layer.parent_id() {
-                Some(_) => None,
-                None => {
-                    let element = PsdElement::Layer(layer.to_owned());
+            .map(|source| {
+                let parent_id = match source {
+                    NodeSource::Group(group_id) => psd
+                        .groups()
+                        .get(group_id)
+                        .and_then(|group| group.parent_id()),
+                    NodeSource::Layer(index) => psd.layers()[*index].parent_id(),
+                };
+
+                parent_id.and_then(|parent_id| group_ids.get(&parent_id).copied())
+            })
+            .collect::<Vec<Option<NodeId>>>();
+
+        let mut keys = vec![None; sources.len()];
+        for (index, source) in sources.iter().enumerate() {
+            if let NodeSource::Layer(layer_index) = source {
+                keys[index] = Some(*layer_index);
 
-                    Some(PsdNode::new(element, tree.clone(), 0))
+                let mut cursor = parents[index];
+                while let Some(NodeId(parent)) = cursor {
+                    keys[parent] = keys[parent].max(Some(*layer_index));
+                    cursor = parents[parent];
                 }
+            }
+        }
+
+        let mut roots = vec![];
+        let mut children = vec![vec![]; sources.len()];
+        for (index, parent) in parents.iter().enumerate() {
+            match parent {
+                Some(NodeId(parent)) => children[*parent].push(NodeId(index)),
+                None => roots.push(NodeId(index)),
+            }
+        }
+
+        // Stable sort, so empty groups keep the order they have in the PSD
+        let order = |ids: &mut Vec<NodeId>| {
+            ids.sort_by_key(|NodeId(index)| (keys[*index].is_none(), keys[*index]))
+        };
+        order(&mut roots);
+        children.iter_mut().for_each(order);
+
+        let records = TreeIndex::match_records(psd, file, &sources);
+        let matched = file.layers.is_empty() || records.iter().any(Option::is_some);
+
+        let mut entries = sources
+            .into_iter()
+            .zip(parents)
+            .zip(children)
+            .zip(records)
+            .map(|(((source, parent), children), record)| NodeEntry {
+                source,
+                parent,
+                children,
+                depth: 0,
+                stacking_index: 0,
+                segment: escape_name(&source.name(psd)),
+                record,
+                bounds: OnceLock::new(),
+                adjustment: OnceLock::new(),
+                effects: OnceLock::new(),
             })
-            .collect::<Vec<PsdNode>>();
+            .collect::<Vec<NodeEntry>>();
 
-        // TODO: Add IDs to layers to determine order in root, assume bottom for now
-        [groups, layers].concat()
-    }
+        let mut stack = roots
+            .iter()
+            .enumerate()
+            .map(|(stacking_index, id)| (*id, 0, stacking_index))
+            .collect::<Vec<(NodeId, usize, usize)>>();
 
-    pub fn list(&self) -> Vec<String> {
-        let mut strings = vec![];
-        for node in &self.get_children() {
-            strings.append(&mut node.list())
+        while let Some((NodeId(index), depth, stacking_index)) = stack.pop() {
+            entries[index].depth = depth;
+            entries[index].stacking_index = stacking_index;
+
+            for (stacking_index, child) in entries[index].children.iter().enumerate() {
+                stack.push((*child, depth + 1, stacking_index));
+            }
         }
 
-        strings
+        // Siblings sharing a name get a suffix, the bottom-most one keeps the plain name
+        let mut suffixes = vec![];
+        for siblings in std::iter::once(&roots).chain(entries.iter().map(|entry| &entry.children)) {
+            let mut seen: HashMap<&str, usize> = HashMap::new();
+
+            for NodeId(index) in siblings {
+                let count = seen.entry(&entries[*index].segment).or_insert(0);
+                *count += 1;
+
+                if *count > 1 {
+                    suffixes.push((*index, *count));
+                }
+            }
+        }
+
+        for (index, count) in suffixes {
+            entries[index].segment = format!("{}#{count}", entries[index].segment);
+        }
+
+        TreeIndex {
+            entries,
+            roots,
+            matched,
+        }
     }
 
-    pub fn export_all_to_file(self) {
-        for node in &self.get_children() {
-            if let PsdElement::Layer(_) = &node.element {
-                node.export_to_file();
-            } else {
-                node.export_all_to_file();
+    /// Pairs every source with its raw record. Both use file order: the n-th layer is the n-th
+    /// record that isn't a divider, the n-th group the n-th record opening a group.
+    fn match_records(psd: &Psd, file: &PsdFile, sources: &[NodeSource]) -> Vec<Option<usize>> {
+        let (mut groups, mut layers) = (vec![], vec![]);
+        for (index, record) in file.layers.iter().enumerate() {
+            match record.divider() {
+                Some(1) | Some(2) => groups.push(index),
+                Some(3) => {}
+                _ => layers.push(index),
             }
         }
+
+        if groups.len() != psd.group_ids_in_order().len() || layers.len() != psd.layers().len() {
+            return vec![None; sources.len()];
+        }
+
+        // Groups come first in `sources`, in the same order as `group_ids_in_order`
+        let mut group_records = groups.into_iter();
+        sources
+            .iter()
+            .map(|source| match source {
+                NodeSource::Group(_) => group_records.next(),
+                NodeSource::Layer(index) => Some(layers[*index]),
+            })
+            .collect()
+    }
+
+    fn entry(&self, NodeId(index): NodeId) -> &NodeEntry {
+        &self.entries[index]
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum PsdElement {
-    Group(PsdGroup),
-    Layer(PsdLayer),
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum NodeType {
+    Group,
+    Layer,
+}
+
+/// Pattern to search the tree with. Every segment of `pattern` can use `*` (any amount of
+/// characters) and `?` (exactly one character), while a `**` segment matches any amount of
+/// nested groups. The filters are ignored when they are `None`.
+#[derive(Debug, Clone, Default)]
+pub struct NodeQuery {
+    pub pattern: String,
+    pub node_type: Option<NodeType>,
+    pub visible: Option<bool>,
+}
+
+impl NodeQuery {
+    pub fn new(pattern: &str) -> NodeQuery {
+        NodeQuery {
+            pattern: pattern.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PsdElement<'a> {
+    Group(&'a PsdGroup, &'a LayerRecord),
+    Layer(&'a PsdLayer, &'a LayerRecord),
 }
 
-impl PsdElement {
+impl<'a> PsdElement<'a> {
     pub fn name(&self) -> String {
         match &self {
-            PsdElement::Group(group) => group.name().to_string(),
-            PsdElement::Layer(layer) => layer.name().to_string(),
+            PsdElement::Group(group, _) => group.name().to_string(),
+            PsdElement::Layer(layer, _) => layer.name().to_string(),
         }
         .trim_matches(char::from(0))
         .to_string()
     }
+
+    pub fn node_type(&self) -> NodeType {
+        match self {
+            PsdElement::Group(..) => NodeType::Group,
+            PsdElement::Layer(..) => NodeType::Layer,
+        }
+    }
+
+    pub fn visible(&self) -> bool {
+        match self {
+            PsdElement::Group(group, _) => group.visible(),
+            PsdElement::Layer(layer, _) => layer.visible(),
+        }
+    }
+
+    pub fn opacity(&self) -> u8 {
+        match self {
+            PsdElement::Group(group, _) => group.opacity(),
+            PsdElement::Layer(layer, _) => layer.opacity(),
+        }
+    }
+
+    fn record(&self) -> &'a LayerRecord {
+        match self {
+            PsdElement::Group(_, record) | PsdElement::Layer(_, record) => record,
+        }
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        self.record().blend_mode()
+    }
+
+    /// Whether this is clipped to the base layer below it
+    pub fn is_clipped(&self) -> bool {
+        self.record().is_clipped()
+    }
+
+    pub fn fill_opacity(&self) -> u8 {
+        self.record().fill_opacity()
+    }
+
+    pub fn locks(&self) -> LayerLocks {
+        self.record().locks()
+    }
+
+    pub fn color_label(&self) -> ColorLabel {
+        self.record().color_label()
+    }
+
+    pub fn mask(&self) -> Option<&'a LayerMask> {
+        self.record().mask.as_ref()
+    }
+
+    pub fn vector_mask(&self) -> Option<VectorMask> {
+        self.record().vector_mask()
+    }
+
+    /// What this changes about the nodes below it, when it's an adjustment layer
+    pub fn adjustment(&self) -> Option<Adjustment> {
+        match self {
+            PsdElement::Layer(_, record) => record.adjustment(),
+            PsdElement::Group(..) => None,
+        }
+    }
+
+    /// The text and its styling, when it's a text layer
+    pub fn text_data(&self) -> Option<TextData> {
+        match self {
+            PsdElement::Layer(_, record) => record.text(),
+            PsdElement::Group(..) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Bounds {
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Bounds {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> i32 {
+        self.left + self.width as i32
+    }
+
+    pub fn bottom(&self) -> i32 {
+        self.top + self.height as i32
+    }
+
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        if self.is_empty() {
+            return *other;
+        }
+
+        if other.is_empty() {
+            return *self;
+        }
+
+        let left = self.left.min(other.left);
+        let top = self.top.min(other.top);
+
+        Bounds {
+            left,
+            top,
+            width: (self.right().max(other.right()) - left) as u32,
+            height: (self.bottom().max(other.bottom()) - top) as u32,
+        }
+    }
 }
 
+/// Cheap handle to a group or layer in a [`PsdTree`].
 #[derive(Debug, Clone)]
 pub struct PsdNode {
-    pub tree: Arc<PsdTree>,
-    pub element: PsdElement,
-    pub depth: usize,
+    pub tree: PsdTree,
+    pub id: NodeId,
 }
 
 impl PsdNode {
-    fn new(element: PsdElement, tree: Arc<PsdTree>, depth: usize) -> PsdNode {
-        PsdNode {
-            tree,
-            element,
-            depth,
+    fn entry(&self) -> &NodeEntry {
+        self.tree.index.entry(self.id)
+    }
+
+    pub fn element(&self) -> PsdElement<'_> {
+        match self.entry().source {
+            NodeSource::Group(group_id) => PsdElement::Group(
+                self.tree.psd.groups().get(&group_id).unwrap(),
+                self.record(),
+            ),
+            NodeSource::Layer(index) => {
+                PsdElement::Layer(&self.tree.psd.layers()[index], self.record())
+            }
         }
     }
 
-    pub fn get_children(&self) -> Option<Vec<PsdNode>> {
-        if let PsdElement::Group(group) = &self.element {
-            let groups = self
-                .tree
-                .psd
-                .groups()
-                .iter()
-                .filter_map(|(id, sub_group)| {
-                    if let Some(parent_id) = sub_group.parent_id() {
-                        if group.id() == parent_id {
-                            Some(PsdNode::new(
-                                PsdElement::Group(
-                                    self.tree.psd.groups().get(id).unwrap().to_owned(),
-                                ),
-                                self.tree.clone(),
-                                &self.depth + 1,
-                            ))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<PsdNode>>();
+    /// The raw layer record of this node, for everything the `psd` crate doesn't parse
+    pub fn record(&self) -> &LayerRecord {
+        match self.entry().record {
+            Some(index) => &self.tree.file.layers[index],
+            None => &EMPTY_RECORD,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.entry().depth
+    }
+
+    /// Position among its siblings, 0 being the bottom-most one
+    pub fn stacking_index(&self) -> usize {
+        self.entry().stacking_index
+    }
 
-            let layers = self
-                .tree
-                .psd
-                .layers()
+    /// The name of this node as it appears in paths: escaped with [`escape_name`] and suffixed
+    /// with `#2`, `#3` etc. when siblings below it share the same name.
+    pub fn segment(&self) -> &str {
+        &self.entry().segment
+    }
+
+    pub fn get_node(&self, path: &str) -> Option<PsdNode> {
+        self.tree.resolve(Some(self.id), path)
+    }
+
+    pub fn find_nodes(&self, query: &NodeQuery) -> Vec<PsdNode> {
+        self.tree.query(Some(self.id), query)
+    }
+
+    pub fn get_parent(&self) -> Option<PsdNode> {
+        self.entry().parent.map(|parent| self.tree.node(parent))
+    }
+
+    /// Whether this node and all of its ancestors are visible
+    pub fn effective_visible(&self) -> bool {
+        self.ancestry().all(|node| node.element().visible())
+    }
+
+    /// Opacity of this node multiplied by the opacity of all of its ancestors
+    pub fn effective_opacity(&self) -> u8 {
+        let opacity = self.ancestry().fold(1.0, |opacity, node| {
+            opacity * node.element().opacity() as f32 / 255.0
+        });
+
+        (opacity * 255.0).round() as u8
+    }
+
+    /// This node followed by its parent, grandparent etc.
+    fn ancestry(&self) -> impl Iterator<Item = PsdNode> {
+        std::iter::successors(Some(self.clone()), |node| node.get_parent())
+    }
+
+    /// Pixel bounds of a layer, or the union of the pixel bounds of everything inside a group
+    pub fn bounds(&self) -> Bounds {
+        *self.entry().bounds.get_or_init(|| match self.element() {
+            PsdElement::Layer(layer, _) => Bounds {
+                left: layer.layer_left(),
+                top: layer.layer_top(),
+                width: layer.width().into(),
+                height: layer.height().into(),
+            },
+            // Empty groups have empty bounds, which don't count towards the union
+            PsdElement::Group(..) => self
+                .entry()
+                .children
                 .iter()
-                .filter_map(|layer| {
-                    if let Some(parent_id) = layer.parent_id() {
-                        if group.id() == parent_id {
-                            Some(PsdNode::new(
-                                PsdElement::Layer(layer.to_owned()),
-                                self.tree.clone(),
-                                &self.depth + 1,
-                            ))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<PsdNode>>();
+                .fold(Bounds::default(), |bounds, child| {
+                    bounds.union(&self.tree.node(*child).bounds())
+                }),
+        })
+    }
+
+    /// The adjustment of an adjustment layer
+    pub fn adjustment(&self) -> Option<Adjustment> {
+        self.entry()
+            .adjustment
+            .get_or_init(|| self.element().adjustment())
+            .clone()
+    }
 
-            Some([groups, layers].concat())
-        } else {
-            None
+    pub fn get_children(&self) -> Option<Vec<PsdNode>> {
+        match self.entry().source {
+            NodeSource::Group(_) => Some(
+                self.entry()
+                    .children
+                    .iter()
+                    .map(|id| self.tree.node(*id))
+                    .collect::<Vec<PsdNode>>(),
+            ),
+            NodeSource::Layer(_) => None,
         }
     }
 
-    // TODO: Clean this up with a decent implementation
-    // TODO: Strip layer names to not have whitespace in names
-    pub fn get_path(&self) -> PathBuf {
-        let mut parts: Vec<String> = vec![self.element.name()];
+    /// The node and its siblings, from bottom to top
+    fn siblings(&self) -> &[NodeId] {
+        match self.entry().parent {
+            Some(parent) => &self.tree.index.entry(parent).children,
+            None => &self.tree.index.roots,
+        }
+    }
 
-        let parent_id = match &self.element {
-            PsdElement::Layer(layer) => layer.parent_id(),
-            PsdElement::Group(group) => group.parent_id(),
-        };
+    /// The layer or group a clipped node is clipped to, which is the first sibling below it that
+    /// isn't clipped itself
+    pub fn clipping_base(&self) -> Option<PsdNode> {
+        if !self.element().is_clipped() {
+            return None;
+        }
 
-        let mut cursor = match parent_id {
-            Some(parent_id) => self.tree.psd.groups().get(&parent_id).unwrap(),
-            None => return PathBuf::from(format!("/{}", self.element.name())),
-        };
+        self.siblings()[..self.stacking_index()]
+            .iter()
+            .rev()
+            .map(|id| self.tree.node(*id))
+            .find(|node| !node.element().is_clipped())
+    }
 
-        while let Some(parent_id) = cursor.parent_id() {
-            parts.push(cursor.name().trim_matches(char::from(0)).to_string());
-            cursor = self.tree.psd.groups().get(&parent_id).unwrap();
+    /// The nodes clipped to this one, from bottom to top
+    pub fn clipping_stack(&self) -> Vec<PsdNode> {
+        if self.element().is_clipped() {
+            return vec![];
         }
 
-        parts.push(cursor.name().trim_matches(char::from(0)).to_string());
+        self.siblings()[self.stacking_index() + 1..]
+            .iter()
+            .map(|id| self.tree.node(*id))
+            .take_while(|node| node.element().is_clipped())
+            .collect::<Vec<PsdNode>>()
+    }
+
+    pub fn get_path(&self) -> PathBuf {
+        let mut parts: Vec<&str> = vec![self.segment()];
+
+        let mut cursor = self.entry().parent;
+        while let Some(parent) = cursor {
+            let entry = self.tree.index.entry(parent);
+
+            parts.push(&entry.segment);
+            cursor = entry.parent;
+        }
 
         parts.reverse();
 
-        PathBuf::from(
-            format!("/{}", parts.join("/"))
-                .trim_matches(char::from(0))
-                .to_string(),
-        )
+        PathBuf::from(format!("/{}", parts.join("/")))
     }
 
     pub fn export_to_file(&self) {
-        if let PsdElement::Layer(layer) = &self.element {
+        self.export_to_file_with(&RenderOptions::default())
+    }
+
+    pub fn export_to_file_with(&self, options: &RenderOptions) {
+        if let PsdElement::Layer(..) = self.element() {
             let path = PathBuf::from(format!(
                 "./psd-output{}.png",
                 self.get_path().to_str().unwrap()
@@ -205,23 +849,80 @@ impl PsdNode {
 
             println!("Exporting to {}", path.to_str().unwrap());
 
+            if options.sixteen_bit && self.tree.file.depth > 8 {
+                if let Some(mut canvas) = self.render_float(options) {
+                    canvas.multiply_alpha(
+                        self.tree
+                            .overrides
+                            .merge(&options.overrides)
+                            .opacity_of(self),
+                    );
+
+                    let Some((x, y, width, height)) = canvas.opaque_bounds() else {
+                        println!("Nothing to export in {}", path.to_str().unwrap());
+                        return;
+                    };
+
+                    let canvas = match options.linear {
+                        true => canvas.to_linear(),
+                        false => canvas.to_srgb(),
+                    };
+
+                    let bytes = canvas
+                        .region(x as i32, y as i32, width, height)
+                        .to_rgba16()
+                        .iter()
+                        .flat_map(|value| value.to_be_bytes())
+                        .collect::<Vec<u8>>();
+
+                    write_to_png(
+                        path.as_path(),
+                        (width, height),
+                        png::BitDepth::Sixteen,
+                        options.linear,
+                        bytes,
+                    );
+                    println!("Done exporting {}", path.to_str().unwrap());
+                    return;
+                }
+
+                println!(
+                    "Can't keep 16 bits per channel for {}, exporting an 8-bit PNG",
+                    path.to_str().unwrap()
+                );
+            }
+
             let buffer = match std::panic::catch_unwind(|| {
-                let mut image = ImageCrop::from_buffer(
-                    self.tree.psd.width(),
-                    self.tree.psd.height(),
-                    layer.rgba(),
-                )
-                .unwrap();
+                let opacity = self
+                    .tree
+                    .overrides
+                    .merge(&options.overrides)
+                    .opacity_of(self);
 
-                let (top_left, bottom_right, width, height, crop) = image.auto_crop();
+                // Effects can reach past the document, which the crop takes care of
+                let mut canvas = match options.effects {
+                    true => self.render_effects(options).0,
+                    false => self.render_with(options),
+                };
+                canvas.multiply_alpha(opacity);
 
-                let opacity = layer.opacity();
-                let mut bytes = crop.into_rgba8().into_raw();
-                for byte in bytes.iter_mut().step_by(3) {
-                    *byte *= opacity / 255;
+                if options.linear {
+                    canvas = FloatCanvas::from(&canvas).to_linear().to_canvas(false);
                 }
 
-                (top_left, bottom_right, width, height, bytes)
+                let mut image =
+                    ImageCrop::from_buffer(canvas.width, canvas.height, canvas.into_rgba())
+                        .unwrap();
+
+                let (top_left, bottom_right, width, height, crop) = image.auto_crop();
+
+                (
+                    top_left,
+                    bottom_right,
+                    width,
+                    height,
+                    crop.into_rgba8().into_raw(),
+                )
             }) {
                 Ok(buffer) => buffer,
                 Err(error) => {
@@ -232,48 +933,134 @@ impl PsdNode {
 
             let (_, _, width, height, buffer) = buffer;
 
-            write_to_png(path.as_path(), (width, height), buffer);
+            write_to_png(
+                path.as_path(),
+                (width, height),
+                png::BitDepth::Eight,
+                options.linear,
+                buffer,
+            );
             println!("Done exporting {}", path.to_str().unwrap());
         }
     }
 
-    fn export_all_to_file(&self) {
-        if let PsdElement::Group(_) = &self.element {
-            if let Some(children) = self.get_children() {
-                for child in children {
-                    match &child.element {
-                        PsdElement::Group(_) => child.export_all_to_file(),
-                        PsdElement::Layer(_) => child.export_to_file(),
-                    }
-                }
+    pub fn descendants<'a>(&self, order: WalkOrder) -> Walk<'a> {
+        let path = self.get_path().to_str().unwrap().to_string();
+
+        Walk::new(self.tree.clone(), order, Some((self.id, path)))
+    }
+
+    pub fn export_all_to_file(&self) {
+        for entry in self.descendants(WalkOrder::DepthFirst) {
+            if let PsdElement::Layer(..) = entry.node.element() {
+                entry.node.export_to_file();
             }
         }
     }
 
     pub fn list(&self) -> Vec<String> {
-        let mut strings = vec![];
-        match &self.element {
-            PsdElement::Group(group) => {
-                if let Some(children) = self.get_children() {
-                    let name = group.name().trim_matches(char::from(0));
-                    strings.push(format!("{}[G] {}", "\t".repeat(self.depth), name));
-
-                    for node in children {
-                        strings.append(&mut node.list());
-                    }
-                }
+        std::iter::once(self.list_line())
+            .chain(
+                self.descendants(WalkOrder::DepthFirst)
+                    .map(|entry| entry.node.list_line()),
+            )
+            .collect::<Vec<String>>()
+    }
+
+    fn list_line(&self) -> String {
+        let tag = match self.element() {
+            PsdElement::Group(..) => "G",
+            PsdElement::Layer(..) => "L",
+        };
+
+        format!(
+            "{}[{tag}] {}",
+            "\t".repeat(self.depth()),
+            self.element().name()
+        )
+    }
+}
+
+impl Serialize for PsdNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let element = self.element();
+
+        let mut node = serializer.serialize_struct("PsdNode", 8)?;
+        node.serialize_field("id", &self.id.0)?;
+        node.serialize_field("name", &element.name())?;
+        node.serialize_field("path", &self.get_path())?;
+        node.serialize_field("node_type", &element.node_type())?;
+        node.serialize_field("bounds", &self.bounds())?;
+        node.serialize_field("visible", &element.visible())?;
+        node.serialize_field("opacity", &element.opacity())?;
+        node.serialize_field("children", &self.get_children().unwrap_or_default())?;
+        node.end()
+    }
+}
+
+/// Percent-encodes the characters of a layer or group name that would otherwise be ambiguous in a
+/// node path or invalid in a file name, so paths can double as export locations.
+pub fn escape_name(name: &str) -> String {
+    // Would otherwise be read as relative path segments
+    if name == "." || name == ".." {
+        return name.replace('.', "%2E");
+    }
+
+    let mut escaped = String::with_capacity(name.len());
+
+    for character in name.chars() {
+        match character {
+            '%' | '/' | '\\' | '#' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => {
+                escaped.push_str(&format!("%{:02X}", character as u32))
             }
-            PsdElement::Layer(layer) => {
-                let name = layer.name().trim_matches(char::from(0));
-                strings.push(format!("{}[L] {}", "\t".repeat(self.depth), name));
+            character if character.is_control() => {
+                escaped.push_str(&format!("%{:02X}", character as u32))
             }
+            character => escaped.push(character),
         }
+    }
+
+    escaped
+}
+
+/// Matches a single path segment against a pattern containing `*` and `?` wildcards.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let text = text.chars().collect::<Vec<char>>();
+
+    let (mut pattern_index, mut text_index) = (0, 0);
+    // Position of the last `*` and how much of the text it has eaten so far
+    let mut backtrack: Option<(usize, usize)> = None;
 
-        strings
+    while text_index < text.len() {
+        match pattern.get(pattern_index) {
+            Some('*') => {
+                backtrack = Some((pattern_index, text_index));
+                pattern_index += 1;
+            }
+            Some(character) if *character == '?' || *character == text[text_index] => {
+                pattern_index += 1;
+                text_index += 1;
+            }
+            _ => match backtrack {
+                Some((star_index, star_text_index)) => {
+                    backtrack = Some((star_index, star_text_index + 1));
+                    pattern_index = star_index + 1;
+                    text_index = star_text_index + 1;
+                }
+                None => return false,
+            },
+        }
     }
+
+    pattern[pattern_index..]
+        .iter()
+        .all(|character| *character == '*')
 }
 
-fn write_to_png(path: &Path, size: (u32, u32), bytes: Vec<u8>) {
+/// Writes RGBA `bytes` with `depth` bits per channel, 16-bit values being big endian. The colors
+/// are sRGB, or linear sRGB when `linear`.
+fn write_to_png(path: &Path, size: (u32, u32), depth: png::BitDepth, linear: bool, bytes: Vec<u8>) {
     std::fs::DirBuilder::new()
         .recursive(true)
         .create(path.parent().unwrap())
@@ -286,9 +1073,12 @@ fn write_to_png(path: &Path, size: (u32, u32), bytes: Vec<u8>) {
     let mut encoder = png::Encoder::new(w, size.0, size.1);
 
     encoder.set_color(png::ColorType::Rgba);
-    encoder.set_depth(png::BitDepth::Eight);
-    encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455)); // 1.0 / 2.2, scaled by 100000
-    encoder.set_source_gamma(png::ScaledFloat::new(1.0 / 2.2)); // 1.0 / 2.2, unscaled, but rounded
+    encoder.set_depth(depth);
+    // Colors are converted out of the color profile of the PSD, so they're always sRGB
+    encoder.set_source_gamma(match linear {
+        true => png::ScaledFloat::new(1.0),
+        false => png::ScaledFloat::from_scaled(45455), // 1.0 / 2.2, scaled by 100000
+    });
     let source_chromaticities = png::SourceChromaticities::new(
         // Using unscaled instantiation here
         (0.31270, 0.32900),
@@ -302,3 +1092,239 @@ fn write_to_png(path: &Path, size: (u32, u32), bytes: Vec<u8>) {
 
     writer.write_image_data(&bytes).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::Document;
+    use super::*;
+
+    /// `/Shadow`, `/Face` holding `a/b` and two layers named `Eye`, and a layer named `.` on top
+    fn face() -> PsdTree {
+        Document::new(1, 1)
+            .empty("Shadow")
+            .divider()
+            .empty("a/b")
+            .empty("Eye")
+            .empty("Eye")
+            .group("Face")
+            .empty(".")
+            .tree()
+    }
+
+    fn paths(nodes: Vec<PsdNode>) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|node| node.get_path().to_str().unwrap().to_string())
+            .collect::<Vec<String>>()
+    }
+
+    #[test]
+    fn escaped_names() {
+        assert_eq!(escape_name("Eye"), "Eye");
+        assert_eq!(escape_name("a/b"), "a%2Fb");
+        assert_eq!(escape_name("100%"), "100%25");
+        assert_eq!(escape_name("Take #2?"), "Take %232%3F");
+        assert_eq!(escape_name("tab\t"), "tab%09");
+        assert_eq!(escape_name("."), "%2E");
+        assert_eq!(escape_name(".."), "%2E%2E");
+        assert_eq!(escape_name("..."), "...");
+        assert_eq!(escape_name("Über"), "Über");
+    }
+
+    #[test]
+    fn colliding_names() {
+        let tree = face();
+
+        assert_eq!(
+            paths(
+                tree.walk(WalkOrder::DepthFirst)
+                    .map(|entry| entry.node)
+                    .collect()
+            ),
+            [
+                "/Shadow",
+                "/Face",
+                "/Face/a%2Fb",
+                "/Face/Eye",
+                "/Face/Eye#2",
+                "/%2E"
+            ]
+        );
+
+        // The bottom-most layer keeps the plain name
+        let eye = tree.get_node("/Face/Eye").unwrap();
+        let other = tree.get_node("/Face/Eye#2").unwrap();
+        assert_ne!(eye.id, other.id);
+        assert_eq!(eye.element().name(), other.element().name());
+        assert!(eye.stacking_index() < other.stacking_index());
+    }
+
+    #[test]
+    fn resolved_paths() {
+        let tree = face();
+        let face = tree.get_node("/Face").unwrap();
+        let path = |node: Option<PsdNode>| node.map(|node| node.get_path());
+
+        assert_eq!(
+            path(tree.get_node("Face/a%2Fb")),
+            Some("/Face/a%2Fb".into())
+        );
+        assert!(tree.get_node("/Face/a/b").is_none());
+        assert_eq!(path(tree.get_node("/%2E")), Some("/%2E".into()));
+
+        assert_eq!(path(face.get_node("Eye#2")), Some("/Face/Eye#2".into()));
+        assert_eq!(path(face.get_node("./Eye")), Some("/Face/Eye".into()));
+        assert_eq!(path(face.get_node("../Shadow")), Some("/Shadow".into()));
+        assert_eq!(
+            path(face.get_node("Eye/../a%2Fb")),
+            Some("/Face/a%2Fb".into())
+        );
+        assert_eq!(path(face.get_node("/Shadow")), Some("/Shadow".into()));
+        assert_eq!(path(face.get_node(".")), Some("/Face".into()));
+
+        // There's nothing above the root, and the root itself isn't a node
+        assert!(face.get_node("..").is_none());
+        assert!(face.get_node("../../Shadow").is_none());
+        assert!(tree.get_node("/..").is_none());
+        assert!(tree.get_node("/").is_none());
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*", "Eye"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("Eye*", "Eyes"));
+        assert!(glob_match("*_l", "eye_l"));
+        assert!(!glob_match("*_l", "eye_r"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("E?e", "Eye"));
+        assert!(!glob_match("E?e", "Ee"));
+        assert!(!glob_match("Eye", "eye"));
+    }
+
+    #[test]
+    fn queries() {
+        let tree = face();
+        let find = |pattern: &str| paths(tree.find_nodes(&NodeQuery::new(pattern)));
+
+        assert_eq!(find("/F?ce/*"), ["/Face/a%2Fb", "/Face/Eye", "/Face/Eye#2"]);
+        assert_eq!(find("**/Eye*"), ["/Face/Eye", "/Face/Eye#2"]);
+        assert_eq!(find("**/**/**/Eye"), ["/Face/Eye"]);
+        assert_eq!(find("**").len(), 6);
+        assert_eq!(
+            find("/Face/**"),
+            ["/Face", "/Face/a%2Fb", "/Face/Eye", "/Face/Eye#2"]
+        );
+        assert!(find("/Shadow/*").is_empty());
+
+        let face = tree.get_node("/Face").unwrap();
+        assert_eq!(
+            paths(face.find_nodes(&NodeQuery::new("a*"))),
+            ["/Face/a%2Fb"]
+        );
+    }
+
+    #[test]
+    fn group_bounds() {
+        let tree = Document::new(10, 10)
+            .layer("Below", [0, 0, 10, 10], [0, 0, 0, 255])
+            .divider()
+            .layer("Left", [1, 1, 3, 2], [255, 0, 0, 255])
+            .layer("Right", [6, 7, 9, 9], [0, 0, 255, 255])
+            .divider()
+            .group("Empty")
+            .group("Eyes")
+            .tree();
+
+        let eyes = tree.get_node("/Eyes").unwrap();
+        assert_eq!(
+            eyes.bounds(),
+            Bounds {
+                left: 1,
+                top: 1,
+                width: 8,
+                height: 8
+            }
+        );
+        assert_eq!(
+            tree.get_node("/Eyes/Empty").unwrap().bounds(),
+            Bounds::default()
+        );
+    }
+
+    #[test]
+    fn effective_visibility_and_opacity() {
+        let tree = Document::new(1, 1)
+            .empty("Sky")
+            .divider()
+            .divider()
+            .empty("Eye")
+            .with(|record| record.opacity = 51)
+            .empty("Mouth")
+            .group("Inner")
+            .with(|record| record.opacity = 102)
+            .group("Outer")
+            .with(|record| {
+                record.opacity = 51;
+                record.flags = 2;
+            })
+            .tree();
+        let node = |path: &str| tree.get_node(path).unwrap();
+
+        // 20% of 40% of the opacity of the layer itself
+        assert_eq!(node("/Outer/Inner/Mouth").effective_opacity(), 20);
+        assert_eq!(node("/Outer/Inner/Eye").effective_opacity(), 4);
+        assert_eq!(node("/Outer/Inner").effective_opacity(), 20);
+        assert_eq!(node("/Sky").effective_opacity(), 255);
+
+        let mouth = node("/Outer/Inner/Mouth");
+        assert!(mouth.element().visible());
+        assert!(!mouth.effective_visible());
+        assert!(!node("/Outer/Inner").effective_visible());
+        assert!(!node("/Outer").effective_visible());
+        assert!(node("/Sky").effective_visible());
+    }
+
+    #[test]
+    fn interleaved_stacking() {
+        let tree = Document::new(1, 1)
+            .empty("A")
+            .divider()
+            .empty("B")
+            .empty("C")
+            .group("First")
+            .empty("D")
+            .divider()
+            .group("Empty")
+            .divider()
+            .empty("E")
+            .group("Second")
+            .empty("F")
+            .tree();
+
+        let order = tree
+            .walk(WalkOrder::DepthFirst)
+            .map(|entry| (entry.node.element().name(), entry.node.stacking_index()))
+            .collect::<Vec<(String, usize)>>();
+
+        // Without any layers to place it, the empty group goes on top of its siblings
+        assert_eq!(
+            order
+                .iter()
+                .map(|(name, index)| (name.as_str(), *index))
+                .collect::<Vec<(&str, usize)>>(),
+            [
+                ("A", 0),
+                ("First", 1),
+                ("B", 0),
+                ("C", 1),
+                ("D", 2),
+                ("Second", 3),
+                ("E", 0),
+                ("F", 4),
+                ("Empty", 5)
+            ]
+        );
+    }
+}
@@ -0,0 +1,550 @@
+//! Adjustment layers, which change the colors of everything below them instead of adding pixels.
+
+use super::descriptor::Descriptor;
+use super::records::{Cursor, LayerRecord};
+use super::Rgb;
+
+/// Blocks that turn a layer into an adjustment layer we don't apply (yet)
+const UNSUPPORTED: [&[u8; 4]; 11] = [
+    b"blnc", b"vibA", b"selc", b"mixr", b"phfl", b"expA", b"grdm", b"nvrt", b"post", b"thrs",
+    b"clrL",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Adjustment {
+    /// Composite, red, green and blue levels
+    Levels([Levels; 4]),
+    /// Composite, red, green and blue curves as (input, output) points from 0 to 255, empty
+    /// when the curve of a channel wasn't changed
+    Curves([Vec<[u8; 2]>; 4]),
+    HueSaturation(HueSaturation),
+    BrightnessContrast {
+        /// From -150 to 150, or -100 to 100 when `legacy`
+        brightness: i16,
+        /// From -50 to 100, or -100 to 100 when `legacy`
+        contrast: i16,
+        legacy: bool,
+    },
+    /// An adjustment layer we can't apply, by the key of its block
+    Unsupported(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Levels {
+    pub input_floor: u16,
+    pub input_ceiling: u16,
+    pub output_floor: u16,
+    pub output_ceiling: u16,
+    pub gamma: f32,
+}
+
+impl Default for Levels {
+    fn default() -> Levels {
+        Levels {
+            input_floor: 0,
+            input_ceiling: 255,
+            output_floor: 0,
+            output_ceiling: 255,
+            gamma: 1.0,
+        }
+    }
+}
+
+/// The master settings of a hue/saturation adjustment, the ones for color ranges aren't read
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HueSaturation {
+    /// From -180 to 180
+    pub hue: i16,
+    /// From -100 to 100
+    pub saturation: i16,
+    /// From -100 to 100
+    pub lightness: i16,
+    pub colorize: bool,
+    /// From 0 to 360
+    pub colorize_hue: i16,
+    /// From 0 to 100
+    pub colorize_saturation: i16,
+    /// From -100 to 100
+    pub colorize_lightness: i16,
+}
+
+impl LayerRecord {
+    pub fn adjustment(&self) -> Option<Adjustment> {
+        if let Some(data) = self.block(b"levl") {
+            return Adjustment::read_levels(data);
+        }
+
+        if let Some(data) = self.block(b"curv") {
+            return Adjustment::read_curves(data);
+        }
+
+        if let Some(data) = self.block(b"hue2").or_else(|| self.block(b"hue ")) {
+            return Adjustment::read_hue_saturation(data);
+        }
+
+        // Newer versions of Photoshop keep the real settings in a descriptor next to `brit`
+        if let Some(descriptor) = self.block(b"CgEd").and_then(Descriptor::from_versioned) {
+            return Some(Adjustment::BrightnessContrast {
+                brightness: descriptor.f64("Brgh").unwrap_or_default() as i16,
+                contrast: descriptor.f64("Cntr").unwrap_or_default() as i16,
+                legacy: descriptor.bool("useLegacy").unwrap_or_default(),
+            });
+        }
+
+        if let Some(data) = self.block(b"brit") {
+            let mut cursor = Cursor::new(data);
+
+            return Some(Adjustment::BrightnessContrast {
+                brightness: cursor.i16().ok()?,
+                contrast: cursor.i16().ok()?,
+                legacy: true,
+            });
+        }
+
+        UNSUPPORTED
+            .iter()
+            .find(|key| self.block(key).is_some())
+            .map(|key| Adjustment::Unsupported(String::from_utf8_lossy(*key).to_string()))
+    }
+}
+
+impl Adjustment {
+    fn read_levels(data: &[u8]) -> Option<Adjustment> {
+        let mut cursor = Cursor::new(data);
+        cursor.u16().ok()?;
+
+        let mut levels = [Levels::default(); 4];
+        for level in levels.iter_mut() {
+            *level = Levels {
+                input_floor: cursor.u16().ok()?,
+                input_ceiling: cursor.u16().ok()?,
+                output_floor: cursor.u16().ok()?,
+                output_ceiling: cursor.u16().ok()?,
+                gamma: cursor.u16().ok()? as f32 / 100.0,
+            };
+        }
+
+        Some(Adjustment::Levels(levels))
+    }
+
+    fn read_curves(data: &[u8]) -> Option<Adjustment> {
+        let mut cursor = Cursor::new(data);
+        cursor.skip(3).ok()?;
+        let channels = cursor.u32().ok()?;
+
+        let mut curves: [Vec<[u8; 2]>; 4] = Default::default();
+        for bit in 0..32 {
+            if channels & (1 << bit) == 0 {
+                continue;
+            }
+
+            let mut points = vec![];
+            for _ in 0..cursor.u16().ok()? {
+                let output = cursor.u16().ok()?;
+                let input = cursor.u16().ok()?;
+
+                points.push([input.min(255) as u8, output.min(255) as u8]);
+            }
+
+            if let Some(curve) = curves.get_mut(bit) {
+                *curve = points;
+            }
+        }
+
+        Some(Adjustment::Curves(curves))
+    }
+
+    fn read_hue_saturation(data: &[u8]) -> Option<Adjustment> {
+        let mut cursor = Cursor::new(data);
+        cursor.u16().ok()?;
+        let colorize = cursor.u8().ok()? != 0;
+        cursor.skip(1).ok()?;
+
+        Some(Adjustment::HueSaturation(HueSaturation {
+            colorize,
+            colorize_hue: cursor.i16().ok()?,
+            colorize_saturation: cursor.i16().ok()?,
+            colorize_lightness: cursor.i16().ok()?,
+            hue: cursor.i16().ok()?,
+            saturation: cursor.i16().ok()?,
+            lightness: cursor.i16().ok()?,
+        }))
+    }
+
+    /// Adjusts the colors of RGBA8 pixels in place, leaving their alpha alone
+    pub fn apply(&self, pixels: &mut [u8]) {
+        let tables = match self {
+            Adjustment::Levels(levels) => Some(levels.map(|level| level.table())),
+            Adjustment::Curves(curves) => Some(curves.clone().map(|curve| curve_table(&curve))),
+            Adjustment::BrightnessContrast {
+                brightness,
+                contrast,
+                legacy,
+            } => {
+                let table = brightness_contrast_table(*brightness, *contrast, *legacy);
+                Some([table, identity(), identity(), identity()])
+            }
+            Adjustment::HueSaturation(..) | Adjustment::Unsupported(..) => None,
+        };
+
+        match (self, tables) {
+            // Channels are adjusted on their own first, then together with the composite
+            (_, Some([composite, red, green, blue])) => {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    pixel[0] = composite[red[pixel[0] as usize] as usize];
+                    pixel[1] = composite[green[pixel[1] as usize] as usize];
+                    pixel[2] = composite[blue[pixel[2] as usize] as usize];
+                }
+            }
+            (Adjustment::HueSaturation(settings), None) => {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    let color = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32 / 255.0);
+                    let color = settings.adjust(color);
+
+                    for channel in 0..3 {
+                        pixel[channel] = (color[channel] * 255.0).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Levels {
+    fn table(&self) -> [u8; 256] {
+        let input = (self.input_floor as f32, self.input_ceiling.max(1) as f32);
+        let output = (self.output_floor as f32, self.output_ceiling as f32);
+        let gamma = self.gamma.max(0.01);
+
+        table(|value| {
+            let value = ((value - input.0) / (input.1 - input.0).max(1.0)).clamp(0.0, 1.0);
+            output.0 + value.powf(1.0 / gamma) * (output.1 - output.0)
+        })
+    }
+}
+
+impl HueSaturation {
+    fn adjust(&self, color: Rgb) -> Rgb {
+        let (hue, saturation, lightness) = hsl(color);
+
+        let (hue, saturation, lightness, change) = match self.colorize {
+            true => (
+                self.colorize_hue as f32 / 360.0,
+                self.colorize_saturation as f32 / 100.0,
+                lightness,
+                self.colorize_lightness,
+            ),
+            false => (
+                (hue + self.hue as f32 / 360.0).rem_euclid(1.0),
+                (saturation * (1.0 + self.saturation as f32 / 100.0)).clamp(0.0, 1.0),
+                lightness,
+                self.lightness,
+            ),
+        };
+
+        // Lightness moves towards black or white rather than scaling
+        let change = change as f32 / 100.0;
+        let lightness = match change < 0.0 {
+            true => lightness * (1.0 + change),
+            false => lightness + (1.0 - lightness) * change,
+        };
+
+        rgb(hue, saturation, lightness)
+    }
+}
+
+fn identity() -> [u8; 256] {
+    table(|value| value)
+}
+
+/// Lookup table for a channel, built out of a function from 0.0 to 255.0
+fn table(function: impl Fn(f32) -> f32) -> [u8; 256] {
+    let mut table = [0; 256];
+    for (index, value) in table.iter_mut().enumerate() {
+        *value = function(index as f32).round().clamp(0.0, 255.0) as u8;
+    }
+
+    table
+}
+
+/// Natural cubic spline through the points of a curve, flat beyond its first and last points
+fn curve_table(points: &[[u8; 2]]) -> [u8; 256] {
+    let mut points = points
+        .iter()
+        .map(|point| (point[0] as f32, point[1] as f32))
+        .collect::<Vec<(f32, f32)>>();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    points.dedup_by(|a, b| a.0 == b.0);
+
+    if points.len() < 2 {
+        return identity();
+    }
+
+    let count = points.len();
+    let mut second = vec![0.0; count];
+    let mut temporary = vec![0.0; count];
+
+    for index in 1..count - 1 {
+        let (previous, point, next) = (points[index - 1], points[index], points[index + 1]);
+        let sigma = (point.0 - previous.0) / (next.0 - previous.0);
+        let p = sigma * second[index - 1] + 2.0;
+
+        second[index] = (sigma - 1.0) / p;
+        temporary[index] = (next.1 - point.1) / (next.0 - point.0)
+            - (point.1 - previous.1) / (point.0 - previous.0);
+        temporary[index] =
+            (6.0 * temporary[index] / (next.0 - previous.0) - sigma * temporary[index - 1]) / p;
+    }
+
+    second[count - 1] = 0.0;
+    for index in (0..count - 1).rev() {
+        second[index] = second[index] * second[index + 1] + temporary[index];
+    }
+
+    table(|value| {
+        if value <= points[0].0 {
+            return points[0].1;
+        }
+
+        if value >= points[count - 1].0 {
+            return points[count - 1].1;
+        }
+
+        let upper = points
+            .iter()
+            .position(|point| point.0 >= value)
+            .unwrap_or(1);
+        let (low, high) = (points[upper - 1], points[upper]);
+        let width = high.0 - low.0;
+        let a = (high.0 - value) / width;
+        let b = (value - low.0) / width;
+
+        a * low.1
+            + b * high.1
+            + ((a * a * a - a) * second[upper - 1] + (b * b * b - b) * second[upper])
+                * width
+                * width
+                / 6.0
+    })
+}
+
+/// Photoshop doesn't document how it applies brightness and contrast, so this comes close
+/// instead of matching it exactly
+fn brightness_contrast_table(brightness: i16, contrast: i16, legacy: bool) -> [u8; 256] {
+    let brightness = brightness as f32;
+    let contrast = contrast as f32;
+
+    table(|value| {
+        let value = value / 255.0;
+
+        let value = match legacy {
+            true => {
+                let value = value + brightness / 255.0;
+                (value - 0.5) * (1.0 + contrast / 100.0) + 0.5
+            }
+            false => {
+                let amount = brightness / 150.0;
+                let value = match amount < 0.0 {
+                    true => value * (1.0 + amount),
+                    false => value + (1.0 - value) * amount,
+                };
+
+                // Full contrast would be a vertical line, whose tangent can come out negative
+                let angle = (contrast / 100.0 + 1.0) * std::f32::consts::FRAC_PI_4;
+                let slant = angle.min(std::f32::consts::FRAC_PI_2 - 1e-3).tan();
+                (value - 0.5) * slant + 0.5
+            }
+        };
+
+        value * 255.0
+    })
+}
+
+fn hsl(color: Rgb) -> (f32, f32, f32) {
+    let max = color.iter().copied().fold(f32::MIN, f32::max);
+    let min = color.iter().copied().fold(f32::MAX, f32::min);
+    let lightness = (max + min) / 2.0;
+
+    if max <= min {
+        return (0.0, 0.0, lightness);
+    }
+
+    let delta = max - min;
+    let saturation = match lightness > 0.5 {
+        true => delta / (2.0 - max - min),
+        false => delta / (max + min),
+    };
+
+    let hue = if max == color[0] {
+        (color[1] - color[2]) / delta + if color[1] < color[2] { 6.0 } else { 0.0 }
+    } else if max == color[1] {
+        (color[2] - color[0]) / delta + 2.0
+    } else {
+        (color[0] - color[1]) / delta + 4.0
+    };
+
+    (hue / 6.0, saturation, lightness)
+}
+
+fn rgb(hue: f32, saturation: f32, lightness: f32) -> Rgb {
+    if saturation <= 0.0 {
+        return [lightness; 3];
+    }
+
+    let q = match lightness < 0.5 {
+        true => lightness * (1.0 + saturation),
+        false => lightness + saturation - lightness * saturation,
+    };
+    let p = 2.0 * lightness - q;
+
+    let channel = |t: f32| {
+        let t = t.rem_euclid(1.0);
+
+        if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        }
+    };
+
+    [
+        channel(hue + 1.0 / 3.0),
+        channel(hue),
+        channel(hue - 1.0 / 3.0),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves() {
+        assert_eq!(curve_table(&[]), identity());
+        assert_eq!(curve_table(&[[0, 0], [255, 255]]), identity());
+
+        // Flat beyond the first and last points, a straight line between two points
+        let table = curve_table(&[[192, 255], [64, 0]]);
+        assert_eq!((table[0], table[64], table[128]), (0, 0, 128));
+        assert_eq!((table[192], table[255]), (255, 255));
+
+        // The spline goes through every point
+        let table = curve_table(&[[0, 0], [128, 200], [255, 255]]);
+        assert_eq!((table[0], table[128], table[255]), (0, 200, 255));
+        assert!(table.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn levels() {
+        let table = Levels {
+            input_floor: 50,
+            input_ceiling: 200,
+            ..Levels::default()
+        }
+        .table();
+        assert_eq!((table[0], table[50], table[125]), (0, 0, 128));
+        assert_eq!((table[200], table[255]), (255, 255));
+
+        let table = Levels {
+            gamma: 2.0,
+            ..Levels::default()
+        }
+        .table();
+        assert_eq!((table[0], table[64], table[255]), (0, 128, 255));
+
+        let table = Levels {
+            output_floor: 10,
+            output_ceiling: 20,
+            ..Levels::default()
+        }
+        .table();
+        assert_eq!((table[0], table[128], table[255]), (10, 15, 20));
+    }
+
+    #[test]
+    fn channels_before_composite() {
+        let invert = Levels {
+            output_floor: 255,
+            output_ceiling: 0,
+            ..Levels::default()
+        };
+        let brighten = Levels {
+            output_floor: 100,
+            ..Levels::default()
+        };
+
+        let mut pixels = [10, 20, 30, 77, 255, 0, 0, 0];
+        Adjustment::Levels([brighten, invert, Levels::default(), Levels::default()])
+            .apply(&mut pixels);
+
+        // Red is inverted first, then everything is lifted, without touching the alpha
+        assert_eq!(pixels, [249, 112, 118, 77, 100, 100, 100, 0]);
+    }
+
+    #[test]
+    fn hue_saturation() {
+        let adjust = |settings: HueSaturation, pixel: [u8; 4]| {
+            let mut pixels = pixel;
+            Adjustment::HueSaturation(settings).apply(&mut pixels);
+            pixels
+        };
+        let red = [255, 0, 0, 255];
+
+        let hue = HueSaturation {
+            hue: 120,
+            ..HueSaturation::default()
+        };
+        assert_eq!(adjust(hue, red), [0, 255, 0, 255]);
+
+        let desaturate = HueSaturation {
+            saturation: -100,
+            ..HueSaturation::default()
+        };
+        assert_eq!(adjust(desaturate, red), [128, 128, 128, 255]);
+
+        let darken = HueSaturation {
+            lightness: -100,
+            ..HueSaturation::default()
+        };
+        assert_eq!(adjust(darken, red), [0, 0, 0, 255]);
+
+        let lighten = HueSaturation {
+            lightness: 100,
+            ..HueSaturation::default()
+        };
+        assert_eq!(adjust(lighten, [10, 20, 30, 40]), [255, 255, 255, 40]);
+
+        let colorize = HueSaturation {
+            colorize: true,
+            colorize_hue: 240,
+            colorize_saturation: 100,
+            ..HueSaturation::default()
+        };
+        assert_eq!(adjust(colorize, [128, 128, 128, 255]), [1, 1, 255, 255]);
+    }
+
+    #[test]
+    fn brightness_contrast() {
+        assert_eq!(brightness_contrast_table(0, 0, true), identity());
+        assert_eq!(brightness_contrast_table(0, 0, false), identity());
+
+        let table = brightness_contrast_table(10, 0, true);
+        assert_eq!((table[0], table[100], table[250]), (10, 110, 255));
+
+        assert_eq!(brightness_contrast_table(150, 0, false), [255; 256]);
+        assert_eq!(brightness_contrast_table(-150, 0, false), [0; 256]);
+
+        // Full contrast leaves only black and white
+        let table = brightness_contrast_table(0, 100, false);
+        assert_eq!((table[100], table[200]), (0, 255));
+
+        let table = brightness_contrast_table(0, 50, true);
+        assert_eq!((table[0], table[128], table[255]), (0, 128, 255));
+        assert_eq!(table[64], 32);
+    }
+}
@@ -0,0 +1,327 @@
+use super::BlendMode;
+
+/// An RGB color with channels from 0.0 to 1.0
+pub type Rgb = [f32; 3];
+
+impl BlendMode {
+    /// Mixes a `source` color onto a `backdrop` color the way Photoshop does, not taking alpha
+    /// into account. Pass through and dissolve mix like normal, as they only differ in how
+    /// their alpha is composited.
+    pub fn blend(&self, backdrop: Rgb, source: Rgb) -> Rgb {
+        match self {
+            BlendMode::PassThrough | BlendMode::Normal | BlendMode::Dissolve => source,
+            BlendMode::Darken => separable(backdrop, source, f32::min),
+            BlendMode::Multiply => separable(backdrop, source, multiply),
+            BlendMode::ColorBurn => separable(backdrop, source, color_burn),
+            BlendMode::LinearBurn => separable(backdrop, source, |b, s| (b + s - 1.0).max(0.0)),
+            BlendMode::DarkerColor => match sum(source) < sum(backdrop) {
+                true => source,
+                false => backdrop,
+            },
+            BlendMode::Lighten => separable(backdrop, source, f32::max),
+            BlendMode::Screen => separable(backdrop, source, screen),
+            BlendMode::ColorDodge => separable(backdrop, source, color_dodge),
+            BlendMode::LinearDodge => separable(backdrop, source, |b, s| (b + s).min(1.0)),
+            BlendMode::LighterColor => match sum(source) > sum(backdrop) {
+                true => source,
+                false => backdrop,
+            },
+            BlendMode::Overlay => separable(backdrop, source, |b, s| hard_light(s, b)),
+            BlendMode::SoftLight => separable(backdrop, source, soft_light),
+            BlendMode::HardLight => separable(backdrop, source, hard_light),
+            BlendMode::VividLight => separable(backdrop, source, vivid_light),
+            BlendMode::LinearLight => {
+                separable(backdrop, source, |b, s| (b + 2.0 * s - 1.0).clamp(0.0, 1.0))
+            }
+            BlendMode::PinLight => separable(backdrop, source, |b, s| match s <= 0.5 {
+                true => b.min(2.0 * s),
+                false => b.max(2.0 * s - 1.0),
+            }),
+            BlendMode::HardMix => separable(backdrop, source, |b, s| match b + s >= 1.0 {
+                true => 1.0,
+                false => 0.0,
+            }),
+            BlendMode::Difference => separable(backdrop, source, |b, s| (b - s).abs()),
+            BlendMode::Exclusion => separable(backdrop, source, |b, s| b + s - 2.0 * b * s),
+            BlendMode::Subtract => separable(backdrop, source, |b, s| (b - s).max(0.0)),
+            BlendMode::Divide => separable(backdrop, source, |b, s| match s <= 0.0 {
+                true if b <= 0.0 => 0.0,
+                true => 1.0,
+                false => (b / s).min(1.0),
+            }),
+            BlendMode::Hue => set_luminosity(
+                set_saturation(source, saturation(backdrop)),
+                luminosity(backdrop),
+            ),
+            BlendMode::Saturation => set_luminosity(
+                set_saturation(backdrop, saturation(source)),
+                luminosity(backdrop),
+            ),
+            BlendMode::Color => set_luminosity(source, luminosity(backdrop)),
+            BlendMode::Luminosity => set_luminosity(backdrop, luminosity(source)),
+        }
+    }
+}
+
+fn separable(backdrop: Rgb, source: Rgb, function: impl Fn(f32, f32) -> f32) -> Rgb {
+    [
+        function(backdrop[0], source[0]),
+        function(backdrop[1], source[1]),
+        function(backdrop[2], source[2]),
+    ]
+}
+
+fn multiply(backdrop: f32, source: f32) -> f32 {
+    backdrop * source
+}
+
+fn screen(backdrop: f32, source: f32) -> f32 {
+    backdrop + source - backdrop * source
+}
+
+fn color_burn(backdrop: f32, source: f32) -> f32 {
+    if backdrop >= 1.0 {
+        1.0
+    } else if source <= 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - backdrop) / source).min(1.0)
+    }
+}
+
+fn color_dodge(backdrop: f32, source: f32) -> f32 {
+    if backdrop <= 0.0 {
+        0.0
+    } else if source >= 1.0 {
+        1.0
+    } else {
+        (backdrop / (1.0 - source)).min(1.0)
+    }
+}
+
+fn hard_light(backdrop: f32, source: f32) -> f32 {
+    match source <= 0.5 {
+        true => multiply(backdrop, 2.0 * source),
+        false => screen(backdrop, 2.0 * source - 1.0),
+    }
+}
+
+/// Photoshop's soft light, which is slightly different from the one in the W3C spec
+fn soft_light(backdrop: f32, source: f32) -> f32 {
+    match source <= 0.5 {
+        true => 2.0 * backdrop * source + backdrop * backdrop * (1.0 - 2.0 * source),
+        false => 2.0 * backdrop * (1.0 - source) + backdrop.sqrt() * (2.0 * source - 1.0),
+    }
+}
+
+fn vivid_light(backdrop: f32, source: f32) -> f32 {
+    match source <= 0.5 {
+        true => color_burn(backdrop, 2.0 * source),
+        false => color_dodge(backdrop, 2.0 * source - 1.0),
+    }
+}
+
+fn sum(color: Rgb) -> f32 {
+    color[0] + color[1] + color[2]
+}
+
+fn luminosity(color: Rgb) -> f32 {
+    0.3 * color[0] + 0.59 * color[1] + 0.11 * color[2]
+}
+
+fn saturation(color: Rgb) -> f32 {
+    color.iter().copied().fold(f32::MIN, f32::max) - color.iter().copied().fold(f32::MAX, f32::min)
+}
+
+fn set_luminosity(color: Rgb, target: f32) -> Rgb {
+    let delta = target - luminosity(color);
+    let color = color.map(|channel| channel + delta);
+
+    // Clip the color back into range while keeping its luminosity
+    let luminosity = luminosity(color);
+    let min = color.iter().copied().fold(f32::MAX, f32::min);
+    let max = color.iter().copied().fold(f32::MIN, f32::max);
+
+    color.map(|channel| {
+        let mut channel = channel;
+
+        if min < 0.0 {
+            channel = luminosity + (channel - luminosity) * luminosity / (luminosity - min);
+        }
+
+        if max > 1.0 {
+            channel = luminosity + (channel - luminosity) * (1.0 - luminosity) / (max - luminosity);
+        }
+
+        channel
+    })
+}
+
+fn set_saturation(color: Rgb, target: f32) -> Rgb {
+    let min = color.iter().copied().fold(f32::MAX, f32::min);
+    let max = color.iter().copied().fold(f32::MIN, f32::max);
+
+    color.map(|channel| match max > min {
+        true => (channel - min) * target / (max - min),
+        false => 0.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psd::Canvas;
+
+    fn assert_close(actual: Rgb, expected: Rgb) {
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert!(
+                (actual - expected).abs() < 0.005,
+                "expected {expected:?}, got {actual:?}"
+            );
+        }
+    }
+
+    fn canvas(pixels: &[[u8; 4]]) -> Canvas {
+        Canvas::from_rgba(pixels.len() as u32, 1, pixels.concat())
+    }
+
+    #[test]
+    fn separable_modes() {
+        let backdrop = [0.2, 0.5, 0.8];
+        let source = [0.6, 0.5, 0.1];
+
+        assert_close(BlendMode::Normal.blend(backdrop, source), source);
+        assert_close(BlendMode::Darken.blend(backdrop, source), [0.2, 0.5, 0.1]);
+        assert_close(BlendMode::Lighten.blend(backdrop, source), [0.6, 0.5, 0.8]);
+        assert_close(
+            BlendMode::Multiply.blend(backdrop, source),
+            [0.12, 0.25, 0.08],
+        );
+        assert_close(
+            BlendMode::Screen.blend(backdrop, source),
+            [0.68, 0.75, 0.82],
+        );
+        assert_close(
+            BlendMode::Overlay.blend(backdrop, source),
+            [0.24, 0.5, 0.64],
+        );
+        assert_close(
+            BlendMode::HardLight.blend(backdrop, source),
+            [0.36, 0.5, 0.16],
+        );
+        assert_close(
+            BlendMode::SoftLight.blend(backdrop, source),
+            [0.2494, 0.5, 0.672],
+        );
+        assert_close(
+            BlendMode::ColorDodge.blend(backdrop, source),
+            [0.5, 1.0, 0.8889],
+        );
+        assert_close(
+            BlendMode::ColorBurn.blend(backdrop, source),
+            [0.0, 0.0, 0.0],
+        );
+        assert_close(
+            BlendMode::LinearDodge.blend(backdrop, source),
+            [0.8, 1.0, 0.9],
+        );
+        assert_close(
+            BlendMode::LinearBurn.blend(backdrop, source),
+            [0.0, 0.0, 0.0],
+        );
+        assert_close(
+            BlendMode::Difference.blend(backdrop, source),
+            [0.4, 0.0, 0.7],
+        );
+        assert_close(
+            BlendMode::Exclusion.blend(backdrop, source),
+            [0.56, 0.5, 0.74],
+        );
+        assert_close(BlendMode::Subtract.blend(backdrop, source), [0.0, 0.0, 0.7]);
+        assert_close(
+            BlendMode::Divide.blend(backdrop, source),
+            [0.3333, 1.0, 1.0],
+        );
+        assert_close(BlendMode::PinLight.blend(backdrop, source), [0.2, 0.5, 0.2]);
+        assert_close(BlendMode::HardMix.blend(backdrop, source), [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn extremes_stay_in_range() {
+        let modes = [
+            BlendMode::ColorBurn,
+            BlendMode::ColorDodge,
+            BlendMode::VividLight,
+            BlendMode::LinearLight,
+            BlendMode::Divide,
+        ];
+
+        for mode in modes {
+            for backdrop in [0.0, 1.0] {
+                for source in [0.0, 1.0] {
+                    let [channel, ..] = mode.blend([backdrop; 3], [source; 3]);
+                    assert!((0.0..=1.0).contains(&channel), "{mode:?} gave {channel}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn darker_and_lighter_color_pick_whole_colors() {
+        let backdrop = [0.9, 0.1, 0.1];
+        let source = [0.3, 0.3, 0.3];
+
+        assert_close(BlendMode::DarkerColor.blend(backdrop, source), source);
+        assert_close(BlendMode::LighterColor.blend(backdrop, source), backdrop);
+    }
+
+    #[test]
+    fn non_separable_modes() {
+        let red = [1.0, 0.0, 0.0];
+        let gray = [0.5, 0.5, 0.5];
+
+        // Red at the luminosity of gray, clipped back into range
+        assert_close(BlendMode::Color.blend(gray, red), [1.0, 0.2857, 0.2857]);
+        assert_close(
+            BlendMode::Luminosity.blend(red, gray),
+            [1.0, 0.2857, 0.2857],
+        );
+
+        // Gray has no saturation, so neither does the result
+        assert_close(BlendMode::Hue.blend(gray, red), [0.5, 0.5, 0.5]);
+        assert_close(BlendMode::Saturation.blend(red, gray), [0.3, 0.3, 0.3]);
+
+        for mode in [BlendMode::Hue, BlendMode::Color, BlendMode::Luminosity] {
+            let color = mode.blend([0.1, 0.8, 0.3], [0.9, 0.2, 0.6]);
+            assert!(color.iter().all(|channel| (0.0..=1.0).contains(channel)));
+        }
+    }
+
+    #[test]
+    fn draw_with_blend_mode() {
+        let mut backdrop = canvas(&[[200, 100, 50, 255], [200, 100, 50, 255], [0, 0, 0, 0]]);
+        let source = canvas(&[
+            [128, 128, 128, 255],
+            [128, 128, 128, 0],
+            [128, 128, 128, 255],
+        ]);
+
+        backdrop.draw(&source, 255, BlendMode::Multiply);
+
+        // Multiplied where both are opaque, untouched where the source is transparent and the
+        // plain source where there's nothing to blend with
+        assert_eq!(backdrop.pixels[0..4], [100, 50, 25, 255]);
+        assert_eq!(backdrop.pixels[4..8], [200, 100, 50, 255]);
+        assert_eq!(backdrop.pixels[8..12], [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn draw_with_opacity() {
+        let mut backdrop = canvas(&[[0, 0, 0, 255]]);
+        let source = canvas(&[[255, 255, 255, 255]]);
+
+        backdrop.draw(&source, 51, BlendMode::Screen);
+
+        assert_eq!(backdrop.pixels, vec![51, 51, 51, 255]);
+    }
+}
@@ -0,0 +1,488 @@
+//! Color modes other than RGB. The `psd` crate only reads the pixels of RGB documents, so the
+//! channels of other documents are converted to RGBA here, and the document is written back out as
+//! an 8-bit RGB PSD for the `psd` crate to read.
+
+use super::depth::quantize;
+use super::records::{decode_samples, to_samples, unpack, Cursor, LayerRecord, PsdFile};
+
+const BITMAP: u16 = 0;
+const GRAYSCALE: u16 = 1;
+const INDEXED: u16 = 2;
+const RGB: u16 = 3;
+const CMYK: u16 = 4;
+const MULTICHANNEL: u16 = 7;
+const DUOTONE: u16 = 8;
+const LAB: u16 = 9;
+
+/// Image resource holding the palette index that is transparent in indexed documents
+const TRANSPARENT_INDEX: u16 = 1047;
+
+/// Image resource holding the embedded ICC profile
+pub(super) const ICC_PROFILE: u16 = 1039;
+
+impl PsdFile {
+    pub fn color_mode_name(&self) -> &'static str {
+        match self.color_mode {
+            BITMAP => "bitmap",
+            GRAYSCALE => "grayscale",
+            INDEXED => "indexed",
+            RGB => "rgb",
+            CMYK => "cmyk",
+            MULTICHANNEL => "multichannel",
+            DUOTONE => "duotone",
+            LAB => "lab",
+            _ => "unknown",
+        }
+    }
+
+    /// Whether the pixels can be converted to RGB, which is every mode except multichannel
+    pub fn supports_color_mode(&self) -> bool {
+        matches!(
+            self.color_mode,
+            BITMAP | GRAYSCALE | INDEXED | RGB | CMYK | DUOTONE | LAB
+        )
+    }
+
+    /// Whether the `psd` crate can read the document as it is
+    pub fn is_rgb_8_bit(&self) -> bool {
+        self.color_mode == RGB && self.depth == 8
+    }
+
+    /// Whether colors are stored in linear light, which Photoshop does for 32-bit documents
+    pub fn is_linear(&self) -> bool {
+        self.depth == 32
+    }
+
+    fn color_channels(&self) -> usize {
+        match self.color_mode {
+            RGB | LAB => 3,
+            CMYK => 4,
+            _ => 1,
+        }
+    }
+
+    /// Turns the color channels of an image (one float per pixel each) into RGBA
+    fn to_rgba(&self, colors: &[Vec<f32>], alpha: Option<&[f32]>) -> Vec<f32> {
+        let length = colors.first().map(Vec::len).unwrap_or_default();
+        let sample = |channel: usize, index: usize| colors[channel][index];
+
+        let transparent = match self.color_mode {
+            INDEXED => self
+                .resource(TRANSPARENT_INDEX)
+                .and_then(|resource| resource.data.get(..2))
+                .map(|index| u16::from_be_bytes([index[0], index[1]]) as usize),
+            _ => None,
+        };
+
+        let profile = self.color_conversion();
+
+        let mut pixels = Vec::with_capacity(length * 4);
+        for index in 0..length {
+            let [red, green, blue] = match self.color_mode {
+                RGB => [sample(0, index), sample(1, index), sample(2, index)],
+                INDEXED => {
+                    let color = (sample(0, index) * 255.0).round() as usize;
+                    let palette = |offset: usize| {
+                        self.color_mode_data
+                            .get(offset + color)
+                            .map(|value| *value as f32 / 255.0)
+                            .unwrap_or_default()
+                    };
+
+                    [palette(0), palette(256), palette(512)]
+                }
+                CMYK => {
+                    // Stored inverted, 1.0 being no ink at all
+                    let black = sample(3, index);
+                    [0, 1, 2].map(|channel| sample(channel, index) * black)
+                }
+                LAB => lab_to_srgb(sample(0, index), sample(1, index), sample(2, index)),
+                // Duotone documents keep the grayscale image the inks are applied to
+                _ => [sample(0, index); 3],
+            };
+
+            let [red, green, blue] = match &profile {
+                Some(profile) => profile.to_srgb([red, green, blue], self.is_linear()),
+                None => [red, green, blue],
+            };
+
+            let alpha = match transparent {
+                Some(transparent) if (sample(0, index) * 255.0).round() as usize == transparent => {
+                    0.0
+                }
+                _ => alpha.map(|alpha| alpha[index]).unwrap_or(1.0),
+            };
+
+            pixels.extend_from_slice(&[red, green, blue, alpha]);
+        }
+
+        pixels
+    }
+
+    /// The pixels of a layer as RGBA at the precision of the document, the size of its rect
+    pub fn layer_samples(&self, record: &LayerRecord) -> Option<Vec<f32>> {
+        let width = (record.right - record.left).max(0) as usize;
+        let height = (record.bottom - record.top).max(0) as usize;
+
+        let channel = |id: i16| {
+            let index = record
+                .channels
+                .iter()
+                .position(|channel| channel.id == id)?;
+            decode_samples(
+                record.channel_data.get(index)?,
+                width,
+                height,
+                self.depth,
+                self.version == 2,
+            )
+        };
+
+        let colors = (0..self.color_channels() as i16)
+            .map(channel)
+            .collect::<Option<Vec<Vec<f32>>>>()?;
+
+        Some(self.to_rgba(&colors, channel(-1).as_deref()))
+    }
+
+    /// The pixels of a layer as 8-bit RGBA, the size of its rect
+    pub fn layer_rgba(&self, record: &LayerRecord) -> Option<Vec<u8>> {
+        let width = (record.right - record.left).max(0) as u32;
+        let samples = self.layer_samples(record)?;
+
+        Some(quantize(&samples, width, self.is_linear(), false))
+    }
+
+    /// The merged image as RGBA at the precision of the document, the size of the document
+    pub fn composite_samples(&self) -> Option<Vec<f32>> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let channels = self.channels as usize;
+
+        let mut cursor = Cursor::new(&self.image_data);
+        let compression = cursor.u16().ok()?;
+
+        // Every channel is stored after the other, as if they were one tall image
+        let raw = unpack(
+            &mut cursor,
+            compression,
+            width,
+            height * channels,
+            self.depth,
+            self.version == 2,
+        )?;
+
+        let length = raw.len() / channels.max(1);
+        let colors = raw
+            .chunks(length.max(1))
+            .take(self.color_channels())
+            .map(|channel| to_samples(channel, width, height, self.depth))
+            .collect::<Vec<Vec<f32>>>();
+
+        match colors.len() == self.color_channels() {
+            true => Some(self.to_rgba(&colors, None)),
+            false => None,
+        }
+    }
+
+    /// The merged image as 8-bit RGBA, the size of the document
+    pub fn composite_rgba(&self) -> Option<Vec<u8>> {
+        let samples = self.composite_samples()?;
+
+        Some(quantize(&samples, self.width, self.is_linear(), false))
+    }
+
+    /// The document as an 8-bit RGB PSD, keeping everything but the pixels of masks
+    pub fn to_rgb_psd(&self) -> Option<Vec<u8>> {
+        if !self.supports_color_mode() {
+            return None;
+        }
+
+        let mut records = vec![];
+        let mut image_data = vec![];
+        for record in self.layers.iter() {
+            let width = (record.right - record.left).max(0) as usize;
+            let height = (record.bottom - record.top).max(0) as usize;
+            let pixels = self
+                .layer_rgba(record)
+                .unwrap_or_else(|| vec![0; width * height * 4]);
+
+            records.extend_from_slice(&record.top.to_be_bytes());
+            records.extend_from_slice(&record.left.to_be_bytes());
+            records.extend_from_slice(&record.bottom.to_be_bytes());
+            records.extend_from_slice(&record.right.to_be_bytes());
+            records.extend_from_slice(&4u16.to_be_bytes());
+
+            for (id, offset) in [(-1i16, 3), (0, 0), (1, 1), (2, 2)] {
+                records.extend_from_slice(&id.to_be_bytes());
+                records.extend_from_slice(&(2 + (width * height) as u32).to_be_bytes());
+
+                // Uncompressed
+                image_data.extend_from_slice(&0u16.to_be_bytes());
+                image_data.extend(pixels.iter().skip(offset).step_by(4));
+            }
+
+            records.extend_from_slice(b"8BIM");
+            records.extend_from_slice(&record.blend_key);
+            records.extend_from_slice(&[record.opacity, record.clipping, record.flags, 0]);
+
+            // Without a mask or blending ranges
+            let mut extra = vec![0; 8];
+
+            let name = record
+                .name
+                .chars()
+                .take(255)
+                .map(|character| character as u8)
+                .collect::<Vec<u8>>();
+            extra.push(name.len() as u8);
+            extra.extend_from_slice(&name);
+            extra.resize(extra.len() + (4 - (name.len() + 1) % 4) % 4, 0);
+
+            for block in record.blocks.iter() {
+                let length = block.data.len() + block.data.len() % 2;
+
+                extra.extend_from_slice(b"8BIM");
+                extra.extend_from_slice(&block.key);
+                extra.extend_from_slice(&(length as u32).to_be_bytes());
+                extra.extend_from_slice(&block.data);
+                extra.resize(extra.len() + block.data.len() % 2, 0);
+            }
+
+            records.extend_from_slice(&(extra.len() as u32).to_be_bytes());
+            records.extend_from_slice(&extra);
+        }
+
+        let mut layer_info = vec![];
+        if !self.layers.is_empty() {
+            layer_info.extend_from_slice(&(self.layers.len() as i16).to_be_bytes());
+            layer_info.extend_from_slice(&records);
+            layer_info.extend_from_slice(&image_data);
+            layer_info.resize(layer_info.len() + layer_info.len() % 2, 0);
+        }
+
+        let mut bytes = vec![];
+        bytes.extend_from_slice(b"8BPS");
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&[0; 6]);
+        bytes.extend_from_slice(&3u16.to_be_bytes());
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.width.to_be_bytes());
+        bytes.extend_from_slice(&8u16.to_be_bytes());
+        bytes.extend_from_slice(&RGB.to_be_bytes());
+
+        // No color mode data
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut resources = vec![];
+        // The pixels are sRGB now, so the color profile would be wrong
+        for resource in self
+            .resources
+            .iter()
+            .filter(|resource| resource.id != ICC_PROFILE)
+        {
+            let name = resource
+                .name
+                .chars()
+                .take(255)
+                .map(|character| character as u8)
+                .collect::<Vec<u8>>();
+
+            resources.extend_from_slice(b"8BIM");
+            resources.extend_from_slice(&resource.id.to_be_bytes());
+            resources.push(name.len() as u8);
+            resources.extend_from_slice(&name);
+            resources.resize(resources.len() + (name.len() + 1) % 2, 0);
+            resources.extend_from_slice(&(resource.data.len() as u32).to_be_bytes());
+            resources.extend_from_slice(&resource.data);
+            resources.resize(resources.len() + resource.data.len() % 2, 0);
+        }
+        bytes.extend_from_slice(&(resources.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&resources);
+
+        // Layer info followed by an empty global layer mask
+        bytes.extend_from_slice(&(layer_info.len() as u32 + 8).to_be_bytes());
+        bytes.extend_from_slice(&(layer_info.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&layer_info);
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+
+        let (width, height) = (self.width as usize, self.height as usize);
+        let composite = self
+            .composite_rgba()
+            .unwrap_or_else(|| vec![0; width * height * 4]);
+
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+        for channel in 0..3 {
+            bytes.extend(composite.iter().skip(channel).step_by(4));
+        }
+
+        Some(bytes)
+    }
+}
+
+/// Converts a Lab color with channels from 0.0 to 1.0, with the D50 white point Photoshop uses,
+/// to sRGB
+fn lab_to_srgb(lightness: f32, a: f32, b: f32) -> [f32; 3] {
+    let y = (lightness as f64 * 100.0 + 16.0) / 116.0;
+    let x = y + (a as f64 * 255.0 - 128.0) / 500.0;
+    let z = y - (b as f64 * 255.0 - 128.0) / 200.0;
+
+    let inverse = |t: f64| match t > 6.0 / 29.0 {
+        true => t.powi(3),
+        false => 3.0 * (6.0 / 29.0f64).powi(2) * (t - 4.0 / 29.0),
+    };
+    let [x, y, z] = [0.96422 * inverse(x), inverse(y), 0.82521 * inverse(z)];
+
+    xyz_to_linear_srgb([x, y, z]).map(|channel| encode_srgb(channel.clamp(0.0, 1.0)) as f32)
+}
+
+/// Converts XYZ with the D50 white point to linear sRGB, adapted to D65 with the Bradford transform
+pub(super) fn xyz_to_linear_srgb([x, y, z]: [f64; 3]) -> [f64; 3] {
+    [
+        3.1338561 * x - 1.6168667 * y - 0.4906146 * z,
+        -0.9787684 * x + 1.9161415 * y + 0.0334540 * z,
+        0.0719453 * x - 0.2289914 * y + 1.4052427 * z,
+    ]
+}
+
+/// Applies the sRGB transfer function to a linear value from 0.0, extending the curve past 1.0
+pub(super) fn encode_srgb(linear: f64) -> f64 {
+    match linear <= 0.0031308 {
+        true => linear * 12.92,
+        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+/// Undoes the sRGB transfer function, the other way around from [`encode_srgb`]
+pub(super) fn decode_srgb(encoded: f64) -> f64 {
+    match encoded <= 0.04045 {
+        true => encoded / 12.92,
+        false => ((encoded + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::records::{ChannelInfo, ImageResource, EMPTY_RECORD};
+    use super::*;
+
+    /// A document without layers whose merged image is stored uncompressed
+    fn document(color_mode: u16, depth: u16, width: u32, channels: &[&[u8]]) -> PsdFile {
+        let mut image_data = vec![0, 0];
+        for channel in channels {
+            image_data.extend_from_slice(channel);
+        }
+
+        PsdFile {
+            version: 1,
+            channels: channels.len() as u16,
+            width,
+            height: 1,
+            depth,
+            color_mode,
+            image_data,
+            ..PsdFile::default()
+        }
+    }
+
+    #[test]
+    fn cmyk_without_ink_is_white() {
+        let file = document(
+            CMYK,
+            8,
+            2,
+            &[&[255, 0], &[255, 255], &[255, 255], &[255, 255]],
+        );
+
+        assert_eq!(
+            file.composite_rgba().unwrap(),
+            vec![255, 255, 255, 255, 0, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn lab_white_and_black() {
+        let file = document(LAB, 8, 2, &[&[255, 0], &[128, 128], &[128, 128]]);
+
+        assert_eq!(
+            file.composite_rgba().unwrap(),
+            vec![255, 255, 255, 255, 0, 0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn indexed_palette_and_transparent_index() {
+        let mut file = document(INDEXED, 8, 2, &[&[0, 1]]);
+        file.color_mode_data = vec![0; 768];
+        file.color_mode_data[0] = 10;
+        file.color_mode_data[256] = 20;
+        file.color_mode_data[512] = 30;
+        file.resources.push(ImageResource {
+            id: TRANSPARENT_INDEX,
+            name: String::new(),
+            data: vec![0, 1],
+        });
+
+        assert_eq!(
+            file.composite_rgba().unwrap(),
+            vec![10, 20, 30, 255, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn bitmap_bits_are_black() {
+        let file = document(BITMAP, 1, 3, &[&[0b0100_0000]]);
+
+        assert_eq!(
+            file.composite_rgba().unwrap(),
+            vec![255, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn srgb_round_trip() {
+        for value in [0.0, 0.002, 0.2, 0.5, 1.0] {
+            assert!((decode_srgb(encode_srgb(value)) - value).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn rgb_psd_of_cmyk_document() {
+        let mut file = document(CMYK, 8, 1, &[&[255], &[255], &[255], &[255]]);
+
+        // A 1x1 layer with full cyan and no other ink
+        let mut layer = EMPTY_RECORD.clone();
+        layer.name = "Cyan".to_string();
+        layer.bottom = 1;
+        layer.right = 1;
+        layer.channels = [0, 1, 2, 3, -1]
+            .map(|id| ChannelInfo { id, length: 3 })
+            .to_vec();
+        layer.channel_data = [0, 255, 255, 255, 255]
+            .map(|value| vec![0, 0, value])
+            .to_vec();
+        file.layers.push(layer);
+
+        file.resources.push(ImageResource {
+            id: ICC_PROFILE,
+            name: String::new(),
+            data: vec![0; 4],
+        });
+
+        let bytes = file.to_rgb_psd().unwrap();
+        let rgb = PsdFile::from_bytes(&bytes).unwrap();
+
+        assert!(rgb.is_rgb_8_bit());
+        assert!(rgb.resource(ICC_PROFILE).is_none());
+        assert_eq!(rgb.composite_rgba().unwrap(), vec![255, 255, 255, 255]);
+        assert_eq!(rgb.layers.len(), 1);
+        assert_eq!(rgb.layers[0].name, "Cyan");
+        assert_eq!(
+            rgb.layer_rgba(&rgb.layers[0]).unwrap(),
+            vec![0, 255, 255, 255]
+        );
+
+        let psd = psd::Psd::from_bytes(&bytes).unwrap();
+        assert_eq!((psd.width(), psd.height()), (1, 1));
+        assert_eq!(psd.layers().len(), 1);
+    }
+}
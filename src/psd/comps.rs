@@ -0,0 +1,325 @@
+//! Layer comps, the snapshots of layer visibility, position and appearance that can be switched
+//! between in Photoshop's Layer Comps panel.
+
+use super::descriptor::Descriptor;
+use super::records::{Cursor, LayerRecord, PsdFile};
+use super::render::Overrides;
+use super::{BlendMode, NodeId, PsdElement, PsdNode, PsdTree};
+
+use std::sync::Arc;
+
+/// Image resource holding the list of layer comps
+const LAYER_COMPS: u16 = 1065;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerComp {
+    pub id: u32,
+    pub name: String,
+    pub comment: String,
+    /// Whether the comp captured the visibility of layers
+    pub visibility: bool,
+    /// Whether the comp captured the position of layers
+    pub position: bool,
+    /// Whether the comp captured the opacity and blend mode of layers
+    pub appearance: bool,
+}
+
+/// How a layer or group looks in a layer comp, `None` where it looks like it was saved
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompSettings {
+    pub visible: Option<bool>,
+    /// Distance in pixels the layer is moved from where it was saved
+    pub offset: Option<(i32, i32)>,
+    pub opacity: Option<u8>,
+    pub blend_mode: Option<BlendMode>,
+}
+
+impl PsdFile {
+    pub fn layer_comps(&self) -> Vec<LayerComp> {
+        let descriptor = match self
+            .resource(LAYER_COMPS)
+            .and_then(|resource| Descriptor::from_versioned(&resource.data))
+        {
+            Some(descriptor) => descriptor,
+            None => return vec![],
+        };
+
+        descriptor
+            .list("list")
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|value| {
+                let comp = value.as_descriptor()?;
+                let captured = comp.f64("capturedInfo").unwrap_or_default() as u32;
+
+                Some(LayerComp {
+                    id: comp.f64("compID")? as u32,
+                    name: comp.text("Nm  ").unwrap_or_default().to_string(),
+                    comment: comp.text("comment").unwrap_or_default().to_string(),
+                    visibility: captured & 1 != 0,
+                    position: captured & 2 != 0,
+                    appearance: captured & 4 != 0,
+                })
+            })
+            .collect::<Vec<LayerComp>>()
+    }
+}
+
+impl LayerRecord {
+    /// Everything the layer stores for layer comp `id`, whether the comp captured it or not
+    pub fn comp_settings(&self, id: u32) -> CompSettings {
+        let descriptor = match self.metadata(b"cmls").and_then(Descriptor::from_versioned) {
+            Some(descriptor) => descriptor,
+            None => return CompSettings::default(),
+        };
+
+        let mut settings = CompSettings::default();
+
+        // Every entry applies to the comps in its `compList`, later entries win
+        for entry in descriptor
+            .list("layerSettings")
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|value| value.as_descriptor())
+        {
+            let applies = entry
+                .list("compList")
+                .unwrap_or_default()
+                .iter()
+                .any(|comp| comp.as_f64().map(|comp| comp as u32) == Some(id));
+
+            if !applies {
+                continue;
+            }
+
+            if let Some(visible) = entry.bool("enab") {
+                settings.visible = Some(visible);
+            }
+
+            if let Some(offset) = entry.descriptor("Ofst") {
+                settings.offset = Some((
+                    offset.f64("Hrzn").unwrap_or_default().round() as i32,
+                    offset.f64("Vrtc").unwrap_or_default().round() as i32,
+                ));
+            }
+
+            if let Some(blend) = entry.descriptor("blendOptions") {
+                if let Some(opacity) = blend.f64("Opct") {
+                    settings.opacity =
+                        Some((opacity.clamp(0.0, 100.0) / 100.0 * 255.0).round() as u8);
+                }
+
+                if let Some(mode) = blend.enumeration("Md  ") {
+                    settings.blend_mode = Some(BlendMode::from_descriptor(mode));
+                }
+            }
+        }
+
+        settings
+    }
+
+    /// Data of an item in the metadata (`shmd`) block
+    fn metadata(&self, key: &[u8; 4]) -> Option<&[u8]> {
+        let mut cursor = Cursor::new(self.block(b"shmd")?);
+
+        for _ in 0..cursor.u32().ok()? {
+            cursor.key().ok()?;
+            let item = cursor.key().ok()?;
+            cursor.skip(4).ok()?;
+            let length = cursor.u32().ok()? as usize;
+            let data = cursor.bytes(length).ok()?;
+
+            if &item == key {
+                return Some(data);
+            }
+        }
+
+        None
+    }
+}
+
+impl PsdTree {
+    pub fn layer_comps(&self) -> Vec<LayerComp> {
+        self.file.layer_comps()
+    }
+
+    pub fn get_layer_comp(&self, name: &str) -> Option<LayerComp> {
+        self.layer_comps()
+            .into_iter()
+            .find(|comp| comp.name == name)
+    }
+
+    /// The overrides that make rendering look like `comp`
+    pub fn comp_overrides(&self, comp: &LayerComp) -> Overrides {
+        let mut overrides = Overrides::default();
+
+        for id in 0..self.index.entries.len() {
+            let node = self.node(NodeId(id));
+            let settings = node.comp_settings(comp);
+
+            if let Some(visible) = settings.visible {
+                overrides.visible.insert(node.id, visible);
+            }
+
+            if let Some(offset) = settings.offset {
+                overrides.offset.insert(node.id, offset);
+            }
+
+            if let Some(opacity) = settings.opacity {
+                overrides.opacity.insert(node.id, opacity);
+            }
+
+            if let Some(blend_mode) = settings.blend_mode {
+                overrides.blend_mode.insert(node.id, blend_mode);
+            }
+        }
+
+        overrides
+    }
+
+    /// A copy of the tree that renders and exports the way `comp` looks
+    pub fn with_comp(&self, comp: &LayerComp) -> PsdTree {
+        PsdTree {
+            overrides: Arc::from(self.comp_overrides(comp)),
+            ..self.clone()
+        }
+    }
+}
+
+impl PsdNode {
+    /// How this node looks in `comp`, leaving out what the comp didn't capture
+    pub fn comp_settings(&self, comp: &LayerComp) -> CompSettings {
+        let settings = self.record().comp_settings(comp.id);
+
+        CompSettings {
+            visible: settings.visible.filter(|_| comp.visibility),
+            // Groups don't have a position, their layers are moved instead
+            offset: match self.element() {
+                PsdElement::Layer(..) => settings.offset.filter(|_| comp.position),
+                PsdElement::Group(..) => None,
+            },
+            opacity: settings.opacity.filter(|_| comp.appearance),
+            blend_mode: settings.blend_mode.filter(|_| comp.appearance),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::descriptor::Value;
+    use super::super::fixture::{descriptor, versioned, Document};
+    use super::*;
+
+    /// `Red` with `Blue` to the right of it, hidden in comp 1, and moved onto `Red` at 20%
+    /// multiply in comp 2
+    fn document() -> PsdTree {
+        let comp = |id: i32, name: &str, captured: i32| {
+            Value::Descriptor(descriptor(vec![
+                ("Nm  ", Value::Text(name.to_string())),
+                ("compID", Value::Integer(id)),
+                ("capturedInfo", Value::Integer(captured)),
+            ]))
+        };
+        let comps = descriptor(vec![(
+            "list",
+            Value::List(vec![comp(1, "Hidden", 1), comp(2, "Moved", 7)]),
+        )]);
+
+        let settings = descriptor(vec![(
+            "layerSettings",
+            Value::List(vec![
+                Value::Descriptor(descriptor(vec![
+                    ("enab", Value::Boolean(false)),
+                    ("compList", Value::List(vec![Value::Integer(1)])),
+                ])),
+                Value::Descriptor(descriptor(vec![
+                    (
+                        "Ofst",
+                        Value::Descriptor(descriptor(vec![
+                            ("Hrzn", Value::Double(-1.0)),
+                            ("Vrtc", Value::Double(0.0)),
+                        ])),
+                    ),
+                    (
+                        "blendOptions",
+                        Value::Descriptor(descriptor(vec![
+                            ("Opct", Value::Unit("#Prc".to_string(), 20.0)),
+                            ("Md  ", Value::Enum("BlnM".to_string(), "Mltp".to_string())),
+                        ])),
+                    ),
+                    ("compList", Value::List(vec![Value::Integer(2)])),
+                ])),
+            ]),
+        )]);
+
+        // A metadata block with just the `cmls` item
+        let data = versioned(&settings);
+        let mut metadata = 1u32.to_be_bytes().to_vec();
+        metadata.extend_from_slice(b"8BIMcmls");
+        metadata.extend_from_slice(&[0; 4]);
+        metadata.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        metadata.extend_from_slice(&data);
+
+        Document::new(2, 1)
+            .resource(LAYER_COMPS, versioned(&comps))
+            .layer("Red", [0, 0, 1, 1], [255, 0, 0, 255])
+            .layer("Blue", [0, 1, 1, 2], [0, 0, 255, 255])
+            .with(|record| record.blocks.push((*b"shmd", metadata)))
+            .tree()
+    }
+
+    fn pixels(tree: &PsdTree) -> Vec<[u8; 4]> {
+        tree.render()
+            .pixels
+            .chunks_exact(4)
+            .map(|pixel| pixel.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn comps() {
+        let tree = document();
+        let comps = tree.layer_comps();
+
+        assert_eq!(
+            comps
+                .iter()
+                .map(|comp| comp.name.as_str())
+                .collect::<Vec<&str>>(),
+            ["Hidden", "Moved"]
+        );
+        assert!(comps[0].visibility && !comps[0].position && !comps[0].appearance);
+        assert!(comps[1].visibility && comps[1].position && comps[1].appearance);
+
+        let blue = tree.get_node("/Blue").unwrap();
+        assert_eq!(
+            blue.comp_settings(&comps[0]),
+            CompSettings {
+                visible: Some(false),
+                ..CompSettings::default()
+            }
+        );
+        assert_eq!(
+            blue.comp_settings(&comps[1]),
+            CompSettings {
+                visible: None,
+                offset: Some((-1, 0)),
+                opacity: Some(51),
+                blend_mode: Some(BlendMode::Multiply),
+            }
+        );
+
+        let red = [255, 0, 0, 255];
+        assert_eq!(pixels(&tree), [red, [0, 0, 255, 255]]);
+
+        let hidden = tree.with_comp(tree.get_layer_comp("Hidden").as_ref().unwrap());
+        assert_eq!(pixels(&hidden), [red, [0; 4]]);
+
+        let moved = tree.with_comp(tree.get_layer_comp("Moved").as_ref().unwrap());
+        assert_eq!(pixels(&moved), [[204, 0, 0, 255], [0; 4]]);
+
+        // Applying a comp makes a new tree
+        assert_eq!(pixels(&tree), [red, [0, 0, 255, 255]]);
+        assert!(tree.get_layer_comp("Missing").is_none());
+    }
+}
@@ -0,0 +1,381 @@
+//! Documents with 16 or 32 bits per channel. Layers can be read at the precision of the document,
+//! everything else works on 8 bits per channel which the samples are quantized to.
+
+use super::color::{decode_srgb, encode_srgb};
+use super::render::Canvas;
+use super::{PsdElement, PsdNode, RenderOptions};
+
+/// Document sized RGBA buffer with a float per channel and straight alpha. Colors of 32-bit
+/// documents are linear (and can go past 1.0), like Photoshop stores them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FloatCanvas {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<f32>,
+    pub linear: bool,
+}
+
+impl FloatCanvas {
+    /// Fully transparent canvas
+    pub fn new(width: u32, height: u32, linear: bool) -> FloatCanvas {
+        FloatCanvas {
+            width,
+            height,
+            pixels: vec![0.0; width as usize * height as usize * 4],
+            linear,
+        }
+    }
+
+    /// Scales the alpha of every pixel by `opacity`
+    pub fn multiply_alpha(&mut self, opacity: u8) {
+        for alpha in self.pixels.iter_mut().skip(3).step_by(4) {
+            *alpha *= opacity as f32 / 255.0;
+        }
+    }
+
+    /// The smallest rect as `x, y, width, height` holding every pixel that isn't fully transparent
+    pub fn opaque_bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+
+        for (index, pixel) in self.pixels.chunks_exact(4).enumerate() {
+            if pixel[3] > 0.0 {
+                let (x, y) = (index as u32 % self.width, index as u32 / self.width);
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x + 1);
+                bottom = bottom.max(y + 1);
+            }
+        }
+
+        match left < right {
+            true => Some((left, top, right - left, bottom - top)),
+            false => None,
+        }
+    }
+
+    /// The part of the canvas at `x, y` with a size of `width` by `height`, transparent past the
+    /// edges
+    pub fn region(&self, x: i32, y: i32, width: u32, height: u32) -> FloatCanvas {
+        let mut region = FloatCanvas::new(width, height, self.linear);
+
+        for row in 0..height as i32 {
+            for column in 0..width as i32 {
+                let (from_x, from_y) = (x + column, y + row);
+                if from_x < 0
+                    || from_y < 0
+                    || from_x >= self.width as i32
+                    || from_y >= self.height as i32
+                {
+                    continue;
+                }
+
+                let from = (from_y as usize * self.width as usize + from_x as usize) * 4;
+                let to = (row as usize * width as usize + column as usize) * 4;
+                region.pixels[to..to + 4].copy_from_slice(&self.pixels[from..from + 4]);
+            }
+        }
+
+        region
+    }
+
+    /// The canvas with linear colors, like the ones of 32-bit documents
+    pub fn to_linear(&self) -> FloatCanvas {
+        self.map_colors(true, |value| decode_srgb(value as f64) as f32)
+    }
+
+    /// The canvas with sRGB encoded colors. Linear colors past 1.0 follow the same curve and stay
+    /// past 1.0.
+    pub fn to_srgb(&self) -> FloatCanvas {
+        self.map_colors(false, |value| encode_srgb(value.max(0.0) as f64) as f32)
+    }
+
+    fn map_colors(&self, linear: bool, convert: impl Fn(f32) -> f32) -> FloatCanvas {
+        let mut canvas = self.clone();
+
+        if self.linear != linear {
+            for (index, value) in canvas.pixels.iter_mut().enumerate() {
+                if index % 4 != 3 {
+                    *value = convert(*value);
+                }
+            }
+            canvas.linear = linear;
+        }
+
+        canvas
+    }
+
+    /// Quantizes the colors as they are to 8 bits per channel, see [`quantize`]
+    pub fn to_canvas(&self, dither: bool) -> Canvas {
+        Canvas::from_rgba(
+            self.width,
+            self.height,
+            quantize(&self.pixels, self.width, false, dither),
+        )
+    }
+
+    /// RGBA with 16 bits per channel, with the colors as they are
+    pub fn to_rgba16(&self) -> Vec<u16> {
+        self.pixels
+            .iter()
+            .map(|value| (value.clamp(0.0, 1.0) * 65535.0).round() as u16)
+            .collect::<Vec<u16>>()
+    }
+
+    /// The bits of every channel as a half float, with the colors as they are
+    pub fn to_rgba_half(&self) -> Vec<u16> {
+        self.pixels
+            .iter()
+            .map(|value| half_float(*value))
+            .collect::<Vec<u16>>()
+    }
+}
+
+/// Rounds a float to the nearest half float, going to infinity past 65504
+fn half_float(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+
+    if value.is_nan() {
+        return sign | 0x7e00;
+    }
+
+    match exponent {
+        31.. => sign | 0x7c00,
+        ..=-11 => sign,
+        // Too small for the exponent, stored without the implicit leading bit instead
+        -10..=0 => sign | ((((mantissa | 0x80_0000) >> (1 - exponent)) + 0x1000) >> 13) as u16,
+        // Rounding up can carry over into the exponent, which is what it should do
+        _ => sign | (((exponent as u32) << 10) + ((mantissa + 0x1000) >> 13)) as u16,
+    }
+}
+
+impl From<&Canvas> for FloatCanvas {
+    fn from(canvas: &Canvas) -> FloatCanvas {
+        FloatCanvas {
+            width: canvas.width,
+            height: canvas.height,
+            pixels: canvas
+                .pixels
+                .iter()
+                .map(|value| *value as f32 / 255.0)
+                .collect::<Vec<f32>>(),
+            linear: false,
+        }
+    }
+}
+
+/// 4x4 Bayer matrix, spreading the rounding error of neighbouring pixels evenly
+const BAYER: [[f32; 4]; 4] = [
+    [0.0, 8.0, 2.0, 10.0],
+    [12.0, 4.0, 14.0, 6.0],
+    [3.0, 11.0, 1.0, 9.0],
+    [15.0, 7.0, 13.0, 5.0],
+];
+
+/// Turns RGBA samples into 8 bits per channel, encoding `linear` colors as sRGB first. Dithering
+/// hides the banding 8 bits leaves in smooth gradients by rounding up or down depending on the
+/// position of the pixel.
+pub(super) fn quantize(samples: &[f32], width: u32, linear: bool, dither: bool) -> Vec<u8> {
+    let width = width.max(1) as usize;
+
+    samples
+        .chunks_exact(4)
+        .enumerate()
+        .flat_map(|(index, pixel)| {
+            let threshold = match dither {
+                true => BAYER[index / width % 4][index % width % 4] / 16.0 + 1.0 / 32.0 - 0.5,
+                false => 0.0,
+            };
+
+            [0, 1, 2, 3].map(|channel| {
+                let value = match channel < 3 && linear {
+                    true => encode_srgb(pixel[channel].clamp(0.0, 1.0) as f64) as f32,
+                    false => pixel[channel],
+                };
+
+                (value.clamp(0.0, 1.0) * 255.0 + threshold)
+                    .round()
+                    .clamp(0.0, 255.0) as u8
+            })
+        })
+        .collect::<Vec<u8>>()
+}
+
+impl PsdNode {
+    /// Like [`PsdNode::render_with`] at the precision of the document, with the same masks,
+    /// offset and clipping. `None` for groups, layers without pixels of their own, and when
+    /// merging clipped layers or baking effects, which only work with 8 bits per channel.
+    pub fn render_float(&self, options: &RenderOptions) -> Option<FloatCanvas> {
+        if options.merge_clipped || options.effects {
+            return None;
+        }
+
+        let record = match self.element() {
+            PsdElement::Layer(_, record) => record,
+            PsdElement::Group(..) => return None,
+        };
+
+        let file = &self.tree.file;
+        let samples = file.layer_samples(record)?;
+        let (width, height) = (self.tree.psd.width(), self.tree.psd.height());
+        let layer_width = (record.right - record.left).max(0) as usize;
+
+        // Fill layers are rendered out of their color instead
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut canvas = FloatCanvas::new(width, height, file.is_linear());
+        for (row, pixels) in samples.chunks_exact(layer_width.max(1) * 4).enumerate() {
+            let y = record.top + row as i32;
+            if y < 0 || y >= height as i32 {
+                continue;
+            }
+
+            for (column, pixel) in pixels.chunks_exact(4).enumerate() {
+                let x = record.left + column as i32;
+                if x < 0 || x >= width as i32 {
+                    continue;
+                }
+
+                let index = (y as usize * width as usize + x as usize) * 4;
+                canvas.pixels[index..index + 4].copy_from_slice(pixel);
+            }
+        }
+
+        // Masks, offset and clipping only change the alpha, which the 8-bit render has as well
+        let coverage = self.render_with(&RenderOptions {
+            clipped: options.clipped,
+            overrides: options.overrides.clone(),
+            ..RenderOptions::default()
+        });
+
+        let overrides = self.tree.overrides.merge(&options.overrides);
+        if let Some((x, y)) = overrides.offset.get(&self.id) {
+            canvas = canvas.region(-x, -y, width, height);
+        }
+
+        for (pixel, coverage) in canvas
+            .pixels
+            .chunks_exact_mut(4)
+            .zip(coverage.pixels.chunks_exact(4))
+        {
+            pixel[3] = match coverage[3] {
+                0 => 0.0,
+                // Keep the precision of the alpha wherever the masks don't change it
+                alpha if (alpha as f32 - (pixel[3] * 255.0).round()).abs() < 1.0 => pixel[3],
+                alpha => alpha as f32 / 255.0,
+            };
+        }
+
+        Some(canvas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_floats() {
+        assert_eq!(half_float(0.0), 0x0000);
+        assert_eq!(half_float(-0.0), 0x8000);
+        assert_eq!(half_float(1.0), 0x3c00);
+        assert_eq!(half_float(0.5), 0x3800);
+        assert_eq!(half_float(-2.0), 0xc000);
+        assert_eq!(half_float(65504.0), 0x7bff);
+        // Halfway between the largest half float and the next power of two rounds up to infinity
+        assert_eq!(half_float(65520.0), 0x7c00);
+        assert_eq!(half_float(f32::INFINITY), 0x7c00);
+        assert_eq!(half_float(-1e10), 0xfc00);
+        assert_eq!(half_float(f32::NAN), 0x7e00);
+        // Smallest subnormal and smallest normal
+        assert_eq!(half_float(2.0f32.powi(-24)), 0x0001);
+        assert_eq!(half_float(2.0f32.powi(-14)), 0x0400);
+        assert_eq!(half_float(1e-10), 0x0000);
+        // 1 + 2^-11 is halfway between two half floats, 1 + 2^-10 is the next one up
+        assert_eq!(half_float(1.0 + 2.0f32.powi(-10)), 0x3c01);
+    }
+
+    #[test]
+    fn quantize_rounds() {
+        let samples = [0.0, 100.4 / 255.0, 100.6 / 255.0, 1.0, -0.5, 2.0, 0.5, 1.0];
+
+        assert_eq!(
+            quantize(&samples, 2, false, false),
+            [0, 100, 101, 255, 0, 255, 128, 255]
+        );
+        // Linear colors are encoded as sRGB first, but never the alpha
+        assert_eq!(
+            quantize(&[0.2159, 0.0, 1.0, 0.5], 1, true, false),
+            [128, 0, 255, 128]
+        );
+    }
+
+    #[test]
+    fn dithering_keeps_the_mean() {
+        for value in [0.25, 100.5, 100.8, 254.5] {
+            let samples = [value / 255.0, value / 255.0, value / 255.0, 1.0].repeat(16);
+            let pixels = quantize(&samples, 4, false, true);
+
+            let red = pixels.iter().step_by(4).map(|red| *red as f32);
+            let mean = red.sum::<f32>() / 16.0;
+            assert!((mean - value).abs() <= 1.0 / 16.0, "{value} became {mean}");
+            assert!(pixels.iter().skip(3).step_by(4).all(|alpha| *alpha == 255));
+        }
+    }
+
+    #[test]
+    fn sixteen_bit() {
+        let canvas = FloatCanvas {
+            width: 2,
+            height: 1,
+            pixels: vec![0.0, 0.5, 1.0, 1.0, -1.0, 2.0, 1.0 / 65535.0, 0.0],
+            linear: false,
+        };
+        assert_eq!(canvas.to_rgba16(), [0, 32768, 65535, 65535, 0, 65535, 1, 0]);
+
+        // Written big endian, like PNG stores them
+        let path = std::env::temp_dir().join("godot_psd_sixteen_bit.png");
+        let bytes = canvas
+            .to_rgba16()
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect::<Vec<u8>>();
+        super::super::write_to_png(&path, (2, 1), png::BitDepth::Sixteen, false, bytes.clone());
+
+        let decoder = png::Decoder::new(std::fs::File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+        assert_eq!(&buffer[..info.buffer_size()], &bytes[..]);
+    }
+
+    #[test]
+    fn srgb_and_linear() {
+        let canvas = FloatCanvas {
+            width: 1,
+            height: 1,
+            pixels: vec![0.5, 1.0, 4.0, 0.5],
+            linear: true,
+        };
+
+        let srgb = canvas.to_srgb();
+        assert!(!srgb.linear);
+        assert!((srgb.pixels[0] - 0.7354).abs() < 1e-3);
+        assert_eq!(srgb.pixels[1], 1.0);
+        // Colors past 1.0 stay past 1.0, the alpha is left alone
+        assert!(srgb.pixels[2] > 1.0);
+        assert_eq!(srgb.pixels[3], 0.5);
+
+        let back = srgb.to_linear();
+        for (value, original) in back.pixels.iter().zip(canvas.pixels.iter()) {
+            assert!((value - original).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Photoshop's action descriptors, the generic key/value structure used by most of the newer
+//! blocks in a PSD file (layer effects, text, layer comps, fills and so on).
+
+use super::records::{Cursor, RecordError};
+
+type Result<T> = std::result::Result<T, RecordError>;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Descriptor {
+    pub name: String,
+    pub class: String,
+    pub items: Vec<(String, Value)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Descriptor(Descriptor),
+    List(Vec<Value>),
+    Double(f64),
+    /// A double with a unit like `#Pxl`, `#Pnt`, `#Prc` or `#Ang`
+    Unit(String, f64),
+    Text(String),
+    /// Type and value of an enumeration
+    Enum(String, String),
+    Integer(i32),
+    LargeInteger(i64),
+    Boolean(bool),
+    Class(String),
+    /// References and raw data, which we don't interpret
+    Data(Vec<u8>),
+}
+
+impl Descriptor {
+    pub(super) fn read(cursor: &mut Cursor) -> Result<Descriptor> {
+        let name = unicode_string(cursor)?;
+        let class = id(cursor)?;
+
+        let items = (0..cursor.u32()?)
+            .map(|_| {
+                let key = id(cursor)?;
+                let value = Value::read(cursor)?;
+
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<(String, Value)>>>()?;
+
+        Ok(Descriptor { name, class, items })
+    }
+
+    /// Reads a descriptor that's prefixed by its 4 byte version, like most blocks have
+    pub(super) fn from_versioned(data: &[u8]) -> Option<Descriptor> {
+        let mut cursor = Cursor::new(data);
+        cursor.u32().ok()?;
+
+        Descriptor::read(&mut cursor).ok()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.items
+            .iter()
+            .find(|(item, _)| item == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn descriptor(&self, key: &str) -> Option<&Descriptor> {
+        self.get(key).and_then(Value::as_descriptor)
+    }
+
+    pub fn list(&self, key: &str) -> Option<&[Value]> {
+        self.get(key).and_then(Value::as_list)
+    }
+
+    pub fn f64(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(Value::as_f64)
+    }
+
+    pub fn bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(Value::as_bool)
+    }
+
+    pub fn text(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Value::as_text)
+    }
+
+    /// The value of an enumeration
+    pub fn enumeration(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Value::Enum(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// An RGB color with channels from 0.0 to 1.0, out of a `Clr ` like item
+    pub fn color(&self, key: &str) -> Option<[f32; 3]> {
+        let color = self.descriptor(key)?;
+
+        match color.class.as_str() {
+            "RGBC" => Some([
+                color.f64("Rd  ")? as f32 / 255.0,
+                color.f64("Grn ")? as f32 / 255.0,
+                color.f64("Bl  ").or_else(|| color.f64("blueFloat"))? as f32 / 255.0,
+            ]),
+            "Grsc" => Some([1.0 - color.f64("Gry ")? as f32 / 100.0; 3]),
+            _ => None,
+        }
+    }
+}
+
+impl Value {
+    fn read(cursor: &mut Cursor) -> Result<Value> {
+        let key = cursor.key()?;
+
+        Ok(match &key {
+            b"Objc" | b"GlbO" => Value::Descriptor(Descriptor::read(cursor)?),
+            b"VlLs" => Value::List(
+                (0..cursor.u32()?)
+                    .map(|_| Value::read(cursor))
+                    .collect::<Result<Vec<Value>>>()?,
+            ),
+            b"doub" => Value::Double(cursor.f64()?),
+            b"UntF" => {
+                let unit = String::from_utf8_lossy(&cursor.key()?).to_string();
+                Value::Unit(unit, cursor.f64()?)
+            }
+            b"TEXT" => Value::Text(unicode_string(cursor)?),
+            b"enum" => Value::Enum(id(cursor)?, id(cursor)?),
+            b"long" => Value::Integer(cursor.i32()?),
+            b"comp" => Value::LargeInteger(cursor.u64()? as i64),
+            b"bool" => Value::Boolean(cursor.u8()? != 0),
+            b"type" | b"GlbC" => {
+                unicode_string(cursor)?;
+                Value::Class(id(cursor)?)
+            }
+            b"alis" | b"tdta" | b"Pth " => {
+                let length = cursor.u32()? as usize;
+                Value::Data(cursor.bytes(length)?.to_vec())
+            }
+            b"UnFl" => {
+                let unit = String::from_utf8_lossy(&cursor.key()?).to_string();
+                let values = (0..cursor.u32()?)
+                    .map(|_| Ok(Value::Unit(unit.clone(), cursor.f64()?)))
+                    .collect::<Result<Vec<Value>>>()?;
+
+                Value::List(values)
+            }
+            b"obj " => {
+                for _ in 0..cursor.u32()? {
+                    reference(cursor)?;
+                }
+
+                Value::Data(vec![])
+            }
+            _ => return Err(RecordError::InvalidSignature("descriptor")),
+        })
+    }
+
+    pub fn as_descriptor(&self) -> Option<&Descriptor> {
+        match self {
+            Value::Descriptor(descriptor) => Some(descriptor),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Double(value) | Value::Unit(_, value) => Some(*value),
+            Value::Integer(value) => Some(*value as f64),
+            Value::LargeInteger(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Skips over a single item of a reference
+fn reference(cursor: &mut Cursor) -> Result<()> {
+    match &cursor.key()? {
+        b"prop" => {
+            unicode_string(cursor)?;
+            id(cursor)?;
+            id(cursor)?;
+        }
+        b"Clss" => {
+            unicode_string(cursor)?;
+            id(cursor)?;
+        }
+        b"Enmr" => {
+            unicode_string(cursor)?;
+            id(cursor)?;
+            id(cursor)?;
+            id(cursor)?;
+        }
+        b"rele" => {
+            unicode_string(cursor)?;
+            id(cursor)?;
+            cursor.i32()?;
+        }
+        b"Idnt" | b"indx" => {
+            cursor.i32()?;
+        }
+        b"name" => {
+            unicode_string(cursor)?;
+            id(cursor)?;
+            unicode_string(cursor)?;
+        }
+        _ => return Err(RecordError::InvalidSignature("descriptor reference")),
+    }
+
+    Ok(())
+}
+
+/// Reads a class or key id, which is either a 4 character code or a longer string
+fn id(cursor: &mut Cursor) -> Result<String> {
+    let length = match cursor.u32()? {
+        0 => 4,
+        length => length as usize,
+    };
+
+    Ok(String::from_utf8_lossy(cursor.bytes(length)?).to_string())
+}
+
+/// Reads a UTF-16 string prefixed by its length in characters
+pub(super) fn unicode_string(cursor: &mut Cursor) -> Result<String> {
+    let length = cursor.u32()? as usize;
+    let units = (0..length)
+        .map(|_| cursor.u16())
+        .collect::<Result<Vec<u16>>>()?;
+
+    Ok(String::from_utf16_lossy(&units)
+        .trim_end_matches(char::from(0))
+        .to_string())
+}
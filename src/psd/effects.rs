@@ -0,0 +1,613 @@
+//! Layer effects (layer styles) from the `lfx2` and `lmfx` blocks, and baking the common ones
+//! into the pixels of a layer.
+
+use super::descriptor::Descriptor;
+use super::records::{Cursor, LayerRecord, PsdFile};
+use super::render::box_blur;
+use super::{BlendMode, Canvas, PsdElement, PsdNode, RenderOptions, Rgb};
+
+/// Image resource holding the angle of the global light
+const GLOBAL_ANGLE: u16 = 1037;
+
+/// The common effects of a layer, leaving out disabled ones. Inner shadows and glows are read,
+/// but not baked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LayerEffects {
+    pub drop_shadows: Vec<Shadow>,
+    pub inner_shadows: Vec<Shadow>,
+    pub outer_glows: Vec<Glow>,
+    pub inner_glows: Vec<Glow>,
+    pub color_overlays: Vec<ColorOverlay>,
+    pub strokes: Vec<Stroke>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadow {
+    pub color: Rgb,
+    pub opacity: u8,
+    pub blend_mode: BlendMode,
+    /// Direction the light comes from in degrees, `None` when it uses the global light
+    pub angle: Option<f64>,
+    pub distance: f64,
+    /// How much of the size grows (or for inner shadows, chokes) the shape before blurring,
+    /// from 0 to 100
+    pub spread: f64,
+    pub size: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Glow {
+    pub color: Rgb,
+    pub opacity: u8,
+    pub blend_mode: BlendMode,
+    /// How much of the size grows (or for inner glows, chokes) the shape before blurring, from 0
+    /// to 100
+    pub spread: f64,
+    pub size: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorOverlay {
+    pub color: Rgb,
+    pub opacity: u8,
+    pub blend_mode: BlendMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stroke {
+    pub color: Rgb,
+    pub opacity: u8,
+    pub blend_mode: BlendMode,
+    pub size: f64,
+    pub position: StrokePosition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrokePosition {
+    Outside,
+    Inside,
+    Center,
+}
+
+impl PsdFile {
+    /// Angle of the global light in degrees, which effects can use instead of their own
+    pub fn global_angle(&self) -> f64 {
+        self.resource(GLOBAL_ANGLE)
+            .and_then(|resource| Cursor::new(&resource.data).i32().ok())
+            .unwrap_or(120) as f64
+    }
+}
+
+impl LayerRecord {
+    /// The descriptor with all layer effects, `lmfx` when the layer uses multiple effects of
+    /// the same kind
+    pub fn effects_descriptor(&self) -> Option<Descriptor> {
+        let data = self.block(b"lmfx").or_else(|| self.block(b"lfx2"))?;
+
+        // Both an effects version and a descriptor version precede the descriptor
+        Descriptor::from_versioned(data.get(4..)?)
+    }
+
+    pub fn effects(&self) -> LayerEffects {
+        let descriptor = match self.effects_descriptor() {
+            Some(descriptor) if descriptor.bool("masterFXSwitch").unwrap_or(true) => descriptor,
+            _ => return LayerEffects::default(),
+        };
+
+        let scale = descriptor.f64("Scl ").unwrap_or(100.0) / 100.0;
+
+        let shadow = |effect: &Descriptor| Shadow {
+            color: color(effect),
+            opacity: opacity(effect),
+            blend_mode: blend_mode(effect),
+            angle: match effect.bool("uglg").unwrap_or(true) {
+                true => None,
+                false => effect.f64("lagl"),
+            },
+            distance: effect.f64("Dstn").unwrap_or_default() * scale,
+            spread: effect.f64("Ckmt").unwrap_or_default(),
+            size: effect.f64("blur").unwrap_or_default() * scale,
+        };
+
+        let glow = |effect: &Descriptor| Glow {
+            color: color(effect),
+            opacity: opacity(effect),
+            blend_mode: blend_mode(effect),
+            spread: effect.f64("Ckmt").unwrap_or_default(),
+            size: effect.f64("blur").unwrap_or_default() * scale,
+        };
+
+        LayerEffects {
+            drop_shadows: effects(&descriptor, "DrSh", "dropShadowMulti")
+                .map(shadow)
+                .collect(),
+            inner_shadows: effects(&descriptor, "IrSh", "innerShadowMulti")
+                .map(shadow)
+                .collect(),
+            // Glows with a gradient instead of a color aren't read
+            outer_glows: effects(&descriptor, "OrGl", "outerGlowMulti")
+                .filter(|effect| effect.get("Clr ").is_some())
+                .map(glow)
+                .collect(),
+            inner_glows: effects(&descriptor, "IrGl", "innerGlowMulti")
+                .filter(|effect| effect.get("Clr ").is_some())
+                .map(glow)
+                .collect(),
+            color_overlays: effects(&descriptor, "SoFi", "solidFillMulti")
+                .map(|effect| ColorOverlay {
+                    color: color(effect),
+                    opacity: opacity(effect),
+                    blend_mode: blend_mode(effect),
+                })
+                .collect(),
+            strokes: effects(&descriptor, "FrFX", "frameFXMulti")
+                // Gradient and pattern strokes aren't baked
+                .filter(|effect| effect.enumeration("PntT").unwrap_or("SClr") == "SClr")
+                .map(|effect| Stroke {
+                    color: color(effect),
+                    opacity: opacity(effect),
+                    blend_mode: blend_mode(effect),
+                    size: effect.f64("Sz  ").unwrap_or(3.0) * scale,
+                    position: match effect.enumeration("Styl") {
+                        Some("InsF") => StrokePosition::Inside,
+                        Some("CtrF") => StrokePosition::Center,
+                        _ => StrokePosition::Outside,
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The enabled effects of one kind, either on their own or out of the list for multiple ones
+fn effects<'a>(
+    descriptor: &'a Descriptor,
+    key: &str,
+    multiple: &str,
+) -> impl Iterator<Item = &'a Descriptor> {
+    let single = descriptor.descriptor(key).into_iter();
+    let multiple = descriptor
+        .list(multiple)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|value| value.as_descriptor());
+
+    single
+        .chain(multiple)
+        .filter(|effect| effect.bool("enab").unwrap_or(true))
+}
+
+fn color(effect: &Descriptor) -> Rgb {
+    effect.color("Clr ").unwrap_or_default()
+}
+
+fn opacity(effect: &Descriptor) -> u8 {
+    (effect.f64("Opct").unwrap_or(100.0).clamp(0.0, 100.0) / 100.0 * 255.0).round() as u8
+}
+
+fn blend_mode(effect: &Descriptor) -> BlendMode {
+    effect
+        .enumeration("Md  ")
+        .map(BlendMode::from_descriptor)
+        .unwrap_or(BlendMode::Normal)
+}
+
+impl LayerEffects {
+    pub fn is_empty(&self) -> bool {
+        self.drop_shadows.is_empty()
+            && self.inner_shadows.is_empty()
+            && self.outer_glows.is_empty()
+            && self.inner_glows.is_empty()
+            && self.color_overlays.is_empty()
+            && self.strokes.is_empty()
+    }
+
+    /// How many pixels the effects reach past the pixels of the layer
+    pub fn extent(&self) -> u32 {
+        let shadows = self
+            .drop_shadows
+            .iter()
+            .map(|shadow| shadow.distance.abs() + shadow.size);
+        let glows = self.outer_glows.iter().map(|glow| glow.size);
+        let strokes = self.strokes.iter().map(|stroke| match stroke.position {
+            StrokePosition::Outside => stroke.size,
+            StrokePosition::Center => stroke.size / 2.0,
+            StrokePosition::Inside => 0.0,
+        });
+
+        shadows
+            .chain(glows)
+            .chain(strokes)
+            .fold(0.0, f64::max)
+            .ceil() as u32
+            + 1
+    }
+
+    /// Draws the effects around and on top of the pixels of a layer, in the order Photoshop
+    /// stacks them. Effects past the edges of `canvas` are cut off.
+    pub fn apply(&self, canvas: &Canvas, global_angle: f64) -> Canvas {
+        let (width, height) = (canvas.width as usize, canvas.height as usize);
+        let alpha = canvas
+            .pixels
+            .iter()
+            .skip(3)
+            .step_by(4)
+            .copied()
+            .collect::<Vec<u8>>();
+
+        let outside = distance_field(&alpha, width, height, true);
+        let inside = match self.strokes.is_empty() {
+            true => vec![],
+            false => distance_field(&alpha, width, height, false),
+        };
+
+        let mut result = Canvas::new(canvas.width, canvas.height);
+
+        for shadow in self.drop_shadows.iter() {
+            let [x, y] = shadow.offset(global_angle);
+            let mut values = spread(&alpha, &outside, shadow.spread, shadow.size, width, height);
+            values = shift(&values, width, height, x.round() as i32, y.round() as i32);
+
+            result.draw(
+                &solid(shadow.color, &values, canvas),
+                shadow.opacity,
+                shadow.blend_mode,
+            );
+        }
+
+        for glow in self.outer_glows.iter() {
+            let values = spread(&alpha, &outside, glow.spread, glow.size, width, height);
+            result.draw(
+                &solid(glow.color, &values, canvas),
+                glow.opacity,
+                glow.blend_mode,
+            );
+        }
+
+        let mut content = canvas.clone();
+        for overlay in self.color_overlays.iter() {
+            content.draw_atop(
+                &solid(overlay.color, &vec![255; alpha.len()], canvas),
+                overlay.opacity,
+                overlay.blend_mode,
+            );
+        }
+
+        result.draw(&content, 255, BlendMode::Normal);
+
+        for stroke in self.strokes.iter() {
+            let (outer, inner) = match stroke.position {
+                StrokePosition::Outside => (stroke.size, 0.0),
+                StrokePosition::Inside => (0.0, stroke.size),
+                StrokePosition::Center => (stroke.size / 2.0, stroke.size / 2.0),
+            };
+
+            let values = alpha
+                .iter()
+                .enumerate()
+                .map(|(index, alpha)| {
+                    let alpha = *alpha as f32 / 255.0;
+                    let grown = (outer as f32 + 1.0 - outside[index]).clamp(0.0, 1.0);
+                    let shrunk = (inside[index] - inner as f32).clamp(0.0, 1.0);
+
+                    let value = (grown - alpha).max(0.0) + alpha * (1.0 - shrunk);
+                    (value.min(1.0) * 255.0).round() as u8
+                })
+                .collect::<Vec<u8>>();
+
+            result.draw(
+                &solid(stroke.color, &values, canvas),
+                stroke.opacity,
+                stroke.blend_mode,
+            );
+        }
+
+        result
+    }
+}
+
+impl Shadow {
+    /// How far the shadow is moved in pixels, to the right and down. The light comes from
+    /// `angle`, or `global_angle` when the shadow uses the global light.
+    pub fn offset(&self, global_angle: f64) -> [f64; 2] {
+        let angle = self.angle.unwrap_or(global_angle).to_radians();
+
+        [-angle.cos() * self.distance, angle.sin() * self.distance]
+    }
+}
+
+impl PsdNode {
+    /// The effects of a layer, groups don't have any
+    pub fn effects(&self) -> LayerEffects {
+        self.entry()
+            .effects
+            .get_or_init(|| match self.element() {
+                PsdElement::Layer(_, record) => record.effects(),
+                PsdElement::Group(..) => LayerEffects::default(),
+            })
+            .clone()
+    }
+
+    /// Like [`PsdNode::render_with`], with the layer effects baked in. The canvas grows past the
+    /// document to fit the effects, so this also returns where its top left corner is.
+    pub fn render_effects(&self, options: &RenderOptions) -> (Canvas, (i32, i32)) {
+        let canvas = self.render_with(options);
+        let effects = self.effects();
+
+        if effects.is_empty() {
+            return (canvas, (0, 0));
+        }
+
+        let margin = effects.extent() as i32;
+        let padded = canvas.region(
+            -margin,
+            -margin,
+            canvas.width + 2 * margin as u32,
+            canvas.height + 2 * margin as u32,
+        );
+
+        (
+            effects.apply(&padded, self.tree.file.global_angle()),
+            (-margin, -margin),
+        )
+    }
+}
+
+/// A canvas filled with `color`, with one alpha value per pixel
+fn solid(color: Rgb, values: &[u8], like: &Canvas) -> Canvas {
+    let color = color.map(|channel| (channel * 255.0).round() as u8);
+    let pixels = values
+        .iter()
+        .flat_map(|value| [color[0], color[1], color[2], *value])
+        .collect::<Vec<u8>>();
+
+    Canvas::from_rgba(like.width, like.height, pixels)
+}
+
+/// Grows the shape by `spread` percent of `size` and blurs it by the rest
+fn spread(
+    alpha: &[u8],
+    distance: &[f32],
+    spread: f64,
+    size: f64,
+    width: usize,
+    height: usize,
+) -> Vec<u8> {
+    let grow = (size * spread / 100.0) as f32;
+    let blur = size - grow as f64;
+
+    let mut values = alpha
+        .iter()
+        .zip(distance)
+        .map(|(alpha, distance)| {
+            let grown = ((grow + 1.0 - distance).clamp(0.0, 1.0) * 255.0).round() as u8;
+            grown.max(*alpha)
+        })
+        .collect::<Vec<u8>>();
+
+    // Three box blurs reach three times their radius, together about as far as the size
+    let radius = (blur / 3.0).round() as usize;
+    if radius > 0 {
+        for _ in 0..3 {
+            box_blur(&mut values, width, height, radius);
+        }
+    }
+
+    values
+}
+
+fn shift(values: &[u8], width: usize, height: usize, x: i32, y: i32) -> Vec<u8> {
+    let mut shifted = vec![0; values.len()];
+
+    for row in 0..height as i32 {
+        let source_row = row - y;
+        if source_row < 0 || source_row >= height as i32 {
+            continue;
+        }
+
+        for column in 0..width as i32 {
+            let source_column = column - x;
+            if source_column >= 0 && source_column < width as i32 {
+                shifted[(row * width as i32 + column) as usize] =
+                    values[(source_row * width as i32 + source_column) as usize];
+            }
+        }
+    }
+
+    shifted
+}
+
+/// Distance from the center of every pixel to the center of the nearest pixel inside the shape,
+/// or outside of it when `to_inside` is false. Edges lie halfway, so neighbouring pixels are
+/// half a pixel away from them. Pixels count as inside when they're at least half opaque.
+fn distance_field(alpha: &[u8], width: usize, height: usize, to_inside: bool) -> Vec<f32> {
+    let far = (width * width + height * height) as f32;
+    let mut field = alpha
+        .iter()
+        .map(|alpha| match (*alpha >= 128) == to_inside {
+            true => 0.0,
+            false => far,
+        })
+        .collect::<Vec<f32>>();
+
+    let mut line = vec![];
+    for x in 0..width {
+        line.clear();
+        line.extend((0..height).map(|y| field[y * width + x]));
+        for (y, value) in squared_distances(&line).into_iter().enumerate() {
+            field[y * width + x] = value;
+        }
+    }
+
+    for y in 0..height {
+        let row = squared_distances(&field[y * width..(y + 1) * width]);
+        field[y * width..(y + 1) * width].copy_from_slice(&row);
+    }
+
+    field.into_iter().map(f32::sqrt).collect()
+}
+
+/// One pass of the squared euclidean distance transform by Felzenszwalb and Huttenlocher
+fn squared_distances(values: &[f32]) -> Vec<f32> {
+    let count = values.len();
+    let mut distances = vec![0.0; count];
+    if count == 0 {
+        return distances;
+    }
+
+    let mut parabolas = vec![0usize; count];
+    let mut boundaries = vec![0.0f32; count + 1];
+    let mut index = 0;
+    boundaries[0] = f32::MIN;
+    boundaries[1] = f32::MAX;
+
+    let intersection = |a: usize, b: usize| {
+        ((values[b] + (b * b) as f32) - (values[a] + (a * a) as f32)) / (2.0 * (b - a) as f32)
+    };
+
+    for point in 1..count {
+        let mut crossing = intersection(parabolas[index], point);
+        while crossing <= boundaries[index] {
+            index -= 1;
+            crossing = intersection(parabolas[index], point);
+        }
+
+        index += 1;
+        parabolas[index] = point;
+        boundaries[index] = crossing;
+        boundaries[index + 1] = f32::MAX;
+    }
+
+    index = 0;
+    for (point, distance) in distances.iter_mut().enumerate() {
+        while boundaries[index + 1] < point as f32 {
+            index += 1;
+        }
+
+        let parabola = parabolas[index];
+        *distance = (point as f32 - parabola as f32).powi(2) + values[parabola];
+    }
+
+    distances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 7x7 canvas with a single opaque white pixel in the middle
+    fn dot() -> Canvas {
+        let mut canvas = Canvas::new(7, 7);
+        canvas.pixels[24 * 4..25 * 4].copy_from_slice(&[255; 4]);
+        canvas
+    }
+
+    fn alpha(canvas: &Canvas) -> Vec<u8> {
+        canvas.pixels.iter().skip(3).step_by(4).copied().collect()
+    }
+
+    #[test]
+    fn distances() {
+        let alpha = alpha(&dot());
+        let outside = distance_field(&alpha, 7, 7, true);
+        let inside = distance_field(&alpha, 7, 7, false);
+
+        assert_eq!(outside[24], 0.0);
+        assert_eq!(outside[23], 1.0);
+        assert_eq!(outside[17], 1.0);
+        assert_eq!(outside[16], 2f32.sqrt());
+        assert_eq!(outside[0], 18f32.sqrt());
+
+        // Only the dot is inside, so everything else is right on the outside
+        assert_eq!(inside[24], 1.0);
+        assert!(inside.iter().enumerate().all(|(i, d)| i == 24 || *d == 0.0));
+    }
+
+    #[test]
+    fn outside_stroke() {
+        let effects = LayerEffects {
+            strokes: vec![Stroke {
+                color: [1.0, 0.0, 0.0],
+                opacity: 255,
+                blend_mode: BlendMode::Normal,
+                size: 1.0,
+                position: StrokePosition::Outside,
+            }],
+            ..LayerEffects::default()
+        };
+        let result = effects.apply(&dot(), 120.0);
+        let pixel = |x: usize, y: usize| &result.pixels[(y * 7 + x) * 4..(y * 7 + x + 1) * 4];
+
+        // A ring around the dot, which an outside stroke leaves alone
+        assert_eq!(pixel(3, 3), [255, 255, 255, 255]);
+        assert_eq!(pixel(2, 3), [255, 0, 0, 255]);
+        assert_eq!(pixel(3, 4), [255, 0, 0, 255]);
+        // Corners are partly covered, the pixels past them not at all
+        assert_eq!(pixel(2, 2)[3], 149);
+        assert_eq!(pixel(1, 3)[3], 0);
+        assert_eq!(pixel(0, 0)[3], 0);
+    }
+
+    #[test]
+    fn inside_stroke() {
+        let effects = LayerEffects {
+            strokes: vec![Stroke {
+                color: [1.0, 0.0, 0.0],
+                opacity: 255,
+                blend_mode: BlendMode::Normal,
+                size: 1.0,
+                position: StrokePosition::Inside,
+            }],
+            ..LayerEffects::default()
+        };
+        let result = effects.apply(&dot(), 120.0);
+
+        assert_eq!(result.pixels[24 * 4..25 * 4], [255, 0, 0, 255]);
+        assert_eq!(alpha(&result).iter().filter(|a| **a > 0).count(), 1);
+    }
+
+    #[test]
+    fn shadow_offset() {
+        let mut shadow = Shadow {
+            color: [0.0, 0.0, 0.0],
+            opacity: 255,
+            blend_mode: BlendMode::Normal,
+            angle: Some(90.0),
+            distance: 2.0,
+            spread: 0.0,
+            size: 0.0,
+        };
+
+        // Light from above casts the shadow straight down
+        let [x, y] = shadow.offset(120.0);
+        assert!(x.abs() < 1e-9);
+        assert_eq!(y, 2.0);
+
+        let effects = LayerEffects {
+            drop_shadows: vec![shadow],
+            ..LayerEffects::default()
+        };
+        let result = effects.apply(&dot(), 120.0);
+        let pixel = |x: usize, y: usize| &result.pixels[(y * 7 + x) * 4..(y * 7 + x + 1) * 4];
+
+        assert_eq!(pixel(3, 3), [255, 255, 255, 255]);
+        assert_eq!(pixel(3, 5), [0, 0, 0, 255]);
+        assert_eq!(alpha(&result).iter().filter(|a| **a > 0).count(), 2);
+
+        // Light from the left with the global light casts it to the right
+        shadow.angle = None;
+        let [x, y] = shadow.offset(180.0);
+        assert_eq!(x, 2.0);
+        assert!(y.abs() < 1e-9);
+
+        let effects = LayerEffects {
+            drop_shadows: vec![shadow],
+            ..LayerEffects::default()
+        };
+        let result = effects.apply(&dot(), 180.0);
+        assert_eq!(
+            result.pixels[(3 * 7 + 5) * 4..(3 * 7 + 6) * 4],
+            [0, 0, 0, 255]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Small documents built in memory for the tests, laid out the way Photoshop writes them so both
+//! the `psd` crate and [`PsdFile`](super::PsdFile) can read them.
+
+use super::descriptor::{Descriptor, Value};
+use super::PsdTree;
+
+/// An 8-bit RGB document. Records go from bottom to top like in the file, so a group is its
+/// [`Document::divider`], then its children, then [`Document::group`] with its name.
+pub(super) struct Document {
+    width: u32,
+    height: u32,
+    records: Vec<Record>,
+    resources: Vec<(u16, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Record {
+    pub name: String,
+    /// Type of the `lsct` block: 0 for layers, 1 for the record naming a group and 3 for the
+    /// divider closing it
+    pub section: u32,
+    /// Top, left, bottom and right
+    pub rect: [i32; 4],
+    /// Every pixel inside of the rect has this color
+    pub color: [u8; 4],
+    pub opacity: u8,
+    pub clipping: u8,
+    pub flags: u8,
+    pub blend_key: [u8; 4],
+    /// Rect and pixels of a raster mask, which is black outside of its rect
+    pub mask: Option<([i32; 4], Vec<u8>)>,
+    pub blocks: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl Record {
+    fn new(name: &str, section: u32) -> Record {
+        Record {
+            name: name.to_string(),
+            section,
+            rect: [0; 4],
+            color: [0; 4],
+            opacity: 255,
+            clipping: 0,
+            flags: 0,
+            blend_key: *b"norm",
+            mask: None,
+            blocks: vec![],
+        }
+    }
+
+    fn size(&self) -> usize {
+        let [top, left, bottom, right] = self.rect;
+
+        ((bottom - top).max(0) * (right - left).max(0)) as usize
+    }
+}
+
+impl Document {
+    pub fn new(width: u32, height: u32) -> Document {
+        Document {
+            width,
+            height,
+            records: vec![],
+            resources: vec![],
+        }
+    }
+
+    /// Adds a layer filled with `color` inside of `rect`
+    pub fn layer(mut self, name: &str, rect: [i32; 4], color: [u8; 4]) -> Document {
+        let mut record = Record::new(name, 0);
+        record.rect = rect;
+        record.color = color;
+        self.records.push(record);
+        self
+    }
+
+    /// Adds a layer without any pixels
+    pub fn empty(mut self, name: &str) -> Document {
+        self.records.push(Record::new(name, 0));
+        self
+    }
+
+    /// Adds the divider closing a group, which goes below its children
+    pub fn divider(mut self) -> Document {
+        self.records.push(Record::new("</Layer group>", 3));
+        self
+    }
+
+    /// Adds the record naming a group, which goes above its children
+    pub fn group(mut self, name: &str) -> Document {
+        let mut record = Record::new(name, 1);
+        record.blend_key = *b"pass";
+        self.records.push(record);
+        self
+    }
+
+    /// Changes the record added last
+    pub fn with(mut self, change: impl FnOnce(&mut Record)) -> Document {
+        change(self.records.last_mut().unwrap());
+        self
+    }
+
+    pub fn resource(mut self, id: u16, data: Vec<u8>) -> Document {
+        self.resources.push((id, data));
+        self
+    }
+
+    pub fn tree(&self) -> PsdTree {
+        PsdTree::from_bytes(&self.to_bytes()).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut records = vec![];
+        let mut image_data = vec![];
+
+        for record in self.records.iter() {
+            for value in record.rect {
+                records.extend_from_slice(&value.to_be_bytes());
+            }
+
+            // Alpha goes first, then red, green and blue
+            let size = record.size();
+            let mut channels = vec![(-1i16, vec![record.color[3]; size])];
+            for (id, value) in record.color.iter().take(3).enumerate() {
+                channels.push((id as i16, vec![*value; size]));
+            }
+            if let Some((_, pixels)) = &record.mask {
+                channels.push((-2, pixels.clone()));
+            }
+
+            records.extend_from_slice(&(channels.len() as u16).to_be_bytes());
+            for (id, pixels) in channels.iter() {
+                records.extend_from_slice(&id.to_be_bytes());
+                records.extend_from_slice(&(pixels.len() as u32 + 2).to_be_bytes());
+
+                // Uncompressed
+                image_data.extend_from_slice(&[0, 0]);
+                image_data.extend_from_slice(pixels);
+            }
+
+            records.extend_from_slice(b"8BIM");
+            records.extend_from_slice(&record.blend_key);
+            records.extend_from_slice(&[record.opacity, record.clipping, record.flags, 0]);
+
+            let mut extra = vec![];
+            match &record.mask {
+                Some((rect, _)) => {
+                    extra.extend_from_slice(&20u32.to_be_bytes());
+                    for value in rect {
+                        extra.extend_from_slice(&value.to_be_bytes());
+                    }
+                    extra.extend_from_slice(&[0, 0, 0, 0]);
+                }
+                None => extra.extend_from_slice(&0u32.to_be_bytes()),
+            }
+            // No blending ranges
+            extra.extend_from_slice(&0u32.to_be_bytes());
+
+            extra.push(record.name.len() as u8);
+            extra.extend_from_slice(record.name.as_bytes());
+            extra.resize(extra.len() + (4 - (record.name.len() + 1) % 4) % 4, 0);
+
+            let mut blocks = record.blocks.clone();
+            if record.section != 0 {
+                let mut data = record.section.to_be_bytes().to_vec();
+                data.extend_from_slice(b"8BIM");
+                data.extend_from_slice(&record.blend_key);
+                blocks.push((*b"lsct", data));
+            }
+            for (key, data) in blocks {
+                extra.extend_from_slice(b"8BIM");
+                extra.extend_from_slice(&key);
+                extra.extend_from_slice(&((data.len() + data.len() % 2) as u32).to_be_bytes());
+                extra.extend_from_slice(&data);
+                extra.resize(extra.len() + data.len() % 2, 0);
+            }
+
+            records.extend_from_slice(&(extra.len() as u32).to_be_bytes());
+            records.extend_from_slice(&extra);
+        }
+
+        let mut layer_info = vec![];
+        if !self.records.is_empty() {
+            layer_info.extend_from_slice(&(self.records.len() as i16).to_be_bytes());
+            layer_info.extend_from_slice(&records);
+            layer_info.extend_from_slice(&image_data);
+            layer_info.resize(layer_info.len() + layer_info.len() % 2, 0);
+        }
+
+        let mut resources = vec![];
+        for (id, data) in self.resources.iter() {
+            resources.extend_from_slice(b"8BIM");
+            resources.extend_from_slice(&id.to_be_bytes());
+            // Empty name, padded to an even length
+            resources.extend_from_slice(&[0, 0]);
+            resources.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            resources.extend_from_slice(data);
+            resources.resize(resources.len() + data.len() % 2, 0);
+        }
+
+        let mut bytes = b"8BPS".to_vec();
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&[0; 6]);
+        bytes.extend_from_slice(&3u16.to_be_bytes());
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.width.to_be_bytes());
+        bytes.extend_from_slice(&8u16.to_be_bytes());
+        bytes.extend_from_slice(&3u16.to_be_bytes());
+        // No color mode data
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+        bytes.extend_from_slice(&(resources.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&resources);
+
+        // Layer info followed by an empty global layer mask
+        bytes.extend_from_slice(&(layer_info.len() as u32 + 8).to_be_bytes());
+        bytes.extend_from_slice(&(layer_info.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&layer_info);
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+
+        // Uncompressed white merged image
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.resize(bytes.len() + (self.width * self.height * 3) as usize, 255);
+
+        bytes
+    }
+}
+
+/// A descriptor prefixed by its version, the way blocks and resources store them
+pub(super) fn versioned(descriptor: &Descriptor) -> Vec<u8> {
+    let mut bytes = 16u32.to_be_bytes().to_vec();
+    write_descriptor(&mut bytes, descriptor);
+    bytes
+}
+
+/// A descriptor with the items in `items` and an empty name and class
+pub(super) fn descriptor(items: Vec<(&str, Value)>) -> Descriptor {
+    Descriptor {
+        name: String::new(),
+        class: "null".to_string(),
+        items: items
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect::<Vec<(String, Value)>>(),
+    }
+}
+
+fn write_descriptor(bytes: &mut Vec<u8>, descriptor: &Descriptor) {
+    write_unicode(bytes, &descriptor.name);
+    write_id(bytes, &descriptor.class);
+    bytes.extend_from_slice(&(descriptor.items.len() as u32).to_be_bytes());
+
+    for (key, value) in descriptor.items.iter() {
+        write_id(bytes, key);
+        write_value(bytes, value);
+    }
+}
+
+fn write_value(bytes: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Descriptor(descriptor) => {
+            bytes.extend_from_slice(b"Objc");
+            write_descriptor(bytes, descriptor);
+        }
+        Value::List(values) => {
+            bytes.extend_from_slice(b"VlLs");
+            bytes.extend_from_slice(&(values.len() as u32).to_be_bytes());
+            for value in values {
+                write_value(bytes, value);
+            }
+        }
+        Value::Double(value) => {
+            bytes.extend_from_slice(b"doub");
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Value::Unit(unit, value) => {
+            bytes.extend_from_slice(b"UntF");
+            bytes.extend_from_slice(unit.as_bytes());
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Value::Text(text) => {
+            bytes.extend_from_slice(b"TEXT");
+            write_unicode(bytes, text);
+        }
+        Value::Enum(kind, value) => {
+            bytes.extend_from_slice(b"enum");
+            write_id(bytes, kind);
+            write_id(bytes, value);
+        }
+        Value::Integer(value) => {
+            bytes.extend_from_slice(b"long");
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Value::LargeInteger(value) => {
+            bytes.extend_from_slice(b"comp");
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Value::Boolean(value) => {
+            bytes.extend_from_slice(b"bool");
+            bytes.push(*value as u8);
+        }
+        Value::Class(class) => {
+            bytes.extend_from_slice(b"type");
+            write_unicode(bytes, "");
+            write_id(bytes, class);
+        }
+        Value::Data(data) => {
+            bytes.extend_from_slice(b"tdta");
+            bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(data);
+        }
+    }
+}
+
+/// Keys of 4 characters are stored with a length of 0
+fn write_id(bytes: &mut Vec<u8>, id: &str) {
+    let length = match id.len() {
+        4 => 0,
+        length => length as u32,
+    };
+
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(id.as_bytes());
+}
+
+fn write_unicode(bytes: &mut Vec<u8>, text: &str) {
+    let units = text.encode_utf16().chain([0]).collect::<Vec<u16>>();
+
+    bytes.extend_from_slice(&(units.len() as u32).to_be_bytes());
+    for unit in units {
+        bytes.extend_from_slice(&unit.to_be_bytes());
+    }
+}
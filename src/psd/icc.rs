@@ -0,0 +1,507 @@
+//! Embedded ICC color profiles. Pixels of documents with a profile other than sRGB, like Display P3
+//! or Adobe RGB, are converted to sRGB through the primaries and tone curves of the profile.
+
+use super::color::{decode_srgb, encode_srgb, xyz_to_linear_srgb, ICC_PROFILE};
+use super::records::PsdFile;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IccProfile {
+    /// The description of the profile, like `Display P3` or `Adobe RGB (1998)`
+    pub name: String,
+    /// The color space of the profile, like `RGB `, `GRAY` or `CMYK`
+    pub color_space: [u8; 4],
+    /// Linear colors to linear sRGB, for RGB profiles built out of primaries
+    matrix: Option<[[f64; 3]; 3]>,
+    /// The red, green and blue curves, or only the gray curve
+    curves: Vec<ToneCurve>,
+}
+
+/// Turns the encoded value of a channel into linear light
+#[derive(Debug, Clone, PartialEq)]
+enum ToneCurve {
+    Gamma(f64),
+    Table(Vec<f64>),
+    Parametric(u16, [f64; 7]),
+}
+
+impl ToneCurve {
+    fn read(data: &[u8]) -> Option<ToneCurve> {
+        match data.get(..4)? {
+            b"curv" => match u32_at(data, 8)? {
+                0 => Some(ToneCurve::Gamma(1.0)),
+                1 => Some(ToneCurve::Gamma(u16_at(data, 12)? as f64 / 256.0)),
+                count => (0..count as usize)
+                    .map(|index| Some(u16_at(data, 12 + index * 2)? as f64 / 65535.0))
+                    .collect::<Option<Vec<f64>>>()
+                    .map(ToneCurve::Table),
+            },
+            b"para" => {
+                let function = u16_at(data, 8)?;
+                let count = [1, 3, 4, 5, 7].get(function as usize)?;
+
+                let mut parameters = [0.0; 7];
+                for (index, parameter) in parameters.iter_mut().take(*count).enumerate() {
+                    *parameter = fixed_at(data, 12 + index * 4)?;
+                }
+
+                Some(ToneCurve::Parametric(function, parameters))
+            }
+            _ => None,
+        }
+    }
+
+    fn linear(&self, value: f64) -> f64 {
+        let value = value.clamp(0.0, 1.0);
+
+        match self {
+            ToneCurve::Gamma(gamma) => value.powf(*gamma),
+            ToneCurve::Table(table) if table.len() < 2 => value,
+            ToneCurve::Table(table) => {
+                let position = value * (table.len() - 1) as f64;
+                let index = (position as usize).min(table.len() - 2);
+                let fraction = position - index as f64;
+
+                table[index] + (table[index + 1] - table[index]) * fraction
+            }
+            ToneCurve::Parametric(function, [g, a, b, c, d, e, f]) => {
+                let curve = |value: f64| (a * value + b).max(0.0).powf(*g);
+
+                match function {
+                    0 => value.powf(*g),
+                    1 if value >= -b / a => curve(value),
+                    1 => 0.0,
+                    2 if value >= -b / a => curve(value) + c,
+                    2 => *c,
+                    3 if value >= *d => curve(value),
+                    3 => c * value,
+                    _ if value >= *d => curve(value) + e,
+                    _ => c * value + f,
+                }
+            }
+        }
+    }
+}
+
+impl IccProfile {
+    pub fn read(data: &[u8]) -> Option<IccProfile> {
+        let color_space = data.get(16..20)?.try_into().ok()?;
+
+        let tags = (0..u32_at(data, 128)? as usize)
+            .filter_map(|index| {
+                let entry = 132 + index * 12;
+                let offset = u32_at(data, entry + 4)? as usize;
+                let length = u32_at(data, entry + 8)? as usize;
+
+                Some((
+                    data.get(entry..entry + 4)?,
+                    data.get(offset..offset + length)?,
+                ))
+            })
+            .collect::<Vec<(&[u8], &[u8])>>();
+
+        let tag = |signature: &[u8; 4]| {
+            tags.iter()
+                .find(|(key, _)| *key == signature)
+                .map(|(_, data)| *data)
+        };
+
+        let name = tag(b"desc").and_then(description).unwrap_or_default();
+
+        let matrix = match &color_space {
+            b"RGB " => {
+                let [red, green, blue] =
+                    [b"rXYZ", b"gXYZ", b"bXYZ"].map(|signature| tag(signature).and_then(xyz));
+
+                match (red, green, blue) {
+                    (Some(red), Some(green), Some(blue)) => {
+                        // The primaries are in XYZ adapted to D50, as sRGB they make up the
+                        // columns of the matrix
+                        let columns = [red, green, blue].map(xyz_to_linear_srgb);
+                        Some([0, 1, 2].map(|row| columns.map(|column| column[row])))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        let curves = match &color_space {
+            b"RGB " => [b"rTRC", b"gTRC", b"bTRC"]
+                .iter()
+                .map(|signature| tag(signature).and_then(ToneCurve::read))
+                .collect::<Option<Vec<ToneCurve>>>()
+                .unwrap_or_default(),
+            b"GRAY" => tag(b"kTRC")
+                .and_then(ToneCurve::read)
+                .into_iter()
+                .collect::<Vec<ToneCurve>>(),
+            _ => vec![],
+        };
+
+        Some(IccProfile {
+            name,
+            color_space,
+            matrix,
+            curves,
+        })
+    }
+
+    /// Whether colors can be converted to sRGB, which works for RGB profiles with primaries and
+    /// gray profiles with a curve
+    pub fn is_supported(&self) -> bool {
+        match &self.color_space {
+            b"RGB " => self.matrix.is_some() && self.curves.len() == 3,
+            b"GRAY" => self.curves.len() == 1,
+            _ => false,
+        }
+    }
+
+    /// Whether colors are sRGB already, leaving tiny differences in the profile be
+    pub fn is_srgb(&self) -> bool {
+        let identity = self.matrix.is_none_or(|matrix| {
+            (0..9).all(|index| {
+                let expected = (index / 3 == index % 3) as u8 as f64;
+                (matrix[index / 3][index % 3] - expected).abs() < 0.01
+            })
+        });
+
+        identity
+            && self.curves.iter().all(|curve| {
+                (1..10).all(|step| {
+                    let value = step as f64 / 10.0;
+                    (curve.linear(value) - decode_srgb(value)).abs() < 0.01
+                })
+            })
+    }
+
+    /// Converts a color to sRGB, or to linear sRGB when the color is `linear` already, like the
+    /// colors of 32-bit documents are. Linear colors can go past 1.0.
+    pub fn to_srgb(&self, color: [f32; 3], linear: bool) -> [f32; 3] {
+        let decode = |curve: &ToneCurve, value: f32| match linear {
+            true => value as f64,
+            false => curve.linear(value as f64),
+        };
+
+        let colors = match (&self.matrix, self.curves.as_slice()) {
+            (Some(matrix), [red, green, blue]) => {
+                let color = [
+                    decode(red, color[0]),
+                    decode(green, color[1]),
+                    decode(blue, color[2]),
+                ];
+
+                matrix
+                    .map(|row| (row[0] * color[0] + row[1] * color[1] + row[2] * color[2]).max(0.0))
+            }
+            (None, [gray]) => color.map(|value| decode(gray, value)),
+            _ => return color,
+        };
+
+        colors.map(|value| match linear {
+            true => value as f32,
+            false => encode_srgb(value.clamp(0.0, 1.0)) as f32,
+        })
+    }
+}
+
+impl PsdFile {
+    pub fn color_profile(&self) -> Option<IccProfile> {
+        IccProfile::read(&self.resource(ICC_PROFILE)?.data)
+    }
+
+    /// The color profile colors are converted to sRGB with, if they aren't sRGB already
+    pub fn color_conversion(&self) -> Option<IccProfile> {
+        self.color_profile()
+            .filter(|profile| profile.is_supported() && !profile.is_srgb())
+    }
+}
+
+/// The English description of a profile, out of a `desc` (version 2) or `mluc` (version 4) tag
+fn description(data: &[u8]) -> Option<String> {
+    match data.get(..4)? {
+        b"desc" => {
+            let length = u32_at(data, 8)? as usize;
+            let text = data.get(12..12 + length)?;
+
+            Some(
+                text.iter()
+                    .take_while(|character| **character != 0)
+                    .map(|character| *character as char)
+                    .collect::<String>(),
+            )
+        }
+        b"mluc" => {
+            let count = u32_at(data, 8)? as usize;
+            let size = u32_at(data, 12)? as usize;
+
+            let records = (0..count).filter_map(|index| data.get(16 + index * size..)?.get(..12));
+            let record = records
+                .clone()
+                .find(|record| &record[..2] == b"en")
+                .or_else(|| records.clone().next())?;
+
+            let length = u32_at(record, 4)? as usize;
+            let offset = u32_at(record, 8)? as usize;
+            let text = data
+                .get(offset..offset + length)?
+                .chunks_exact(2)
+                .map(|character| u16::from_be_bytes([character[0], character[1]]))
+                .collect::<Vec<u16>>();
+
+            Some(
+                String::from_utf16_lossy(&text)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            )
+        }
+        _ => None,
+    }
+}
+
+fn xyz(data: &[u8]) -> Option<[f64; 3]> {
+    match data.get(..4)? {
+        b"XYZ " => Some([fixed_at(data, 8)?, fixed_at(data, 12)?, fixed_at(data, 16)?]),
+        _ => None,
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Signed 15.16 fixed point number
+fn fixed_at(data: &[u8], offset: usize) -> Option<f64> {
+    Some(u32_at(data, offset)? as i32 as f64 / 65536.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(value: f64) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    fn xyz_tag([x, y, z]: [f64; 3]) -> Vec<u8> {
+        [b"XYZ \0\0\0\0".to_vec(), [x, y, z].map(fixed).concat()].concat()
+    }
+
+    fn gamma_tag(gamma: f64) -> Vec<u8> {
+        let mut tag = b"curv\0\0\0\0".to_vec();
+        tag.extend_from_slice(&1u32.to_be_bytes());
+        tag.extend_from_slice(&((gamma * 256.0) as u16).to_be_bytes());
+        tag
+    }
+
+    fn para_tag(function: u16, parameters: &[f64]) -> Vec<u8> {
+        let mut tag = b"para\0\0\0\0".to_vec();
+        tag.extend_from_slice(&function.to_be_bytes());
+        tag.extend_from_slice(&[0, 0]);
+        for parameter in parameters {
+            tag.extend_from_slice(&fixed(*parameter));
+        }
+        tag
+    }
+
+    fn desc_tag(name: &str) -> Vec<u8> {
+        let mut tag = b"desc\0\0\0\0".to_vec();
+        tag.extend_from_slice(&(name.len() as u32 + 1).to_be_bytes());
+        tag.extend_from_slice(name.as_bytes());
+        tag.push(0);
+        tag
+    }
+
+    fn mluc_tag(records: &[(&[u8; 4], &str)]) -> Vec<u8> {
+        let mut tag = b"mluc\0\0\0\0".to_vec();
+        tag.extend_from_slice(&(records.len() as u32).to_be_bytes());
+        tag.extend_from_slice(&12u32.to_be_bytes());
+
+        let mut strings = vec![];
+        for (language, name) in records {
+            let text = name
+                .encode_utf16()
+                .flat_map(u16::to_be_bytes)
+                .collect::<Vec<u8>>();
+
+            tag.extend_from_slice(*language);
+            tag.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            tag.extend_from_slice(
+                &((16 + records.len() * 12 + strings.len()) as u32).to_be_bytes(),
+            );
+            strings.extend_from_slice(&text);
+        }
+
+        tag.extend_from_slice(&strings);
+        tag
+    }
+
+    /// A profile with a header, a tag table and the tags one after the other
+    fn profile(color_space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[16..20].copy_from_slice(color_space);
+        data.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+
+        let mut offset = 132 + tags.len() * 12;
+        for (signature, tag) in tags {
+            data.extend_from_slice(*signature);
+            data.extend_from_slice(&(offset as u32).to_be_bytes());
+            data.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            offset += tag.len();
+        }
+
+        for (_, tag) in tags {
+            data.extend_from_slice(tag);
+        }
+
+        data
+    }
+
+    fn rgb_profile(name: Vec<u8>, primaries: [[f64; 3]; 3], curve: Vec<u8>) -> IccProfile {
+        IccProfile::read(&profile(
+            b"RGB ",
+            &[
+                (b"desc", name),
+                (b"rXYZ", xyz_tag(primaries[0])),
+                (b"gXYZ", xyz_tag(primaries[1])),
+                (b"bXYZ", xyz_tag(primaries[2])),
+                (b"rTRC", curve.clone()),
+                (b"gTRC", curve.clone()),
+                (b"bTRC", curve),
+            ],
+        ))
+        .unwrap()
+    }
+
+    const SRGB_PRIMARIES: [[f64; 3]; 3] = [
+        [0.4361, 0.2225, 0.0139],
+        [0.3851, 0.7169, 0.0971],
+        [0.1431, 0.0606, 0.7141],
+    ];
+
+    const SRGB_CURVE: [f64; 5] = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+
+    #[test]
+    fn curves() {
+        let curve = ToneCurve::read(&gamma_tag(2.2)).unwrap();
+        assert!((curve.linear(0.5) - 0.5f64.powf(2.2)).abs() < 0.001);
+
+        let mut identity = b"curv\0\0\0\0".to_vec();
+        identity.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(ToneCurve::read(&identity), Some(ToneCurve::Gamma(1.0)));
+
+        // Tables are interpolated between their entries
+        let mut table = b"curv\0\0\0\0".to_vec();
+        table.extend_from_slice(&3u32.to_be_bytes());
+        for value in [0u16, 16384, 65535] {
+            table.extend_from_slice(&value.to_be_bytes());
+        }
+        let curve = ToneCurve::read(&table).unwrap();
+        assert!((curve.linear(0.5) - 0.25).abs() < 0.001);
+        assert!((curve.linear(0.75) - 0.625).abs() < 0.001);
+        assert_eq!(curve.linear(1.0), 1.0);
+
+        assert_eq!(ToneCurve::read(b"text\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn parametric_curves() {
+        let linear = |function: u16, parameters: &[f64], value: f64| {
+            ToneCurve::read(&para_tag(function, parameters))
+                .unwrap()
+                .linear(value)
+        };
+        let close = |actual: f64, expected: f64| {
+            assert!(
+                (actual - expected).abs() < 0.001,
+                "expected {expected}, got {actual}"
+            )
+        };
+
+        close(linear(0, &[2.0], 0.5), 0.25);
+
+        // (a * x + b) ^ g, cut off below -b / a
+        close(linear(1, &[1.0, 2.0, -0.5], 0.5), 0.5);
+        close(linear(1, &[1.0, 2.0, -0.5], 0.2), 0.0);
+
+        // Same as 1, with c added everywhere
+        close(linear(2, &[1.0, 2.0, -0.5, 0.1], 0.5), 0.6);
+        close(linear(2, &[1.0, 2.0, -0.5, 0.1], 0.2), 0.1);
+
+        // The sRGB curve, linear below d
+        close(linear(3, &SRGB_CURVE, 0.5), decode_srgb(0.5));
+        close(linear(3, &SRGB_CURVE, 0.02), decode_srgb(0.02));
+
+        // Same as 3, with e and f added above and below d
+        let parameters = [1.0, 1.0, 0.0, 0.5, 0.5, 0.1, 0.2];
+        close(linear(4, &parameters, 0.75), 0.85);
+        close(linear(4, &parameters, 0.25), 0.325);
+
+        assert_eq!(ToneCurve::read(&para_tag(5, &[1.0])), None);
+    }
+
+    #[test]
+    fn descriptions() {
+        assert_eq!(
+            description(&desc_tag("Adobe RGB (1998)")),
+            Some("Adobe RGB (1998)".to_string())
+        );
+
+        // English is picked out of the languages, falling back to the first one
+        assert_eq!(
+            description(&mluc_tag(&[(b"deDE", "Anzeige"), (b"enUS", "Display P3")])),
+            Some("Display P3".to_string())
+        );
+        assert_eq!(
+            description(&mluc_tag(&[(b"deDE", "Anzeige")])),
+            Some("Anzeige".to_string())
+        );
+    }
+
+    #[test]
+    fn srgb() {
+        let srgb = rgb_profile(
+            desc_tag("sRGB IEC61966-2.1"),
+            SRGB_PRIMARIES,
+            para_tag(3, &SRGB_CURVE),
+        );
+        assert_eq!(srgb.name, "sRGB IEC61966-2.1");
+        assert!(srgb.is_supported() && srgb.is_srgb());
+
+        // Gamma 2.2 is close enough to the sRGB curve to leave be, gamma 1.8 isn't
+        let gamma = rgb_profile(desc_tag("Gamma 2.2"), SRGB_PRIMARIES, gamma_tag(2.2));
+        assert!(gamma.is_supported() && gamma.is_srgb());
+        let gamma = rgb_profile(desc_tag("Gamma 1.8"), SRGB_PRIMARIES, gamma_tag(1.8));
+        assert!(gamma.is_supported() && !gamma.is_srgb());
+
+        // Display P3 primaries, with the sRGB curve
+        let p3 = rgb_profile(
+            mluc_tag(&[(b"enUS", "Display P3")]),
+            [
+                [0.5151, 0.2412, -0.0011],
+                [0.2920, 0.6922, 0.0419],
+                [0.1571, 0.0666, 0.7841],
+            ],
+            para_tag(3, &SRGB_CURVE),
+        );
+        assert_eq!(p3.name, "Display P3");
+        assert!(!p3.is_srgb());
+
+        // White stays white, P3 red is past the reddest sRGB red
+        let white = p3.to_srgb([1.0, 1.0, 1.0], false);
+        assert!(white.iter().all(|channel| *channel > 0.999));
+        let red = p3.to_srgb([1.0, 0.0, 0.0], true);
+        assert!(red[0] > 1.0 && red[1] == 0.0);
+
+        let cmyk = IccProfile::read(&profile(b"CMYK", &[(b"desc", desc_tag("Coated"))])).unwrap();
+        assert!(!cmyk.is_supported());
+        assert_eq!(cmyk.to_srgb([0.5, 0.5, 0.5], false), [0.5, 0.5, 0.5]);
+    }
+}
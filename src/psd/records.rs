@@ -0,0 +1,1153 @@
+//! Raw parsing of the parts of a PSD file the `psd` crate doesn't expose, like the additional
+//! layer information attached to every layer record.
+
+use std::fmt;
+
+use super::descriptor::Descriptor;
+
+#[derive(Debug)]
+pub enum RecordError {
+    UnexpectedEnd,
+    InvalidSignature(&'static str),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::UnexpectedEnd => write!(f, "unexpected end of data"),
+            RecordError::InvalidSignature(section) => write!(f, "invalid signature in {section}"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, RecordError>;
+
+pub(super) struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Cursor { data, position: 0 }
+    }
+
+    pub(super) fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub(super) fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.remaining() {
+            return Err(RecordError::UnexpectedEnd);
+        }
+
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    pub(super) fn skip(&mut self, length: usize) -> Result<()> {
+        self.bytes(length).map(|_| ())
+    }
+
+    pub(super) fn peek(&self, length: usize) -> Option<&'a [u8]> {
+        self.data.get(self.position..self.position + length)
+    }
+
+    pub(super) fn key(&mut self) -> Result<[u8; 4]> {
+        let mut key = [0; 4];
+        key.copy_from_slice(self.bytes(4)?);
+
+        Ok(key)
+    }
+
+    pub(super) fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(super) fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub(super) fn i16(&mut self) -> Result<i16> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub(super) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(super) fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub(super) fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    /// Reads a section length, which is 8 bytes wide for large documents (PSB)
+    fn length(&mut self, large: bool) -> Result<usize> {
+        match large {
+            true => Ok(self.u64()? as usize),
+            false => Ok(self.u32()? as usize),
+        }
+    }
+
+    /// Reads a pascal string, of which the total length is padded to a multiple of `padding`
+    fn pascal_string(&mut self, padding: usize) -> Result<String> {
+        let length = self.u8()? as usize;
+        let string = self.bytes(length)?;
+
+        let total = length + 1;
+        self.skip((padding - total % padding) % padding)?;
+
+        Ok(string
+            .iter()
+            .map(|byte| char::from(*byte))
+            .collect::<String>())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PsdFile {
+    /// 1 for PSD, 2 for large documents (PSB)
+    pub version: u16,
+    pub channels: u16,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u16,
+    pub color_mode: u16,
+    pub color_mode_data: Vec<u8>,
+    pub resources: Vec<ImageResource>,
+    /// All layer records in file order (bottom to top), including group dividers
+    pub layers: Vec<LayerRecord>,
+    /// Compressed image data of the merged image, with all of its channels
+    pub image_data: Vec<u8>,
+}
+
+impl PsdFile {
+    pub fn from_bytes(bytes: &[u8]) -> Result<PsdFile> {
+        let mut cursor = Cursor::new(bytes);
+
+        if cursor.bytes(4)? != b"8BPS" {
+            return Err(RecordError::InvalidSignature("header"));
+        }
+
+        let version = cursor.u16()?;
+        cursor.skip(6)?;
+        let channels = cursor.u16()?;
+        let height = cursor.u32()?;
+        let width = cursor.u32()?;
+        let depth = cursor.u16()?;
+        let color_mode = cursor.u16()?;
+
+        let length = cursor.u32()? as usize;
+        let color_mode_data = cursor.bytes(length)?.to_vec();
+
+        let length = cursor.u32()? as usize;
+        let resources = ImageResource::read_all(cursor.bytes(length)?)?;
+
+        let large = version == 2;
+        let length = cursor.length(large)?;
+        let mut section = Cursor::new(cursor.bytes(length)?);
+
+        let mut layers = match section.remaining() {
+            0 => vec![],
+            _ => {
+                let length = section.length(large)?;
+                match length {
+                    0 => vec![],
+                    length => LayerRecord::read_all(
+                        &mut Cursor::new(section.bytes(length)?),
+                        large,
+                        depth,
+                    )?,
+                }
+            }
+        };
+
+        // Documents with 16 or 32 bits per channel keep their layers in a tagged block after the
+        // global layer mask instead
+        if layers.is_empty() && section.remaining() >= 4 {
+            let length = section.u32()? as usize;
+            section.skip(length)?;
+
+            let blocks = TaggedBlock::read_all(&mut section, large)?;
+            if let Some(block) = blocks
+                .iter()
+                .find(|block| matches!(&block.key, b"Lr16" | b"Lr32"))
+            {
+                layers = LayerRecord::read_all(&mut Cursor::new(&block.data), large, depth)?;
+            }
+        }
+
+        let image_data = cursor.bytes(cursor.remaining())?.to_vec();
+
+        Ok(PsdFile {
+            version,
+            channels,
+            width,
+            height,
+            depth,
+            color_mode,
+            color_mode_data,
+            resources,
+            layers,
+            image_data,
+        })
+    }
+
+    pub fn resource(&self, id: u16) -> Option<&ImageResource> {
+        self.resources.iter().find(|resource| resource.id == id)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageResource {
+    pub id: u16,
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl ImageResource {
+    fn read_all(bytes: &[u8]) -> Result<Vec<ImageResource>> {
+        let mut cursor = Cursor::new(bytes);
+        let mut resources = vec![];
+
+        while cursor.remaining() >= 12 {
+            if cursor.bytes(4)? != b"8BIM" {
+                return Err(RecordError::InvalidSignature("image resources"));
+            }
+
+            let id = cursor.u16()?;
+            let name = cursor.pascal_string(2)?;
+            let length = cursor.u32()? as usize;
+            let data = cursor.bytes(length)?.to_vec();
+            cursor.skip(length % 2)?;
+
+            resources.push(ImageResource { id, name, data });
+        }
+
+        Ok(resources)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelInfo {
+    pub id: i16,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaggedBlock {
+    pub key: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerRecord {
+    pub top: i32,
+    pub left: i32,
+    pub bottom: i32,
+    pub right: i32,
+    pub channels: Vec<ChannelInfo>,
+    pub blend_key: [u8; 4],
+    pub opacity: u8,
+    /// 0 for base layers, 1 when clipped to the layer below
+    pub clipping: u8,
+    pub flags: u8,
+    pub mask_data: Vec<u8>,
+    pub mask: Option<LayerMask>,
+    pub name: String,
+    pub blocks: Vec<TaggedBlock>,
+    /// Compressed image data of every channel, in the same order as `channels`
+    pub channel_data: Vec<Vec<u8>>,
+}
+
+/// The raster mask of a layer or group
+#[derive(Debug, Clone)]
+pub struct LayerMask {
+    pub top: i32,
+    pub left: i32,
+    pub bottom: i32,
+    pub right: i32,
+    /// Value of the mask outside of its rect
+    pub default_color: u8,
+    pub disabled: bool,
+    pub inverted: bool,
+    /// How much of the mask is applied, from 0 to 255
+    pub density: u8,
+    /// Blur radius in pixels
+    pub feather: f64,
+    /// Id of the channel holding the pixels, as layers with a vector mask keep it in another one
+    pub channel: i16,
+    /// One byte per pixel in the rect, empty when the channel couldn't be decoded
+    pub pixels: Vec<u8>,
+}
+
+/// Used for nodes we couldn't find a record for, so they behave like plain normal layers
+pub(super) static EMPTY_RECORD: LayerRecord = LayerRecord {
+    top: 0,
+    left: 0,
+    bottom: 0,
+    right: 0,
+    channels: Vec::new(),
+    blend_key: *b"norm",
+    opacity: 255,
+    clipping: 0,
+    flags: 0,
+    mask_data: Vec::new(),
+    mask: None,
+    name: String::new(),
+    blocks: Vec::new(),
+    channel_data: Vec::new(),
+};
+
+/// Keys of tagged blocks that have an 8 byte length in large documents (PSB)
+const LARGE_KEYS: [&[u8; 4]; 13] = [
+    b"LMsk", b"Lr16", b"Lr32", b"Layr", b"Mt16", b"Mt32", b"Mtrn", b"Alph", b"FMsk", b"lnk2",
+    b"FEid", b"FXid", b"PxSD",
+];
+
+impl LayerRecord {
+    /// Reads the layer info section, starting at the layer count
+    fn read_all(cursor: &mut Cursor, large: bool, depth: u16) -> Result<Vec<LayerRecord>> {
+        // Negative when the first alpha channel holds the transparency of the merged result
+        let count = cursor.i16()?.unsigned_abs();
+
+        let mut records = (0..count)
+            .map(|_| LayerRecord::read(cursor, large))
+            .collect::<Result<Vec<LayerRecord>>>()?;
+
+        // The image data of every channel follows the records in the same order. The colors are
+        // kept for documents the `psd` crate can't read (see `color.rs`), masks are decoded here.
+        for record in records.iter_mut() {
+            for channel in record.channels.clone() {
+                let Ok(data) = cursor.bytes(channel.length) else {
+                    return Ok(records);
+                };
+
+                record.channel_data.push(data.to_vec());
+
+                if let Some(mask) = record
+                    .mask
+                    .as_mut()
+                    .filter(|mask| mask.channel == channel.id)
+                {
+                    let width = (mask.right - mask.left).max(0) as usize;
+                    let height = (mask.bottom - mask.top).max(0) as usize;
+
+                    mask.pixels =
+                        decode_channel(data, width, height, depth, large).unwrap_or_default();
+                }
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn read(cursor: &mut Cursor, large: bool) -> Result<LayerRecord> {
+        let top = cursor.i32()?;
+        let left = cursor.i32()?;
+        let bottom = cursor.i32()?;
+        let right = cursor.i32()?;
+
+        let channels = (0..cursor.u16()?)
+            .map(|_| {
+                Ok(ChannelInfo {
+                    id: cursor.i16()?,
+                    length: cursor.length(large)?,
+                })
+            })
+            .collect::<Result<Vec<ChannelInfo>>>()?;
+
+        if cursor.bytes(4)? != b"8BIM" {
+            return Err(RecordError::InvalidSignature("layer record"));
+        }
+
+        let blend_key = cursor.key()?;
+        let opacity = cursor.u8()?;
+        let clipping = cursor.u8()?;
+        let flags = cursor.u8()?;
+        cursor.skip(1)?;
+
+        let length = cursor.u32()? as usize;
+        let mut extra = Cursor::new(cursor.bytes(length)?);
+
+        let length = extra.u32()? as usize;
+        let mask_data = extra.bytes(length)?.to_vec();
+        let mask = LayerMask::read(&mask_data, &channels);
+
+        let length = extra.u32()? as usize;
+        extra.skip(length)?;
+
+        let name = extra.pascal_string(4)?;
+        let blocks = TaggedBlock::read_all(&mut extra, large)?;
+
+        Ok(LayerRecord {
+            top,
+            left,
+            bottom,
+            right,
+            channels,
+            blend_key,
+            opacity,
+            clipping,
+            flags,
+            mask_data,
+            mask,
+            name,
+            blocks,
+            channel_data: vec![],
+        })
+    }
+
+    pub fn block(&self, key: &[u8; 4]) -> Option<&[u8]> {
+        self.blocks
+            .iter()
+            .find(|block| &block.key == key)
+            .map(|block| block.data.as_slice())
+    }
+
+    /// Section divider type, 1 and 2 being (open and closed) groups and 3 the hidden marker
+    /// closing off a group
+    pub fn divider(&self) -> Option<u32> {
+        let data = self.block(b"lsct").or_else(|| self.block(b"lsdk"))?;
+
+        Cursor::new(data).u32().ok()
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        // Groups store their real blend mode in the divider block
+        let key = match self.block(b"lsct") {
+            Some(data) if data.len() >= 12 && &data[4..8] == b"8BIM" => &data[8..12],
+            _ => &self.blend_key,
+        };
+
+        BlendMode::from_key(key)
+    }
+
+    pub fn is_clipped(&self) -> bool {
+        self.clipping != 0
+    }
+
+    pub fn fill_opacity(&self) -> u8 {
+        match self.block(b"iOpa") {
+            Some([fill_opacity, ..]) => *fill_opacity,
+            _ => 255,
+        }
+    }
+
+    pub fn locks(&self) -> LayerLocks {
+        let flags = self
+            .block(b"lspf")
+            .and_then(|data| Cursor::new(data).u32().ok())
+            .unwrap_or(0);
+
+        LayerLocks {
+            transparency: flags & 1 != 0,
+            pixels: flags & 2 != 0,
+            position: flags & 4 != 0,
+            all: flags & 0x8000_0000 != 0 || flags & 7 == 7,
+        }
+    }
+
+    pub fn vector_mask(&self) -> Option<VectorMask> {
+        let data = self.block(b"vmsk").or_else(|| self.block(b"vsms"))?;
+
+        VectorMask::read(data, MaskParameters::from_mask_data(&self.mask_data))
+    }
+
+    /// The color a shape or solid color fill layer is filled with, as RGB from 0.0 to 1.0
+    pub fn fill_color(&self) -> Option<[f32; 3]> {
+        Descriptor::from_versioned(self.block(b"SoCo")?)?.color("Clr ")
+    }
+
+    pub fn color_label(&self) -> ColorLabel {
+        let color = self
+            .block(b"lclr")
+            .and_then(|data| Cursor::new(data).u16().ok())
+            .unwrap_or(0);
+
+        ColorLabel::from_index(color)
+    }
+}
+
+impl LayerMask {
+    /// Reads the raster mask out of the mask data of a record, if it has a channel for it
+    fn read(data: &[u8], channels: &[ChannelInfo]) -> Option<LayerMask> {
+        let mut cursor = Cursor::new(data);
+
+        let mut top = cursor.i32().ok()?;
+        let mut left = cursor.i32().ok()?;
+        let mut bottom = cursor.i32().ok()?;
+        let mut right = cursor.i32().ok()?;
+        let mut default_color = cursor.u8().ok()?;
+        let mut flags = cursor.u8().ok()?;
+
+        let parameters = MaskParameters::read(&mut cursor, data.len(), flags)?;
+
+        let has_channel = |id: i16| channels.iter().any(|channel| channel.id == id);
+
+        // With a vector mask as well, the raster mask is described by the "real" fields
+        let channel = if has_channel(-3) && data.len() > 20 && cursor.remaining() >= 18 {
+            flags = cursor.u8().ok()?;
+            default_color = cursor.u8().ok()?;
+            top = cursor.i32().ok()?;
+            left = cursor.i32().ok()?;
+            bottom = cursor.i32().ok()?;
+            right = cursor.i32().ok()?;
+
+            -3
+        } else if has_channel(-2) {
+            -2
+        } else {
+            return None;
+        };
+
+        Some(LayerMask {
+            top,
+            left,
+            bottom,
+            right,
+            default_color,
+            disabled: flags & 2 != 0,
+            inverted: flags & 4 != 0,
+            density: parameters.density,
+            feather: parameters.feather,
+            channel,
+            pixels: vec![],
+        })
+    }
+}
+
+/// Density and feather of the raster and vector mask, which are stored in the mask data
+#[derive(Debug, Clone, Copy)]
+struct MaskParameters {
+    density: u8,
+    feather: f64,
+    vector_density: u8,
+    vector_feather: f64,
+}
+
+impl Default for MaskParameters {
+    fn default() -> Self {
+        MaskParameters {
+            density: 255,
+            feather: 0.0,
+            vector_density: 255,
+            vector_feather: 0.0,
+        }
+    }
+}
+
+impl MaskParameters {
+    fn read(cursor: &mut Cursor, length: usize, flags: u8) -> Option<MaskParameters> {
+        let mut parameters = MaskParameters::default();
+
+        if length <= 20 || flags & 16 == 0 {
+            return Some(parameters);
+        }
+
+        let present = cursor.u8().ok()?;
+
+        if present & 1 != 0 {
+            parameters.density = cursor.u8().ok()?;
+        }
+
+        if present & 2 != 0 {
+            parameters.feather = cursor.f64().ok()?;
+        }
+
+        if present & 4 != 0 {
+            parameters.vector_density = cursor.u8().ok()?;
+        }
+
+        if present & 8 != 0 {
+            parameters.vector_feather = cursor.f64().ok()?;
+        }
+
+        Some(parameters)
+    }
+
+    /// Reads the parameters out of the mask data of a record
+    fn from_mask_data(data: &[u8]) -> Option<MaskParameters> {
+        let mut cursor = Cursor::new(data);
+        cursor.skip(17).ok()?;
+        let flags = cursor.u8().ok()?;
+
+        MaskParameters::read(&mut cursor, data.len(), flags)
+    }
+}
+
+/// The vector mask of a layer, or the outline of a shape layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorMask {
+    pub disabled: bool,
+    pub inverted: bool,
+    /// How much of the mask is applied, from 0 to 255
+    pub density: u8,
+    /// Blur radius in pixels
+    pub feather: f64,
+    /// Whether everything outside of the subpaths starts out filled
+    pub initial_fill: bool,
+    pub subpaths: Vec<Subpath>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subpath {
+    pub closed: bool,
+    /// How this subpath is combined with the ones before it
+    pub operation: PathOperation,
+    pub knots: Vec<Knot>,
+}
+
+/// A point on a bezier path with its two control points. Coordinates are `[x, y]` relative to
+/// the size of the document, so `[1.0, 1.0]` is the bottom right corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Knot {
+    pub preceding: [f64; 2],
+    pub anchor: [f64; 2],
+    pub leaving: [f64; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathOperation {
+    Combine,
+    Subtract,
+    Intersect,
+    Exclude,
+}
+
+impl VectorMask {
+    fn read(data: &[u8], parameters: Option<MaskParameters>) -> Option<VectorMask> {
+        let mut cursor = Cursor::new(data);
+        cursor.u32().ok()?;
+        let flags = cursor.u32().ok()?;
+
+        let mut initial_fill = false;
+        let mut subpaths: Vec<Subpath> = vec![];
+
+        while cursor.remaining() >= 26 {
+            let mut record = Cursor::new(cursor.bytes(26).ok()?);
+
+            match record.u16().ok()? {
+                selector @ (0 | 3) => {
+                    record.u16().ok()?;
+
+                    // Older files don't store an operation, of which the subpaths are xor'ed
+                    let operation = match record.i16().ok()? {
+                        1 => PathOperation::Combine,
+                        2 => PathOperation::Subtract,
+                        3 => PathOperation::Intersect,
+                        _ => PathOperation::Exclude,
+                    };
+
+                    subpaths.push(Subpath {
+                        closed: selector == 0,
+                        operation,
+                        knots: vec![],
+                    });
+                }
+                1 | 2 | 4 | 5 => {
+                    let mut point = || -> Option<[f64; 2]> {
+                        let y = record.i32().ok()? as f64 / (1 << 24) as f64;
+                        let x = record.i32().ok()? as f64 / (1 << 24) as f64;
+
+                        Some([x, y])
+                    };
+
+                    let knot = Knot {
+                        preceding: point()?,
+                        anchor: point()?,
+                        leaving: point()?,
+                    };
+
+                    subpaths.last_mut()?.knots.push(knot);
+                }
+                8 => initial_fill = record.u16().ok()? == 1,
+                _ => {}
+            }
+        }
+
+        let parameters = parameters.unwrap_or_default();
+
+        Some(VectorMask {
+            disabled: flags & 4 != 0,
+            inverted: flags & 1 != 0,
+            density: parameters.vector_density,
+            feather: parameters.vector_feather,
+            initial_fill,
+            subpaths,
+        })
+    }
+}
+
+/// Decodes the image data of a single channel to 8 bits per pixel, or `None` when it's broken
+pub(super) fn decode_channel(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    depth: u16,
+    large: bool,
+) -> Option<Vec<u8>> {
+    let mut cursor = Cursor::new(data);
+    let compression = cursor.u16().ok()?;
+    let raw = unpack(&mut cursor, compression, width, height, depth, large)?;
+
+    Some(to_8_bit(&raw, width, height, depth))
+}
+
+/// Like [`decode_channel`], but at the precision of the document
+pub(super) fn decode_samples(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    depth: u16,
+    large: bool,
+) -> Option<Vec<f32>> {
+    let mut cursor = Cursor::new(data);
+    let compression = cursor.u16().ok()?;
+    let raw = unpack(&mut cursor, compression, width, height, depth, large)?;
+
+    Some(to_samples(&raw, width, height, depth))
+}
+
+/// Reads `height` rows of raw samples at `depth` bits, after the compression method
+pub(super) fn unpack(
+    cursor: &mut Cursor,
+    compression: u16,
+    width: usize,
+    height: usize,
+    depth: u16,
+    large: bool,
+) -> Option<Vec<u8>> {
+    let row_length = (width * depth as usize).div_ceil(8);
+
+    match compression {
+        0 => Some(cursor.bytes(row_length * height).ok()?.to_vec()),
+        1 => {
+            // PackBits, prefixed by the byte count of every row
+            cursor.skip(height * if large { 4 } else { 2 }).ok()?;
+            unpack_bits(cursor, row_length * height)
+        }
+        2 | 3 => {
+            let data = cursor.bytes(cursor.remaining()).ok()?;
+            let mut raw = miniz_oxide::inflate::decompress_to_vec_zlib(data).ok()?;
+            raw.resize(row_length * height, 0);
+
+            if compression == 3 {
+                unpredict(&mut raw, width, depth);
+            }
+
+            Some(raw)
+        }
+        _ => None,
+    }
+}
+
+/// Undoes the prediction of ZIP compressed data, which stores every sample as the difference with
+/// the one before it in the same row
+fn unpredict(raw: &mut [u8], width: usize, depth: u16) {
+    let row_length = (width * depth as usize).div_ceil(8);
+
+    for row in raw.chunks_exact_mut(row_length.max(1)) {
+        match depth {
+            16 => {
+                let mut previous = 0u16;
+                for sample in row.chunks_exact_mut(2) {
+                    previous = previous.wrapping_add(u16::from_be_bytes([sample[0], sample[1]]));
+                    sample.copy_from_slice(&previous.to_be_bytes());
+                }
+            }
+            32 => {
+                for index in 1..row.len() {
+                    row[index] = row[index].wrapping_add(row[index - 1]);
+                }
+
+                // The bytes of the floats are split up, first the highest byte of every float
+                let planes = row.to_vec();
+                for (index, byte) in row.iter_mut().enumerate() {
+                    *byte = planes[(index % 4) * width + index / 4];
+                }
+            }
+            _ => {
+                for index in 1..row.len() {
+                    row[index] = row[index].wrapping_add(row[index - 1]);
+                }
+            }
+        }
+    }
+}
+
+/// Decompresses PackBits runs until there are `length` bytes
+pub(super) fn unpack_bits(cursor: &mut Cursor, length: usize) -> Option<Vec<u8>> {
+    let mut raw = Vec::with_capacity(length);
+    while raw.len() < length {
+        let header = cursor.u8().ok()? as i8;
+
+        match header {
+            -128 => {}
+            0.. => raw.extend_from_slice(cursor.bytes(header as usize + 1).ok()?),
+            _ => {
+                let byte = cursor.u8().ok()?;
+                raw.resize(raw.len() + (1 - header as isize) as usize, byte);
+            }
+        }
+    }
+
+    raw.truncate(length);
+    Some(raw)
+}
+
+/// Turns raw samples into a float per pixel, from 0.0 to 1.0 except for the linear values of
+/// 32-bit documents which can go past 1.0. Bitmap documents use a bit per pixel, set for black.
+pub(super) fn to_samples(raw: &[u8], width: usize, height: usize, depth: u16) -> Vec<f32> {
+    match depth {
+        1 => {
+            let row_length = width.div_ceil(8);
+            (0..height)
+                .flat_map(|y| {
+                    (0..width).map(
+                        move |x| match raw[y * row_length + x / 8] & (0x80 >> (x % 8)) {
+                            0 => 1.0,
+                            _ => 0.0,
+                        },
+                    )
+                })
+                .collect::<Vec<f32>>()
+        }
+        16 => raw
+            .chunks_exact(2)
+            .map(|value| u16::from_be_bytes([value[0], value[1]]) as f32 / 65535.0)
+            .collect::<Vec<f32>>(),
+        32 => raw
+            .chunks_exact(4)
+            .map(|value| f32::from_be_bytes(value.try_into().unwrap()))
+            .collect::<Vec<f32>>(),
+        _ => raw
+            .iter()
+            .map(|value| *value as f32 / 255.0)
+            .collect::<Vec<f32>>(),
+    }
+}
+
+/// Turns raw samples into one byte per pixel
+pub(super) fn to_8_bit(raw: &[u8], width: usize, height: usize, depth: u16) -> Vec<u8> {
+    match depth {
+        8 => raw.to_vec(),
+        _ => to_samples(raw, width, height, depth)
+            .into_iter()
+            .map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8)
+            .collect::<Vec<u8>>(),
+    }
+}
+
+impl TaggedBlock {
+    fn read_all(cursor: &mut Cursor, large: bool) -> Result<Vec<TaggedBlock>> {
+        let mut blocks = vec![];
+
+        while cursor.remaining() >= 12 {
+            // Some writers pad blocks to a multiple of 4 without counting it in the length
+            if !matches!(cursor.peek(4), Some(b"8BIM") | Some(b"8B64")) {
+                match (1..4).find(|padding| {
+                    matches!(
+                        cursor
+                            .data
+                            .get(cursor.position + padding..cursor.position + padding + 4),
+                        Some(b"8BIM") | Some(b"8B64")
+                    )
+                }) {
+                    Some(padding) => cursor.skip(padding)?,
+                    None => break,
+                }
+            }
+
+            cursor.skip(4)?;
+            let key = cursor.key()?;
+            let length = cursor.length(large && LARGE_KEYS.contains(&&key))?;
+            let data = cursor.bytes(length)?.to_vec();
+
+            blocks.push(TaggedBlock { key, data });
+        }
+
+        Ok(blocks)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    PassThrough,
+    Normal,
+    Dissolve,
+    Darken,
+    Multiply,
+    ColorBurn,
+    LinearBurn,
+    DarkerColor,
+    Lighten,
+    Screen,
+    ColorDodge,
+    LinearDodge,
+    LighterColor,
+    Overlay,
+    SoftLight,
+    HardLight,
+    VividLight,
+    LinearLight,
+    PinLight,
+    HardMix,
+    Difference,
+    Exclusion,
+    Subtract,
+    Divide,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode {
+    pub fn from_key(key: &[u8]) -> BlendMode {
+        match key {
+            b"pass" => BlendMode::PassThrough,
+            b"diss" => BlendMode::Dissolve,
+            b"dark" => BlendMode::Darken,
+            b"mul " => BlendMode::Multiply,
+            b"idiv" => BlendMode::ColorBurn,
+            b"lbrn" => BlendMode::LinearBurn,
+            b"dkCl" => BlendMode::DarkerColor,
+            b"lite" => BlendMode::Lighten,
+            b"scrn" => BlendMode::Screen,
+            b"div " => BlendMode::ColorDodge,
+            b"lddg" => BlendMode::LinearDodge,
+            b"lgCl" => BlendMode::LighterColor,
+            b"over" => BlendMode::Overlay,
+            b"sLit" => BlendMode::SoftLight,
+            b"hLit" => BlendMode::HardLight,
+            b"vLit" => BlendMode::VividLight,
+            b"lLit" => BlendMode::LinearLight,
+            b"pLit" => BlendMode::PinLight,
+            b"hMix" => BlendMode::HardMix,
+            b"diff" => BlendMode::Difference,
+            b"smud" => BlendMode::Exclusion,
+            b"fsub" => BlendMode::Subtract,
+            b"fdiv" => BlendMode::Divide,
+            b"hue " => BlendMode::Hue,
+            b"sat " => BlendMode::Saturation,
+            b"colr" => BlendMode::Color,
+            b"lum " => BlendMode::Luminosity,
+            _ => BlendMode::Normal,
+        }
+    }
+
+    /// Blend mode out of the `BlnM` enumeration descriptors use, like in layer comps and effects
+    pub fn from_descriptor(value: &str) -> BlendMode {
+        match value {
+            "passThrough" => BlendMode::PassThrough,
+            "Dslv" => BlendMode::Dissolve,
+            "Drkn" => BlendMode::Darken,
+            "Mltp" => BlendMode::Multiply,
+            "CBrn" => BlendMode::ColorBurn,
+            "linearBurn" => BlendMode::LinearBurn,
+            "darkerColor" => BlendMode::DarkerColor,
+            "Lghn" => BlendMode::Lighten,
+            "Scrn" => BlendMode::Screen,
+            "CDdg" => BlendMode::ColorDodge,
+            "linearDodge" => BlendMode::LinearDodge,
+            "lighterColor" => BlendMode::LighterColor,
+            "Ovrl" => BlendMode::Overlay,
+            "SftL" => BlendMode::SoftLight,
+            "HrdL" => BlendMode::HardLight,
+            "vividLight" => BlendMode::VividLight,
+            "linearLight" => BlendMode::LinearLight,
+            "pinLight" => BlendMode::PinLight,
+            "hardMix" => BlendMode::HardMix,
+            "Dfrn" => BlendMode::Difference,
+            "Xclu" => BlendMode::Exclusion,
+            "blendSubtraction" => BlendMode::Subtract,
+            "blendDivide" => BlendMode::Divide,
+            "H   " => BlendMode::Hue,
+            "Strt" => BlendMode::Saturation,
+            "Clr " => BlendMode::Color,
+            "Lmns" => BlendMode::Luminosity,
+            _ => BlendMode::Normal,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlendMode::PassThrough => "pass_through",
+            BlendMode::Normal => "normal",
+            BlendMode::Dissolve => "dissolve",
+            BlendMode::Darken => "darken",
+            BlendMode::Multiply => "multiply",
+            BlendMode::ColorBurn => "color_burn",
+            BlendMode::LinearBurn => "linear_burn",
+            BlendMode::DarkerColor => "darker_color",
+            BlendMode::Lighten => "lighten",
+            BlendMode::Screen => "screen",
+            BlendMode::ColorDodge => "color_dodge",
+            BlendMode::LinearDodge => "linear_dodge",
+            BlendMode::LighterColor => "lighter_color",
+            BlendMode::Overlay => "overlay",
+            BlendMode::SoftLight => "soft_light",
+            BlendMode::HardLight => "hard_light",
+            BlendMode::VividLight => "vivid_light",
+            BlendMode::LinearLight => "linear_light",
+            BlendMode::PinLight => "pin_light",
+            BlendMode::HardMix => "hard_mix",
+            BlendMode::Difference => "difference",
+            BlendMode::Exclusion => "exclusion",
+            BlendMode::Subtract => "subtract",
+            BlendMode::Divide => "divide",
+            BlendMode::Hue => "hue",
+            BlendMode::Saturation => "saturation",
+            BlendMode::Color => "color",
+            BlendMode::Luminosity => "luminosity",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LayerLocks {
+    pub transparency: bool,
+    pub pixels: bool,
+    pub position: bool,
+    pub all: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorLabel {
+    None,
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Blue,
+    Violet,
+    Gray,
+}
+
+impl ColorLabel {
+    fn from_index(index: u16) -> ColorLabel {
+        match index {
+            1 => ColorLabel::Red,
+            2 => ColorLabel::Orange,
+            3 => ColorLabel::Yellow,
+            4 => ColorLabel::Green,
+            5 => ColorLabel::Blue,
+            6 => ColorLabel::Violet,
+            7 => ColorLabel::Gray,
+            _ => ColorLabel::None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorLabel::None => "none",
+            ColorLabel::Red => "red",
+            ColorLabel::Orange => "orange",
+            ColorLabel::Yellow => "yellow",
+            ColorLabel::Green => "green",
+            ColorLabel::Blue => "blue",
+            ColorLabel::Violet => "violet",
+            ColorLabel::Gray => "gray",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels(ids: &[i16]) -> Vec<ChannelInfo> {
+        ids.iter()
+            .map(|id| ChannelInfo { id: *id, length: 2 })
+            .collect::<Vec<ChannelInfo>>()
+    }
+
+    fn rect(top: i32, left: i32, bottom: i32, right: i32) -> Vec<u8> {
+        [top, left, bottom, right]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect::<Vec<u8>>()
+    }
+
+    #[test]
+    fn raster_mask() {
+        // Rect, default color, flags (inverted) and padding up to 20 bytes
+        let mut data = rect(1, 2, 3, 4);
+        data.extend_from_slice(&[255, 4, 0, 0]);
+
+        let mask = LayerMask::read(&data, &channels(&[0, -2])).unwrap();
+        assert_eq!((mask.top, mask.left, mask.bottom, mask.right), (1, 2, 3, 4));
+        assert_eq!((mask.default_color, mask.channel), (255, -2));
+        assert!(mask.inverted && !mask.disabled);
+        assert_eq!((mask.density, mask.feather), (255, 0.0));
+
+        assert!(LayerMask::read(&data, &channels(&[0, -1])).is_none());
+    }
+
+    #[test]
+    fn raster_and_vector_mask() {
+        // The vector mask's rect and flags, then the "real" flags (disabled), default color and
+        // rect of the raster mask
+        let mut data = rect(0, 0, 0, 0);
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&[2, 255]);
+        data.extend_from_slice(&rect(10, 20, 30, 40));
+
+        let mask = LayerMask::read(&data, &channels(&[0, -2, -3])).unwrap();
+        assert_eq!(
+            (mask.top, mask.left, mask.bottom, mask.right),
+            (10, 20, 30, 40)
+        );
+        assert_eq!((mask.default_color, mask.channel), (255, -3));
+        assert!(mask.disabled && !mask.inverted);
+
+        // Without a channel for the real mask the first rect is the raster mask
+        let mask = LayerMask::read(&data, &channels(&[0, -2])).unwrap();
+        assert_eq!((mask.bottom, mask.channel), (0, -2));
+    }
+
+    #[test]
+    fn density_and_feather() {
+        // Flags with parameters present, then every parameter followed by the real fields
+        let mut data = rect(0, 0, 8, 8);
+        data.extend_from_slice(&[0, 16, 1 | 2 | 4 | 8, 128]);
+        data.extend_from_slice(&2.5f64.to_be_bytes());
+        data.push(64);
+        data.extend_from_slice(&1.5f64.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&rect(1, 1, 7, 7));
+
+        let mask = LayerMask::read(&data, &channels(&[-2, -3])).unwrap();
+        assert_eq!((mask.density, mask.feather), (128, 2.5));
+        assert_eq!((mask.top, mask.bottom, mask.channel), (1, 7, -3));
+
+        let parameters = MaskParameters::from_mask_data(&data).unwrap();
+        assert_eq!(
+            (parameters.vector_density, parameters.vector_feather),
+            (64, 1.5)
+        );
+
+        // Only some of the parameters present
+        let mut data = rect(0, 0, 8, 8);
+        data.extend_from_slice(&[0, 16, 4, 32]);
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&rect(0, 0, 8, 8));
+
+        let parameters = MaskParameters::from_mask_data(&data).unwrap();
+        assert_eq!((parameters.density, parameters.feather), (255, 0.0));
+        assert_eq!(
+            (parameters.vector_density, parameters.vector_feather),
+            (32, 0.0)
+        );
+    }
+}
@@ -0,0 +1,762 @@
+use std::collections::HashMap;
+
+use super::{BlendMode, Bounds, LayerMask, NodeId, PsdElement, PsdNode, PsdTree};
+
+/// Document sized RGBA8 buffer with straight (not pre-multiplied) alpha
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Canvas {
+    /// Fully transparent canvas
+    pub fn new(width: u32, height: u32) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    pub fn from_rgba(width: u32, height: u32, mut pixels: Vec<u8>) -> Canvas {
+        pixels.resize(width as usize * height as usize * 4, 0);
+
+        Canvas {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn into_rgba(self) -> Vec<u8> {
+        self.pixels
+    }
+
+    /// Scales the alpha of every pixel by `opacity`
+    pub fn multiply_alpha(&mut self, opacity: u8) {
+        if opacity == 255 {
+            return;
+        }
+
+        for alpha in self.pixels.iter_mut().skip(3).step_by(4) {
+            *alpha = (*alpha as f32 * opacity as f32 / 255.0).round() as u8;
+        }
+    }
+
+    /// Draws `source` on top of this canvas with `opacity`, mixing colors using `mode`
+    pub fn draw(&mut self, source: &Canvas, opacity: u8, mode: BlendMode) {
+        self.composite(source, opacity, mode, false)
+    }
+
+    /// Like [`Canvas::draw`], but only onto pixels that are already there, keeping the alpha of
+    /// this canvas the way clipping masks do
+    pub fn draw_atop(&mut self, source: &Canvas, opacity: u8, mode: BlendMode) {
+        self.composite(source, opacity, mode, true)
+    }
+
+    /// Scales the alpha of every pixel by the alpha of the same pixel in `mask`
+    pub fn mask(&mut self, mask: &Canvas) {
+        let values = mask.pixels.iter().skip(3).step_by(4).copied();
+        self.apply_mask(values);
+    }
+
+    /// Scales the alpha of every pixel by a mask with one value per pixel
+    pub fn apply_mask(&mut self, values: impl IntoIterator<Item = u8>) {
+        for (pixel, value) in self.pixels.chunks_exact_mut(4).zip(values) {
+            pixel[3] = (pixel[3] as f32 * value as f32 / 255.0).round() as u8;
+        }
+    }
+
+    fn composite(&mut self, source: &Canvas, opacity: u8, mode: BlendMode, atop: bool) {
+        let opacity = opacity as f32 / 255.0;
+
+        for (index, (backdrop, source)) in self
+            .pixels
+            .chunks_exact_mut(4)
+            .zip(source.pixels.chunks_exact(4))
+            .enumerate()
+        {
+            let mut source_alpha = source[3] as f32 / 255.0 * opacity;
+
+            // Dissolve draws pixels either fully or not at all, using alpha as the odds
+            if mode == BlendMode::Dissolve {
+                source_alpha = match noise(index) < source_alpha {
+                    true => 1.0,
+                    false => 0.0,
+                };
+            }
+
+            if source_alpha <= 0.0 {
+                continue;
+            }
+
+            let backdrop_alpha = backdrop[3] as f32 / 255.0;
+            if atop && backdrop_alpha <= 0.0 {
+                continue;
+            }
+
+            let alpha = match atop {
+                true => backdrop_alpha,
+                false => source_alpha + backdrop_alpha * (1.0 - source_alpha),
+            };
+
+            let backdrop_color = [backdrop[0], backdrop[1], backdrop[2]].map(|c| c as f32 / 255.0);
+            let source_color = [source[0], source[1], source[2]].map(|c| c as f32 / 255.0);
+            let mixed = mode.blend(backdrop_color, source_color);
+
+            for channel in 0..3 {
+                // Only blend as far as there's a backdrop to blend with
+                let color = (1.0 - backdrop_alpha) * source_color[channel]
+                    + backdrop_alpha * mixed[channel];
+                let color = match atop {
+                    true => color * source_alpha + backdrop_color[channel] * (1.0 - source_alpha),
+                    false => {
+                        (color * source_alpha
+                            + backdrop_color[channel] * backdrop_alpha * (1.0 - source_alpha))
+                            / alpha
+                    }
+                };
+
+                backdrop[channel] = (color * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+
+            backdrop[3] = (alpha * 255.0).round() as u8;
+        }
+    }
+
+    /// A `width` by `height` part of the canvas starting at `x` and `y`, which can reach past its
+    /// edges to grow it with transparent pixels
+    pub fn region(&self, x: i32, y: i32, width: u32, height: u32) -> Canvas {
+        let mut region = Canvas::new(width, height);
+
+        for row in 0..height as i32 {
+            let source_row = row + y;
+            if source_row < 0 || source_row >= self.height as i32 {
+                continue;
+            }
+
+            let from = (-x).clamp(0, width as i32);
+            let to = (self.width as i32 - x).clamp(0, width as i32);
+            if from >= to {
+                continue;
+            }
+
+            let source = (source_row * self.width as i32 + from + x) as usize * 4;
+            let target = (row * width as i32 + from) as usize * 4;
+            let length = (to - from) as usize * 4;
+
+            region.pixels[target..target + length]
+                .copy_from_slice(&self.pixels[source..source + length]);
+        }
+
+        region
+    }
+
+    /// Moves the pixels `x` to the right and `y` down, leaving transparency behind
+    pub fn translate(&mut self, x: i32, y: i32) {
+        if x == 0 && y == 0 {
+            return;
+        }
+
+        let (width, height) = (self.width as i32, self.height as i32);
+        let mut pixels = vec![0; self.pixels.len()];
+
+        for row in 0..height {
+            let target = row + y;
+            if target < 0 || target >= height {
+                continue;
+            }
+
+            let from = (-x).clamp(0, width);
+            let to = (width - x).clamp(0, width);
+            if from >= to {
+                continue;
+            }
+
+            let source = (row * width + from) as usize * 4..(row * width + to) as usize * 4;
+            let start = (target * width + from + x) as usize * 4;
+            pixels[start..start + source.len()].copy_from_slice(&self.pixels[source]);
+        }
+
+        self.pixels = pixels;
+    }
+
+    /// Moves every pixel `amount` of the way towards the pixel in `other`, scaled by the value of
+    /// the pixel in `mask` when there is one
+    fn lerp(&mut self, other: &Canvas, amount: f32, mask: Option<&[u8]>) {
+        for (index, (pixel, other)) in self
+            .pixels
+            .chunks_exact_mut(4)
+            .zip(other.pixels.chunks_exact(4))
+            .enumerate()
+        {
+            let amount = match mask {
+                Some(mask) => amount * mask[index] as f32 / 255.0,
+                None => amount,
+            };
+
+            for (value, other) in pixel.iter_mut().zip(other.iter()) {
+                *value = (*value as f32 + (*other as f32 - *value as f32) * amount).round() as u8;
+            }
+        }
+    }
+}
+
+/// Cheap and stable per pixel noise from 0.0 to 1.0, so dissolve looks the same every export
+fn noise(index: usize) -> f32 {
+    let mut hash = (index as u32).wrapping_mul(0x9E37_79B9);
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85EB_CA6B);
+    hash ^= hash >> 13;
+
+    (hash & 0xFFFF) as f32 / 65535.0
+}
+
+impl PsdTree {
+    /// Flattens every visible node in the document into a single canvas
+    pub fn render(&self) -> Canvas {
+        self.render_with(&RenderOptions::default())
+    }
+
+    /// Like [`PsdTree::render`], but with the overrides in `options`
+    pub fn render_with(&self, options: &RenderOptions) -> Canvas {
+        // Flat documents, like indexed and bitmap ones, only have the merged image
+        if self.index.roots.is_empty() {
+            if let Some(pixels) = self.file.composite_rgba() {
+                return Canvas::from_rgba(self.psd.width(), self.psd.height(), pixels);
+            }
+        }
+
+        let mut canvas = Canvas::new(self.psd.width(), self.psd.height());
+        let overrides = self.overrides.merge(&options.overrides);
+        self.draw_all(&mut canvas, &self.index.roots, &overrides);
+
+        canvas
+    }
+
+    fn draw_all(&self, canvas: &mut Canvas, children: &[NodeId], overrides: &Overrides) {
+        for child in children {
+            let node = self.node(*child);
+            let element = node.element();
+
+            // Clipped nodes are drawn together with the node they're clipped to
+            if !overrides.is_visible(&node) || node.clipping_base().is_some() {
+                continue;
+            }
+
+            let opacity = overrides.draw_opacity(&node);
+            let blend_mode = overrides.blend_mode_of(&node);
+
+            match element {
+                PsdElement::Layer(..) if node.adjustment().is_some() => {
+                    node.adjust(canvas, opacity, blend_mode)
+                }
+                // Pass through groups draw their children straight onto what's below them, where
+                // the masks of the group let them
+                PsdElement::Group(..)
+                    if blend_mode == BlendMode::PassThrough && node.clipping_stack().is_empty() =>
+                {
+                    let mut passed = canvas.clone();
+                    self.draw_all(&mut passed, &node.entry().children, overrides);
+
+                    let mask = node.mask_values(canvas.width, canvas.height);
+                    canvas.lerp(&passed, opacity as f32 / 255.0, mask.as_deref());
+                }
+                _ => canvas.draw(
+                    &node.render_node(false, true, overrides),
+                    opacity,
+                    blend_mode,
+                ),
+            }
+        }
+    }
+}
+
+/// What to include when rendering a single node
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    /// Cut a clipped node down to the pixels of the node it's clipped to
+    pub clipped: bool,
+    /// Draw the visible nodes clipped to this one on top of it
+    pub merge_clipped: bool,
+    /// Bake the layer effects of a layer into its image when exporting it
+    pub effects: bool,
+    /// Export 16-bit PNGs of layers in documents with more than 8 bits per channel, as far as
+    /// [`PsdNode::render_float`] can render them
+    pub sixteen_bit: bool,
+    /// Export linear sRGB colors instead of sRGB encoded ones
+    pub linear: bool,
+    pub overrides: Overrides,
+}
+
+/// How to render nodes instead of the way they were saved, without changing the tree
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub visible: HashMap<NodeId, bool>,
+    pub opacity: HashMap<NodeId, u8>,
+    /// Pixels to move layers by, right and down
+    pub offset: HashMap<NodeId, (i32, i32)>,
+    pub blend_mode: HashMap<NodeId, BlendMode>,
+}
+
+impl Overrides {
+    /// These overrides with the ones in `other` on top
+    pub fn merge(&self, other: &Overrides) -> Overrides {
+        let mut merged = self.clone();
+        merged.visible.extend(other.visible.iter());
+        merged.opacity.extend(other.opacity.iter());
+        merged.offset.extend(other.offset.iter());
+        merged.blend_mode.extend(other.blend_mode.iter());
+
+        merged
+    }
+
+    pub fn is_visible(&self, node: &PsdNode) -> bool {
+        match self.visible.get(&node.id) {
+            Some(visible) => *visible,
+            None => node.element().visible(),
+        }
+    }
+
+    pub fn opacity_of(&self, node: &PsdNode) -> u8 {
+        match self.opacity.get(&node.id) {
+            Some(opacity) => *opacity,
+            None => node.element().opacity(),
+        }
+    }
+
+    pub fn blend_mode_of(&self, node: &PsdNode) -> BlendMode {
+        match self.blend_mode.get(&node.id) {
+            Some(blend_mode) => *blend_mode,
+            None => node.element().blend_mode(),
+        }
+    }
+
+    /// Opacity to draw a node with, which includes its fill opacity
+    fn draw_opacity(&self, node: &PsdNode) -> u8 {
+        let opacity = self.opacity_of(node) as u16 * node.element().fill_opacity() as u16;
+
+        (opacity / 255) as u8
+    }
+}
+
+impl PsdNode {
+    /// Renders the pixels of a layer, or the visible nodes inside a group flattened bottom to top,
+    /// without the visibility and opacity of the node itself.
+    pub fn render(&self) -> Canvas {
+        self.render_with(&RenderOptions::default())
+    }
+
+    /// Renders the raster mask of this node inside of its rect, clipped to the document
+    pub fn render_mask(&self) -> Option<(Bounds, Vec<u8>)> {
+        let mask = self.element().mask()?;
+        let (width, height) = (self.tree.psd.width(), self.tree.psd.height());
+
+        // Broken masks can end before they start, which leaves nothing to render
+        let left = mask.left.clamp(0, width as i32);
+        let top = mask.top.clamp(0, height as i32);
+        let bounds = Bounds {
+            left,
+            top,
+            width: (mask.right.clamp(0, width as i32) - left).max(0) as u32,
+            height: (mask.bottom.clamp(0, height as i32) - top).max(0) as u32,
+        };
+
+        let values = mask.render(width, height);
+        let pixels = values
+            .chunks_exact(width as usize)
+            .skip(bounds.top as usize)
+            .take(bounds.height as usize)
+            .flat_map(|row| &row[bounds.left as usize..bounds.right() as usize])
+            .copied()
+            .collect::<Vec<u8>>();
+
+        Some((bounds, pixels))
+    }
+
+    pub fn render_with(&self, options: &RenderOptions) -> Canvas {
+        let overrides = self.tree.overrides.merge(&options.overrides);
+
+        self.render_node(options.clipped, options.merge_clipped, &overrides)
+    }
+
+    fn render_node(&self, clipped: bool, merge_clipped: bool, overrides: &Overrides) -> Canvas {
+        let (width, height) = (self.tree.psd.width(), self.tree.psd.height());
+
+        let element = self.element();
+        let mut canvas = match element {
+            // Shape and fill layers without pixels of their own are filled with their color
+            PsdElement::Layer(layer, record)
+                if (layer.width() == 0 || layer.height() == 0) && record.fill_color().is_some() =>
+            {
+                let color = record.fill_color().unwrap_or_default();
+                let pixel = [color[0], color[1], color[2], 1.0].map(|c| (c * 255.0).round() as u8);
+
+                Canvas::from_rgba(
+                    width,
+                    height,
+                    pixel.repeat(width as usize * height as usize),
+                )
+            }
+            PsdElement::Layer(layer, _) => Canvas::from_rgba(width, height, layer.rgba()),
+            PsdElement::Group(..) => {
+                let mut canvas = Canvas::new(width, height);
+                self.tree
+                    .draw_all(&mut canvas, &self.entry().children, overrides);
+
+                canvas
+            }
+        };
+
+        self.apply_masks(&mut canvas);
+
+        if let Some((x, y)) = overrides.offset.get(&self.id) {
+            canvas.translate(*x, *y);
+        }
+
+        if clipped {
+            if let Some(base) = self.clipping_base() {
+                canvas.mask(&base.render_node(false, false, overrides));
+            }
+        }
+
+        if merge_clipped {
+            for node in self.clipping_stack() {
+                if !overrides.is_visible(&node) {
+                    continue;
+                }
+
+                if node.adjustment().is_some() {
+                    node.adjust(
+                        &mut canvas,
+                        overrides.draw_opacity(&node),
+                        overrides.blend_mode_of(&node),
+                    );
+                    continue;
+                }
+
+                canvas.draw_atop(
+                    &node.render_node(false, false, overrides),
+                    overrides.draw_opacity(&node),
+                    overrides.blend_mode_of(&node),
+                );
+            }
+        }
+
+        canvas
+    }
+}
+
+impl PsdNode {
+    fn apply_masks(&self, canvas: &mut Canvas) {
+        if let Some(values) = self.mask_values(canvas.width, canvas.height) {
+            canvas.apply_mask(values);
+        }
+    }
+
+    /// The enabled raster and vector masks of this node multiplied together, one value per pixel
+    fn mask_values(&self, width: u32, height: u32) -> Option<Vec<u8>> {
+        let element = self.element();
+        let mut masks = vec![];
+
+        if let Some(mask) = element.mask() {
+            if !mask.disabled && mask.is_decoded() {
+                masks.push(mask.render(width, height));
+            }
+        }
+
+        if let Some(mask) = element.vector_mask() {
+            if !mask.disabled {
+                masks.push(mask.render(width, height));
+            }
+        }
+
+        masks.into_iter().reduce(|values, other| {
+            values
+                .iter()
+                .zip(other.iter())
+                .map(|(value, other)| (*value as f32 * *other as f32 / 255.0).round() as u8)
+                .collect::<Vec<u8>>()
+        })
+    }
+
+    /// Applies this adjustment layer to everything already drawn onto `canvas`, through its masks
+    fn adjust(&self, canvas: &mut Canvas, opacity: u8, mode: BlendMode) {
+        let adjustment = match self.adjustment() {
+            Some(adjustment) => adjustment,
+            None => return,
+        };
+
+        let mut adjusted = canvas.clone();
+        adjustment.apply(&mut adjusted.pixels);
+
+        // Adjustments don't add pixels, so only the masks decide where they apply
+        for alpha in adjusted.pixels.iter_mut().skip(3).step_by(4) {
+            *alpha = 255;
+        }
+
+        self.apply_masks(&mut adjusted);
+        canvas.draw_atop(&adjusted, opacity, mode);
+    }
+}
+
+impl LayerMask {
+    /// Whether the pixels of the mask could be read, which isn't the case when they're broken
+    pub fn is_decoded(&self) -> bool {
+        let width = (self.right - self.left).max(0) as usize;
+        let height = (self.bottom - self.top).max(0) as usize;
+
+        self.pixels.len() == width * height
+    }
+
+    /// The mask over the whole document, one value per pixel, with its feather, inversion and
+    /// density applied. Doesn't take `disabled` into account.
+    pub fn render(&self, width: u32, height: u32) -> Vec<u8> {
+        let (width, height) = (width as usize, height as usize);
+        let mut values = vec![self.default_color; width * height];
+
+        let mask_width = (self.right - self.left).max(0) as usize;
+        if mask_width > 0 && self.is_decoded() {
+            for (row, line) in self.pixels.chunks_exact(mask_width).enumerate() {
+                let y = self.top + row as i32;
+                if y < 0 || y as usize >= height {
+                    continue;
+                }
+
+                for (column, value) in line.iter().enumerate() {
+                    let x = self.left + column as i32;
+                    if x >= 0 && (x as usize) < width {
+                        values[y as usize * width + x as usize] = *value;
+                    }
+                }
+            }
+        }
+
+        finish_mask(
+            &mut values,
+            width,
+            height,
+            self.feather,
+            self.inverted,
+            self.density,
+        );
+
+        values
+    }
+}
+
+/// Applies the feather, inversion and density all masks have to a rendered mask
+pub(super) fn finish_mask(
+    values: &mut [u8],
+    width: usize,
+    height: usize,
+    feather: f64,
+    inverted: bool,
+    density: u8,
+) {
+    // Three box blurs come close to the gaussian blur Photoshop feathers with
+    let radius = (feather / 2.0).round() as usize;
+    if radius > 0 {
+        for _ in 0..3 {
+            box_blur(values, width, height, radius);
+        }
+    }
+
+    for value in values.iter_mut() {
+        if inverted {
+            *value = 255 - *value;
+        }
+
+        *value = 255 - ((255 - *value) as u16 * density as u16 / 255) as u8;
+    }
+}
+
+/// Blurs a buffer with one value per pixel horizontally and then vertically, clamping at the edges
+pub(super) fn box_blur(values: &mut [u8], width: usize, height: usize, radius: usize) {
+    let blur_line = |line: Vec<u8>| -> Vec<u8> {
+        let last = line.len() as isize - 1;
+        let sample = |index: isize| line[index.clamp(0, last) as usize] as u32;
+        let size = 2 * radius as u32 + 1;
+
+        let mut sum = (-(radius as isize)..=radius as isize)
+            .map(sample)
+            .sum::<u32>();
+
+        (0..line.len() as isize)
+            .map(|index| {
+                let value = (sum as f32 / size as f32).round() as u8;
+                sum = sum + sample(index + radius as isize + 1) - sample(index - radius as isize);
+
+                value
+            })
+            .collect::<Vec<u8>>()
+    };
+
+    for y in 0..height {
+        let row = blur_line(values[y * width..(y + 1) * width].to_vec());
+        values[y * width..(y + 1) * width].copy_from_slice(&row);
+    }
+
+    for x in 0..width {
+        let column = blur_line((0..height).map(|y| values[y * width + x]).collect());
+        for (y, value) in column.into_iter().enumerate() {
+            values[y * width + x] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixture::Document;
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    fn pixels(canvas: &Canvas) -> Vec<[u8; 4]> {
+        canvas
+            .pixels
+            .chunks_exact(4)
+            .map(|pixel| pixel.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn stacking() {
+        let tree = Document::new(3, 1)
+            .layer("Red", [0, 0, 1, 2], RED)
+            .layer("Blue", [0, 1, 1, 3], BLUE)
+            .tree();
+
+        assert_eq!(pixels(&tree.render()), [RED, BLUE, BLUE]);
+
+        let tree = Document::new(3, 1)
+            .layer("Red", [0, 0, 1, 2], RED)
+            .layer("Blue", [0, 1, 1, 3], BLUE)
+            .with(|record| record.opacity = 51)
+            .tree();
+
+        assert_eq!(
+            pixels(&tree.render()),
+            [RED, [204, 0, 51, 255], [0, 0, 255, 51]]
+        );
+    }
+
+    #[test]
+    fn hidden_groups() {
+        let tree = Document::new(1, 1)
+            .layer("Red", [0, 0, 1, 1], RED)
+            .divider()
+            .layer("Blue", [0, 0, 1, 1], BLUE)
+            .group("Hidden")
+            .with(|record| record.flags = 2)
+            .tree();
+
+        assert_eq!(pixels(&tree.render()), [RED]);
+
+        // Rendering the group itself leaves out its own visibility
+        let group = tree.get_node("/Hidden").unwrap();
+        assert_eq!(pixels(&group.render()), [BLUE]);
+    }
+
+    #[test]
+    fn pass_through_groups() {
+        let document = |blend_key: &[u8; 4], opacity: u8| {
+            Document::new(1, 1)
+                .layer("Magenta", [0, 0, 1, 1], [255, 0, 255, 255])
+                .divider()
+                .layer("Green", [0, 0, 1, 1], GREEN)
+                .with(|record| record.blend_key = *b"mul ")
+                .group("Group")
+                .with(|record| {
+                    record.blend_key = *blend_key;
+                    record.opacity = opacity;
+                })
+                .tree()
+        };
+
+        // Passing through multiplies with the layer below the group, isolated groups multiply
+        // with nothing
+        assert_eq!(pixels(&document(b"pass", 255).render()), [[0, 0, 0, 255]]);
+        assert_eq!(pixels(&document(b"norm", 255).render()), [GREEN]);
+
+        assert_eq!(
+            pixels(&document(b"pass", 51).render()),
+            [[204, 0, 204, 255]]
+        );
+    }
+
+    #[test]
+    fn clipped_layers() {
+        let document = |base_flags: u8| {
+            Document::new(3, 1)
+                .layer("Base", [0, 0, 1, 2], RED)
+                .with(|record| record.flags = base_flags)
+                .layer("Clipped", [0, 0, 1, 3], BLUE)
+                .with(|record| record.clipping = 1)
+                .tree()
+        };
+        let tree = document(0);
+        let clipped = tree.get_node("/Clipped").unwrap();
+        let base = tree.get_node("/Base").unwrap();
+
+        // Clipped layers are drawn atop the pixels of their base
+        assert_eq!(pixels(&tree.render()), [BLUE, BLUE, [0; 4]]);
+
+        assert_eq!(pixels(&clipped.render()), [BLUE; 3]);
+        let options = RenderOptions {
+            clipped: true,
+            ..RenderOptions::default()
+        };
+        // Cutting it down only takes away alpha
+        assert_eq!(
+            pixels(&clipped.render_with(&options)),
+            [BLUE, BLUE, [0, 0, 255, 0]]
+        );
+
+        assert_eq!(pixels(&base.render()), [RED, RED, [0; 4]]);
+        let options = RenderOptions {
+            merge_clipped: true,
+            ..RenderOptions::default()
+        };
+        assert_eq!(pixels(&base.render_with(&options)), [BLUE, BLUE, [0; 4]]);
+
+        // Hiding the base hides what's clipped to it
+        assert_eq!(pixels(&document(2).render()), [[0; 4]; 3]);
+    }
+
+    #[test]
+    fn overrides() {
+        let tree = Document::new(3, 1)
+            .layer("Red", [0, 0, 1, 2], RED)
+            .divider()
+            .layer("Blue", [0, 1, 1, 3], BLUE)
+            .group("Group")
+            .tree();
+        let blue = tree.get_node("/Group/Blue").unwrap();
+        let red = tree.get_node("/Red").unwrap();
+
+        let mut options = RenderOptions::default();
+        options.overrides.visible.insert(blue.id, false);
+        assert_eq!(pixels(&tree.render_with(&options)), [RED, RED, [0; 4]]);
+
+        let group = tree.get_node("/Group").unwrap();
+        assert_eq!(pixels(&group.render_with(&options)), [[0; 4]; 3]);
+
+        let mut options = RenderOptions::default();
+        options.overrides.opacity.insert(blue.id, 51);
+        options.overrides.offset.insert(red.id, (1, 0));
+        assert_eq!(
+            pixels(&tree.render_with(&options)),
+            [[0; 4], [204, 0, 51, 255], [204, 0, 51, 255]]
+        );
+
+        // The tree itself is left alone
+        assert_eq!(pixels(&tree.render()), [RED, BLUE, BLUE]);
+        assert!(blue.element().visible());
+        assert_eq!(blue.element().opacity(), 255);
+    }
+}
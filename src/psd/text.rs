@@ -0,0 +1,576 @@
+//! Text layers, out of the `TySh` block and the engine data inside of it. Engine data is
+//! Photoshop's own PostScript like format, holding the styling of the text.
+
+use super::descriptor::{Descriptor, Value};
+use super::records::{Cursor, LayerRecord};
+use super::Rgb;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextData {
+    /// The text with `\n` between lines
+    pub text: String,
+    /// The style of the first character, for text that uses a single style
+    pub style: TextStyle,
+    /// Every part of the text with its own style, in order
+    pub runs: Vec<TextRun>,
+    pub alignment: TextAlignment,
+    /// Transform from text space to the document, as `xx, xy, yx, yy, tx, ty`
+    pub transform: [f64; 6],
+    /// Rect around the text as `left, top, right, bottom` in document pixels
+    pub bounds: [f64; 4],
+    /// Rect of the text box for paragraph text as `left, top, right, bottom` in document
+    /// pixels, `None` for point text
+    pub box_bounds: Option<[f64; 4]>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TextStyle {
+    /// PostScript name of the font, like `ArialMT`
+    pub font: String,
+    /// In pixels, with the scale of the transform applied
+    pub size: f64,
+    pub color: Rgb,
+    /// Distance between lines in pixels, with the scale of the transform applied
+    pub leading: f64,
+    /// Space between characters in 1/1000 em
+    pub tracking: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TextRun {
+    /// Index of the first character in `TextData::text`
+    pub start: usize,
+    pub length: usize,
+    pub style: TextStyle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextAlignment {
+    Left,
+    Right,
+    Center,
+    JustifyLeft,
+    JustifyRight,
+    JustifyCenter,
+    JustifyAll,
+}
+
+impl TextAlignment {
+    fn from_index(index: i64) -> TextAlignment {
+        match index {
+            1 => TextAlignment::Right,
+            2 => TextAlignment::Center,
+            3 => TextAlignment::JustifyLeft,
+            4 => TextAlignment::JustifyRight,
+            5 => TextAlignment::JustifyCenter,
+            6 => TextAlignment::JustifyAll,
+            _ => TextAlignment::Left,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextAlignment::Left => "left",
+            TextAlignment::Right => "right",
+            TextAlignment::Center => "center",
+            TextAlignment::JustifyLeft => "justify_left",
+            TextAlignment::JustifyRight => "justify_right",
+            TextAlignment::JustifyCenter => "justify_center",
+            TextAlignment::JustifyAll => "justify_all",
+        }
+    }
+}
+
+impl LayerRecord {
+    pub fn text(&self) -> Option<TextData> {
+        let mut cursor = Cursor::new(self.block(b"TySh")?);
+        cursor.u16().ok()?;
+
+        let mut transform = [0.0; 6];
+        for value in transform.iter_mut() {
+            *value = cursor.f64().ok()?;
+        }
+
+        cursor.u16().ok()?;
+        cursor.u32().ok()?;
+        let descriptor = Descriptor::read(&mut cursor).ok()?;
+
+        let text = descriptor
+            .text("Txt ")
+            .unwrap_or_default()
+            .replace('\r', "\n");
+
+        let engine = match descriptor.get("EngineData") {
+            Some(Value::Data(data)) => EngineValue::parse(data),
+            _ => None,
+        }
+        .unwrap_or(EngineValue::Array(vec![]));
+
+        let [xx, xy, yx, yy, tx, ty] = transform;
+        // Rotated or skewed text covers the rect around all four transformed corners
+        let to_document = |rect: [f64; 4]| {
+            let corners = [
+                (rect[0], rect[1]),
+                (rect[2], rect[1]),
+                (rect[0], rect[3]),
+                (rect[2], rect[3]),
+            ]
+            .map(|(x, y)| (xx * x + yx * y + tx, xy * x + yy * y + ty));
+
+            corners.iter().fold(
+                [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+                |[left, top, right, bottom], (x, y)| {
+                    [left.min(*x), top.min(*y), right.max(*x), bottom.max(*y)]
+                },
+            )
+        };
+
+        let bounds = descriptor
+            .descriptor("bounds")
+            .map(|bounds| {
+                [
+                    bounds.f64("Left").unwrap_or_default(),
+                    bounds.f64("Top ").unwrap_or_default(),
+                    bounds.f64("Rght").unwrap_or_default(),
+                    bounds.f64("Btom").unwrap_or_default(),
+                ]
+            })
+            .unwrap_or_default();
+
+        let shape = engine.path(&["EngineDict", "Rendered", "Shapes", "Children"]);
+        let shape = shape.and_then(|children| children.index(0));
+        // Shape type 1 is paragraph text, 0 is point text
+        let box_bounds = match shape.and_then(|shape| shape.get("ShapeType")?.as_f64()) {
+            Some(shape_type) if shape_type as i64 == 1 => shape
+                .and_then(|shape| shape.path(&["Cookie", "Photoshop", "BoxBounds"]))
+                .and_then(|bounds| {
+                    Some([
+                        bounds.index(0)?.as_f64()?,
+                        bounds.index(1)?.as_f64()?,
+                        bounds.index(2)?.as_f64()?,
+                        bounds.index(3)?.as_f64()?,
+                    ])
+                }),
+            _ => None,
+        };
+
+        let scale = (xy * xy + yy * yy).sqrt();
+        let styles = Styles::new(&engine, scale.max(f64::EPSILON));
+        let runs = styles.runs(&engine, &text);
+
+        let alignment = engine
+            .path(&["EngineDict", "ParagraphRun", "RunArray"])
+            .and_then(|runs| runs.index(0))
+            .and_then(|run| run.path(&["ParagraphSheet", "Properties", "Justification"]))
+            .or_else(|| styles.paragraph("Justification"))
+            .and_then(EngineValue::as_f64)
+            .map(|index| TextAlignment::from_index(index as i64))
+            .unwrap_or(TextAlignment::Left);
+
+        Some(TextData {
+            text,
+            style: runs
+                .first()
+                .map(|run| run.style.clone())
+                .unwrap_or_else(|| styles.style(None)),
+            runs,
+            alignment,
+            transform,
+            bounds: to_document(bounds),
+            box_bounds: box_bounds.map(to_document),
+        })
+    }
+}
+
+/// Resolves style properties of runs, falling back on the document's normal style sheet
+struct Styles<'a> {
+    fonts: Vec<String>,
+    normal_style: Option<&'a EngineValue>,
+    normal_paragraph: Option<&'a EngineValue>,
+    scale: f64,
+}
+
+impl<'a> Styles<'a> {
+    fn new(engine: &'a EngineValue, scale: f64) -> Styles<'a> {
+        let resources = engine.get("ResourceDict");
+        let normal = |set: &str, index: &str, data: &str| {
+            let resources = resources?;
+            let index = resources.get(index).and_then(EngineValue::as_f64)?;
+
+            resources.get(set)?.index(index as usize)?.get(data)
+        };
+
+        let fonts = resources
+            .and_then(|resources| resources.get("FontSet"))
+            .map(|fonts| {
+                fonts
+                    .items()
+                    .iter()
+                    .map(|font| {
+                        font.get("Name")
+                            .and_then(EngineValue::as_str)
+                            .unwrap_or_default()
+                            .to_string()
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+
+        Styles {
+            fonts,
+            normal_style: normal("StyleSheetSet", "TheNormalStyleSheet", "StyleSheetData"),
+            normal_paragraph: normal("ParagraphSheetSet", "TheNormalParagraphSheet", "Properties"),
+            scale,
+        }
+    }
+
+    fn paragraph(&self, key: &str) -> Option<&'a EngineValue> {
+        self.normal_paragraph?.get(key)
+    }
+
+    /// The style out of the `StyleSheetData` of a run
+    fn style(&self, data: Option<&EngineValue>) -> TextStyle {
+        let get = |key: &str| {
+            data.and_then(|data| data.get(key))
+                .or_else(|| self.normal_style?.get(key))
+        };
+        let number = |key: &str| get(key).and_then(EngineValue::as_f64);
+
+        let size = number("FontSize").unwrap_or(12.0);
+        let leading = match get("AutoLeading").and_then(EngineValue::as_bool) {
+            Some(false) => number("Leading").unwrap_or(size * 1.2),
+            _ => size * 1.2,
+        };
+
+        // Colors are stored as alpha, red, green and blue
+        let color = get("FillColor")
+            .and_then(|color| color.get("Values"))
+            .and_then(|values| {
+                Some([
+                    values.index(1)?.as_f64()? as f32,
+                    values.index(2)?.as_f64()? as f32,
+                    values.index(3)?.as_f64()? as f32,
+                ])
+            })
+            .unwrap_or_default();
+
+        TextStyle {
+            font: number("Font")
+                .and_then(|index| self.fonts.get(index as usize))
+                .cloned()
+                .unwrap_or_default(),
+            size: size * self.scale,
+            color,
+            leading: leading * self.scale,
+            tracking: number("Tracking").unwrap_or_default(),
+        }
+    }
+
+    fn runs(&self, engine: &EngineValue, text: &str) -> Vec<TextRun> {
+        let style_run = match engine.path(&["EngineDict", "StyleRun"]) {
+            Some(style_run) => style_run,
+            None => return vec![],
+        };
+
+        let lengths = style_run
+            .get("RunLengthArray")
+            .map(EngineValue::items)
+            .unwrap_or_default();
+        let styles = style_run
+            .get("RunArray")
+            .map(EngineValue::items)
+            .unwrap_or_default();
+
+        // Run lengths count UTF-16 code units, the runs themselves count characters
+        let offsets = std::iter::once(0)
+            .chain(text.chars().scan(0, |offset, character| {
+                *offset += character.len_utf16();
+                Some(*offset)
+            }))
+            .collect::<Vec<usize>>();
+        let units = offsets.last().copied().unwrap_or_default();
+        let character = |offset: usize| offsets.partition_point(|start| *start < offset);
+
+        let mut start = 0;
+        lengths
+            .iter()
+            .zip(styles)
+            .filter_map(|(length, style)| {
+                // Engine data ends every text with a line break the text itself doesn't have
+                let length = (length.as_f64()? as usize).min(units.saturating_sub(start));
+                let run = TextRun {
+                    start: character(start),
+                    length: character(start + length) - character(start),
+                    style: self.style(style.path(&["StyleSheet", "StyleSheetData"])),
+                };
+
+                start += length;
+                match length {
+                    0 => None,
+                    _ => Some(run),
+                }
+            })
+            .collect::<Vec<TextRun>>()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum EngineValue {
+    Dictionary(Vec<(String, EngineValue)>),
+    Array(Vec<EngineValue>),
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Name(String),
+}
+
+impl EngineValue {
+    fn parse(data: &[u8]) -> Option<EngineValue> {
+        EngineParser { data, position: 0 }.value()
+    }
+
+    fn get(&self, key: &str) -> Option<&EngineValue> {
+        match self {
+            EngineValue::Dictionary(items) => items
+                .iter()
+                .find(|(item, _)| item == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn path(&self, keys: &[&str]) -> Option<&EngineValue> {
+        keys.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    fn index(&self, index: usize) -> Option<&EngineValue> {
+        self.items().get(index)
+    }
+
+    fn items(&self) -> &[EngineValue] {
+        match self {
+            EngineValue::Array(items) => items,
+            _ => &[],
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            EngineValue::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            EngineValue::Boolean(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            EngineValue::String(value) | EngineValue::Name(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+struct EngineParser<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl EngineParser<'_> {
+    fn value(&mut self) -> Option<EngineValue> {
+        self.skip_whitespace();
+
+        match self.data.get(self.position)? {
+            b'<' if self.data.get(self.position + 1) == Some(&b'<') => {
+                self.position += 2;
+
+                let mut items = vec![];
+                loop {
+                    self.skip_whitespace();
+                    match self.data.get(self.position)? {
+                        b'>' if self.data.get(self.position + 1) == Some(&b'>') => {
+                            self.position += 2;
+                            return Some(EngineValue::Dictionary(items));
+                        }
+                        b'/' => {
+                            self.position += 1;
+                            let key = self.word();
+                            items.push((key, self.value()?));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'[' => {
+                self.position += 1;
+
+                let mut items = vec![];
+                loop {
+                    self.skip_whitespace();
+                    if self.data.get(self.position)? == &b']' {
+                        self.position += 1;
+                        return Some(EngineValue::Array(items));
+                    }
+
+                    items.push(self.value()?);
+                }
+            }
+            b'(' => {
+                self.position += 1;
+                Some(EngineValue::String(self.string()?))
+            }
+            b'/' => {
+                self.position += 1;
+                Some(EngineValue::Name(self.word()))
+            }
+            _ => {
+                let word = self.word();
+                match word.as_str() {
+                    "" => None,
+                    "true" => Some(EngineValue::Boolean(true)),
+                    "false" => Some(EngineValue::Boolean(false)),
+                    word => Some(match word.parse::<f64>() {
+                        Ok(number) => EngineValue::Number(number),
+                        Err(_) => EngineValue::Name(word.to_string()),
+                    }),
+                }
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.data.get(self.position) {
+            match byte {
+                b' ' | b'\t' | b'\r' | b'\n' | 0 => self.position += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let start = self.position;
+        while let Some(byte) = self.data.get(self.position) {
+            match byte {
+                b' ' | b'\t' | b'\r' | b'\n' | 0 | b'/' | b'[' | b']' | b'<' | b'>' | b'(' => break,
+                _ => self.position += 1,
+            }
+        }
+
+        String::from_utf8_lossy(&self.data[start..self.position]).to_string()
+    }
+
+    /// Reads a string up to its closing parenthesis, which is UTF-16 when it starts with a byte
+    /// order mark
+    fn string(&mut self) -> Option<String> {
+        let mut bytes = vec![];
+        loop {
+            let byte = *self.data.get(self.position)?;
+            self.position += 1;
+
+            match byte {
+                b'\\' => {
+                    bytes.push(*self.data.get(self.position)?);
+                    self.position += 1;
+                }
+                b')' => break,
+                byte => bytes.push(byte),
+            }
+        }
+
+        Some(match bytes.strip_prefix(&[0xFE, 0xFF]) {
+            Some(utf16) => String::from_utf16_lossy(
+                &utf16
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect::<Vec<u16>>(),
+            ),
+            None => bytes.iter().map(|byte| char::from(*byte)).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> EngineValue {
+        EngineValue::String(value.to_string())
+    }
+
+    #[test]
+    fn nested_dictionaries_and_arrays() {
+        let engine = EngineValue::parse(
+            b"<< /EngineDict << /Editor << /Text (Hi) >> /Runs [ 1 2.5 [ true ] ] >> /Name /Foo >>",
+        )
+        .unwrap();
+
+        assert_eq!(
+            engine.path(&["EngineDict", "Editor", "Text"]),
+            Some(&string("Hi"))
+        );
+
+        let runs = engine.path(&["EngineDict", "Runs"]).unwrap();
+        assert_eq!(runs.index(0).and_then(EngineValue::as_f64), Some(1.0));
+        assert_eq!(runs.index(1).and_then(EngineValue::as_f64), Some(2.5));
+        assert_eq!(
+            runs.index(2).and_then(|items| items.index(0)?.as_bool()),
+            Some(true)
+        );
+        assert_eq!(
+            engine.get("Name").and_then(EngineValue::as_str),
+            Some("Foo")
+        );
+    }
+
+    #[test]
+    fn unclosed_dictionaries() {
+        assert_eq!(EngineValue::parse(b"<< /Key 1 >"), None);
+        assert_eq!(EngineValue::parse(b"<< /Key 1 > >>"), None);
+        assert_eq!(EngineValue::parse(b"<< /Key [ 1"), None);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            EngineValue::parse(br"(a \) b \\ c)"),
+            Some(string(r"a ) b \ c"))
+        );
+
+        // UTF-16 with a byte order mark, where an escaped parenthesis is part of a character
+        let mut data = b"(\xFE\xFF".to_vec();
+        for unit in "Hé😀)".encode_utf16() {
+            for byte in unit.to_be_bytes() {
+                if matches!(byte, b'(' | b')' | b'\\') {
+                    data.push(b'\\');
+                }
+                data.push(byte);
+            }
+        }
+        data.push(b')');
+
+        assert_eq!(EngineValue::parse(&data), Some(string("Hé😀)")));
+    }
+
+    #[test]
+    fn runs_count_utf_16() {
+        let engine = EngineValue::parse(
+            b"<< /EngineDict << /StyleRun << /RunLengthArray [ 3 2 1 ] /RunArray [ << >> << >> << >> ] >> >> >>",
+        )
+        .unwrap();
+
+        // The emoji takes up two UTF-16 code units, the trailing line break isn't in the text
+        let styles = Styles::new(&engine, 1.0);
+        let runs = styles
+            .runs(&engine, "a😀b")
+            .iter()
+            .map(|run| (run.start, run.length))
+            .collect::<Vec<(usize, usize)>>();
+
+        assert_eq!(runs, vec![(0, 2), (2, 1)]);
+    }
+}
@@ -0,0 +1,142 @@
+//! Translation CSVs in the format Godot imports for the `TranslationServer`, with a row for every
+//! text layer.
+
+use super::{PsdTree, WalkOrder};
+
+use std::collections::HashSet;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslationEntry {
+    /// Generated out of the path, like `MENU_START_BUTTON_LABEL` for `/Menu/Start Button/Label`
+    pub key: String,
+    pub path: String,
+    pub text: String,
+}
+
+impl PsdTree {
+    /// Every text layer with its key, from top to bottom of the layer panel
+    pub fn translation_entries(&self) -> Vec<TranslationEntry> {
+        let mut keys = HashSet::new();
+
+        // Walking depth first goes from the bottom of the layer panel up, and text layers never
+        // have children, so the other way around is the order of the panel
+        let mut layers = self
+            .walk(WalkOrder::DepthFirst)
+            .filter_map(|entry| {
+                let text = entry.node.element().text_data()?.text;
+
+                Some((entry.node.id, entry.path, text))
+            })
+            .collect::<Vec<_>>();
+        layers.reverse();
+
+        layers
+            .into_iter()
+            .map(|(id, path, text)| {
+                let path = path.to_str().unwrap().to_string();
+
+                // Names without any letters or digits fall back to the id of the node
+                let base = match translation_key(&path) {
+                    key if key.is_empty() => format!("TEXT_{}", id.0),
+                    key => key,
+                };
+
+                // Different paths can end up as the same key, later ones get a number
+                let mut key = base.clone();
+                let mut number = 2;
+                while !keys.insert(key.clone()) {
+                    key = format!("{base}_{number}");
+                    number += 1;
+                }
+
+                TranslationEntry { key, path, text }
+            })
+            .collect::<Vec<TranslationEntry>>()
+    }
+
+    /// A translation CSV with a `keys` column and the text of every text layer in a `locale`
+    /// column
+    pub fn translation_csv(&self, locale: &str) -> String {
+        to_csv(&self.translation_entries(), locale)
+    }
+
+    /// Writes the translation CSV to `path`, returning how many strings it holds
+    pub fn export_translation_csv(&self, path: &Path, locale: &str) -> std::io::Result<usize> {
+        let entries = self.translation_entries();
+        std::fs::write(path, to_csv(&entries, locale))?;
+
+        Ok(entries.len())
+    }
+}
+
+fn to_csv(entries: &[TranslationEntry], locale: &str) -> String {
+    let mut csv = format!("keys,{}\n", csv_field(locale));
+
+    for entry in entries {
+        csv.push_str(&format!(
+            "{},{}\n",
+            csv_field(&entry.key),
+            csv_field(&entry.text)
+        ));
+    }
+
+    csv
+}
+
+/// Turns a node path into an upper case key with only letters, digits and underscores, which can
+/// be empty. Letters and digits outside of ASCII are kept as well.
+fn translation_key(path: &str) -> String {
+    let mut key = String::with_capacity(path.len());
+    let mut characters = path.chars();
+
+    while let Some(character) = characters.next() {
+        // Escaped characters like `%2F` are separators as well
+        if character == '%' {
+            characters.nth(1);
+        }
+
+        match character.is_alphanumeric() {
+            true => key.extend(character.to_uppercase()),
+            false if !key.is_empty() && !key.ends_with('_') => key.push('_'),
+            false => {}
+        }
+    }
+
+    key.trim_end_matches('_').to_string()
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            translation_key("/Menu/Start Button/Label"),
+            "MENU_START_BUTTON_LABEL"
+        );
+        assert_eq!(
+            translation_key("/Menü/Straße %2F Größe"),
+            "MENÜ_STRASSE_GRÖSSE"
+        );
+        assert_eq!(translation_key("/メニュー/タイトル"), "メニュー_タイトル");
+        assert_eq!(translation_key("/★/!!"), "");
+    }
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_field("Start"), "Start");
+        assert_eq!(
+            csv_field("Say \"hi\", then go"),
+            "\"Say \"\"hi\"\", then go\""
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use super::render::finish_mask;
+use super::{PathOperation, Subpath, VectorMask};
+
+/// Vertical samples per pixel row, horizontal coverage is calculated exactly
+const SAMPLES: usize = 4;
+
+impl Subpath {
+    /// The subpath flattened into straight lines, in document pixels. Closed subpaths don't
+    /// repeat their first point at the end.
+    pub fn points(&self, width: u32, height: u32) -> Vec<[f32; 2]> {
+        let scale = |point: [f64; 2]| {
+            [
+                (point[0] * width as f64) as f32,
+                (point[1] * height as f64) as f32,
+            ]
+        };
+
+        let mut points = vec![];
+        let count = match self.closed {
+            true => self.knots.len(),
+            false => self.knots.len().saturating_sub(1),
+        };
+
+        if let Some(first) = self.knots.first() {
+            points.push(scale(first.anchor));
+        }
+
+        for index in 0..count {
+            let from = &self.knots[index];
+            let to = &self.knots[(index + 1) % self.knots.len()];
+
+            let curve = [
+                scale(from.anchor),
+                scale(from.leaving),
+                scale(to.preceding),
+                scale(to.anchor),
+            ];
+
+            // Enough steps to keep every line shorter than a few pixels
+            let length = curve
+                .windows(2)
+                .map(|pair| distance(pair[0], pair[1]))
+                .sum::<f32>();
+            let steps = ((length / 4.0).ceil() as usize).clamp(1, 64);
+
+            for step in 1..=steps {
+                points.push(bezier(curve, step as f32 / steps as f32));
+            }
+        }
+
+        // The last curve of a closed subpath ends where it started
+        if self.closed && count > 0 {
+            points.pop();
+        }
+
+        points
+    }
+
+    /// How much of every pixel in the document is inside the subpath, from 0.0 to 1.0
+    fn coverage(&self, width: u32, height: u32) -> Vec<f32> {
+        let (width, height) = (width as usize, height as usize);
+        let mut coverage = vec![0.0; width * height];
+
+        let points = self.points(width as u32, height as u32);
+        if points.len() < 3 {
+            return coverage;
+        }
+
+        let edges = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(from, to)| (*from, *to))
+            .collect::<Vec<([f32; 2], [f32; 2])>>();
+
+        let top = points.iter().map(|point| point[1]).fold(f32::MAX, f32::min);
+        let bottom = points.iter().map(|point| point[1]).fold(f32::MIN, f32::max);
+
+        let first_row = top.floor().max(0.0) as usize;
+        let last_row = (bottom.ceil().max(0.0) as usize).min(height);
+
+        let mut crossings = vec![];
+        for row in first_row..last_row {
+            let line = &mut coverage[row * width..(row + 1) * width];
+
+            for sample in 0..SAMPLES {
+                let y = row as f32 + (sample as f32 + 0.5) / SAMPLES as f32;
+
+                crossings.clear();
+                for (from, to) in edges.iter() {
+                    if (from[1] <= y) != (to[1] <= y) {
+                        let t = (y - from[1]) / (to[1] - from[1]);
+                        crossings.push(from[0] + t * (to[0] - from[0]));
+                    }
+                }
+
+                crossings.sort_by(|a, b| a.total_cmp(b));
+
+                // Even-odd: every other pair of crossings is inside
+                for span in crossings.chunks_exact(2) {
+                    fill_span(line, span[0], span[1], 1.0 / SAMPLES as f32);
+                }
+            }
+        }
+
+        coverage
+    }
+}
+
+impl VectorMask {
+    /// The mask over the whole document, one value per pixel, with the subpaths combined and its
+    /// feather, inversion and density applied. Doesn't take `disabled` into account.
+    pub fn render(&self, width: u32, height: u32) -> Vec<u8> {
+        let initial = match self.initial_fill {
+            true => 1.0,
+            false => 0.0,
+        };
+        let mut mask = vec![initial; width as usize * height as usize];
+
+        for subpath in self.subpaths.iter() {
+            let coverage = subpath.coverage(width, height);
+
+            for (value, coverage) in mask.iter_mut().zip(coverage) {
+                *value = match subpath.operation {
+                    PathOperation::Combine => *value + coverage - *value * coverage,
+                    PathOperation::Subtract => *value * (1.0 - coverage),
+                    PathOperation::Intersect => *value * coverage,
+                    PathOperation::Exclude => *value + coverage - 2.0 * *value * coverage,
+                };
+            }
+        }
+
+        let mut values = mask
+            .into_iter()
+            .map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8)
+            .collect::<Vec<u8>>();
+
+        finish_mask(
+            &mut values,
+            width as usize,
+            height as usize,
+            self.feather,
+            self.inverted,
+            self.density,
+        );
+
+        values
+    }
+}
+
+/// Adds `amount` of coverage to the pixels between `from` and `to`, partially covering the
+/// pixels at both ends
+fn fill_span(line: &mut [f32], from: f32, to: f32, amount: f32) {
+    let from = from.clamp(0.0, line.len() as f32);
+    let to = to.clamp(0.0, line.len() as f32);
+    if to <= from {
+        return;
+    }
+
+    let first = from.floor() as usize;
+    let last = to.floor() as usize;
+
+    if first == last {
+        line[first] += (to - from) * amount;
+        return;
+    }
+
+    line[first] += (first as f32 + 1.0 - from) * amount;
+    for value in line[first + 1..last].iter_mut() {
+        *value += amount;
+    }
+
+    if last < line.len() {
+        line[last] += (to - last as f32) * amount;
+    }
+}
+
+fn bezier(curve: [[f32; 2]; 4], t: f32) -> [f32; 2] {
+    let u = 1.0 - t;
+    let weights = [u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t];
+
+    let mut point = [0.0; 2];
+    for (control, weight) in curve.iter().zip(weights) {
+        point[0] += control[0] * weight;
+        point[1] += control[1] * weight;
+    }
+
+    point
+}
+
+fn distance(from: [f32; 2], to: [f32; 2]) -> f32 {
+    ((to[0] - from[0]).powi(2) + (to[1] - from[1]).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psd::Knot;
+
+    /// A closed subpath of straight lines through `points`, in pixels of an 8x8 document
+    fn polygon(points: &[[f64; 2]], operation: PathOperation) -> Subpath {
+        let knots = points
+            .iter()
+            .map(|point| {
+                let anchor = [point[0] / 8.0, point[1] / 8.0];
+                Knot {
+                    preceding: anchor,
+                    anchor,
+                    leaving: anchor,
+                }
+            })
+            .collect();
+
+        Subpath {
+            closed: true,
+            operation,
+            knots,
+        }
+    }
+
+    fn mask(subpaths: Vec<Subpath>) -> Vec<u8> {
+        VectorMask {
+            disabled: false,
+            inverted: false,
+            density: 255,
+            feather: 0.0,
+            initial_fill: false,
+            subpaths,
+        }
+        .render(8, 8)
+    }
+
+    #[test]
+    fn points() {
+        let square = polygon(
+            &[[2.0, 2.0], [6.0, 2.0], [6.0, 6.0], [2.0, 6.0]],
+            PathOperation::Combine,
+        );
+        let points = square.points(8, 8);
+
+        assert_eq!(points[0], [2.0, 2.0]);
+        assert_ne!(points.last(), Some(&[2.0, 2.0]));
+        assert!(points.iter().all(|[x, y]| {
+            (2.0..=6.0).contains(x)
+                && (2.0..=6.0).contains(y)
+                && (*x == 2.0 || *x == 6.0 || *y == 2.0 || *y == 6.0)
+        }));
+    }
+
+    #[test]
+    fn square() {
+        let square = polygon(
+            &[[1.5, 1.5], [6.5, 1.5], [6.5, 6.5], [1.5, 6.5]],
+            PathOperation::Combine,
+        );
+        let values = mask(vec![square]);
+
+        assert_eq!(values[0], 0);
+        assert_eq!(values[3 * 8 + 3], 255);
+        assert_eq!(values[5 * 8 + 5], 255);
+        // Edges cover half of their pixels, corners a quarter
+        assert_eq!(values[8 + 3], 128);
+        assert_eq!(values[3 * 8 + 6], 128);
+        assert_eq!(values[8 + 1], 64);
+        assert_eq!(values[7 * 8 + 7], 0);
+    }
+
+    #[test]
+    fn triangle() {
+        let triangle = polygon(
+            &[[0.0, 0.0], [8.0, 0.0], [0.0, 8.0]],
+            PathOperation::Combine,
+        );
+        let coverage = triangle.coverage(8, 8);
+
+        assert_eq!(coverage[0], 1.0);
+        assert_eq!(coverage[6 * 8], 1.0);
+        assert_eq!(coverage[7 * 8 + 7], 0.0);
+        // The diagonal cuts these pixels in half
+        assert!((coverage[4 * 8 + 3] - 0.5).abs() < 1e-4);
+        assert!((coverage[7] - 0.5).abs() < 1e-4);
+        assert!((coverage.iter().sum::<f32>() - 32.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn holes() {
+        let outer = [[1.0, 1.0], [7.0, 1.0], [7.0, 7.0], [1.0, 7.0]];
+        let inner = [[3.0, 3.0], [5.0, 3.0], [5.0, 5.0], [3.0, 5.0]];
+
+        // Within a subpath the fill is even-odd, so an inner loop going the same way is a hole
+        let mut both = outer.to_vec();
+        both.push(outer[0]);
+        both.extend_from_slice(&inner);
+        both.push(inner[0]);
+        let values = mask(vec![polygon(&both, PathOperation::Combine)]);
+        assert_eq!(values[2 * 8 + 2], 255);
+        assert_eq!(values[4 * 8 + 4], 0);
+
+        // Between subpaths combining fills it like nonzero would, excluding cuts the hole
+        let combined = mask(vec![
+            polygon(&outer, PathOperation::Combine),
+            polygon(&inner, PathOperation::Combine),
+        ]);
+        assert_eq!(combined[2 * 8 + 2], 255);
+        assert_eq!(combined[4 * 8 + 4], 255);
+
+        let excluded = mask(vec![
+            polygon(&outer, PathOperation::Combine),
+            polygon(&inner, PathOperation::Exclude),
+        ]);
+        assert_eq!(excluded[2 * 8 + 2], 255);
+        assert_eq!(excluded[4 * 8 + 4], 0);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use super::{NodeId, PsdNode, PsdTree};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    DepthFirst,
+    BreadthFirst,
+}
+
+type EntryFilter<'a> = Box<dyn FnMut(&WalkEntry) -> bool + 'a>;
+
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    pub node: PsdNode,
+    pub depth: usize,
+    pub path: PathBuf,
+}
+
+/// Iterator over the nodes of a [`PsdTree`], siblings always being visited from bottom to top.
+///
+/// Children of the last yielded node are only queued once the next node is requested, so calling
+/// [`Walk::skip_children`] in between prunes that node's subtree.
+pub struct Walk<'a> {
+    tree: PsdTree,
+    order: WalkOrder,
+    queue: VecDeque<(NodeId, String)>,
+    last: Option<(NodeId, String)>,
+    filter: Option<EntryFilter<'a>>,
+}
+
+impl<'a> Walk<'a> {
+    pub(super) fn new(tree: PsdTree, order: WalkOrder, from: Option<(NodeId, String)>) -> Self {
+        let mut walk = Walk {
+            tree,
+            order,
+            queue: VecDeque::new(),
+            last: None,
+            filter: None,
+        };
+
+        match from {
+            Some((id, path)) => walk.expand(id, &path),
+            None => {
+                for root in walk.tree.index.roots.iter() {
+                    let segment = &walk.tree.index.entry(*root).segment;
+                    walk.queue.push_back((*root, format!("/{segment}")));
+                }
+            }
+        }
+
+        walk
+    }
+
+    /// Don't descend into the node that was yielded last.
+    pub fn skip_children(&mut self) {
+        self.last = None;
+    }
+
+    /// Only yields nodes for which `predicate` returns `true`. Nodes that are filtered out are
+    /// pruned along with everything below them.
+    pub fn filter_entry(mut self, predicate: impl FnMut(&WalkEntry) -> bool + 'a) -> Self {
+        self.filter = Some(Box::new(predicate));
+        self
+    }
+
+    fn expand(&mut self, id: NodeId, path: &str) {
+        let children = &self.tree.index.entry(id).children;
+
+        match self.order {
+            WalkOrder::DepthFirst => {
+                for child in children.iter().rev() {
+                    let segment = &self.tree.index.entry(*child).segment;
+                    self.queue.push_front((*child, format!("{path}/{segment}")));
+                }
+            }
+            WalkOrder::BreadthFirst => {
+                for child in children.iter() {
+                    let segment = &self.tree.index.entry(*child).segment;
+                    self.queue.push_back((*child, format!("{path}/{segment}")));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = WalkEntry;
+
+    fn next(&mut self) -> Option<WalkEntry> {
+        loop {
+            if let Some((id, path)) = self.last.take() {
+                self.expand(id, &path);
+            }
+
+            let (id, path) = self.queue.pop_front()?;
+
+            let entry = WalkEntry {
+                node: self.tree.node(id),
+                depth: self.tree.index.entry(id).depth,
+                path: PathBuf::from(&path),
+            };
+
+            if let Some(filter) = &mut self.filter {
+                if !filter(&entry) {
+                    continue;
+                }
+            }
+
+            self.last = Some((id, path));
+
+            return Some(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixture::Document;
+    use super::*;
+
+    /// `/Background`, `/Body` holding `Arm` and `Head` with `Eye` in it, and `/Sky` on top
+    fn body() -> PsdTree {
+        Document::new(1, 1)
+            .empty("Background")
+            .divider()
+            .empty("Arm")
+            .divider()
+            .empty("Eye")
+            .group("Head")
+            .group("Body")
+            .empty("Sky")
+            .tree()
+    }
+
+    fn paths(walk: Walk) -> Vec<String> {
+        walk.map(|entry| entry.path.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn orders() {
+        let tree = body();
+
+        assert_eq!(
+            paths(tree.walk(WalkOrder::DepthFirst)),
+            [
+                "/Background",
+                "/Body",
+                "/Body/Arm",
+                "/Body/Head",
+                "/Body/Head/Eye",
+                "/Sky"
+            ]
+        );
+        assert_eq!(
+            paths(tree.walk(WalkOrder::BreadthFirst)),
+            [
+                "/Background",
+                "/Body",
+                "/Sky",
+                "/Body/Arm",
+                "/Body/Head",
+                "/Body/Head/Eye"
+            ]
+        );
+
+        let depths = tree
+            .walk(WalkOrder::DepthFirst)
+            .map(|entry| entry.depth)
+            .collect::<Vec<usize>>();
+        assert_eq!(depths, [0, 0, 1, 1, 2, 0]);
+
+        let body = tree.get_node("/Body").unwrap();
+        assert_eq!(
+            paths(body.descendants(WalkOrder::BreadthFirst)),
+            ["/Body/Arm", "/Body/Head", "/Body/Head/Eye"]
+        );
+    }
+
+    #[test]
+    fn skipped_children() {
+        for order in [WalkOrder::DepthFirst, WalkOrder::BreadthFirst] {
+            let mut walk = body().walk(order);
+            let mut visited = vec![];
+
+            while let Some(entry) = walk.next() {
+                if entry.node.element().name() == "Body" {
+                    walk.skip_children();
+                }
+                visited.push(entry.path.to_str().unwrap().to_string());
+            }
+
+            assert_eq!(visited, ["/Background", "/Body", "/Sky"]);
+        }
+
+        // Skipping only prunes the node yielded last
+        let mut walk = body().walk(WalkOrder::DepthFirst);
+        let mut visited = vec![];
+        while let Some(entry) = walk.next() {
+            if entry.node.element().name() == "Head" {
+                walk.skip_children();
+            }
+            visited.push(entry.path.to_str().unwrap().to_string());
+        }
+
+        assert_eq!(
+            visited,
+            ["/Background", "/Body", "/Body/Arm", "/Body/Head", "/Sky"]
+        );
+    }
+
+    #[test]
+    fn filtered_entries() {
+        let tree = body();
+        let without_head = |entry: &WalkEntry| entry.node.element().name() != "Head";
+
+        assert_eq!(
+            paths(tree.walk(WalkOrder::DepthFirst).filter_entry(without_head)),
+            ["/Background", "/Body", "/Body/Arm", "/Sky"]
+        );
+        assert_eq!(
+            paths(
+                tree.walk(WalkOrder::BreadthFirst)
+                    .filter_entry(|entry| entry.depth == 0)
+            ),
+            ["/Background", "/Body", "/Sky"]
+        );
+    }
+}